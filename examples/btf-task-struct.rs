@@ -0,0 +1,51 @@
+use bpf_script::compiler::Compiler;
+use bpf_script::types::{BaseType, TypeDatabase};
+use btf::Btf;
+
+fn main() {
+    let prog = r#"
+            fn(task: &task_struct)
+              pid = task.pid
+              tgid = task.tgid
+              return pid
+        "#;
+
+    let btf = match Btf::from_file("/sys/kernel/btf/vmlinux") {
+        Ok(btf) => btf,
+        Err(err) => {
+            eprintln!("Kernel BTF isn't available, skipping: {:?}", err);
+            return;
+        }
+    };
+
+    let mut database = TypeDatabase::default();
+    database
+        .add_btf_types(&btf)
+        .expect("Failed to add BTF types.");
+
+    let task_struct = database
+        .get_type_by_name("task_struct")
+        .expect("Couldn't find task_struct.");
+    println!("task_struct: {} bytes", task_struct.get_size());
+    if let BaseType::Struct(structure) = &task_struct.base_type {
+        for name in ["pid", "tgid"] {
+            let field = structure.fields.get(name).expect("Couldn't find field.");
+            let field_type = database
+                .get_type_by_id(field.type_id)
+                .expect("Couldn't find field type.");
+            println!(
+                "  {}: offset {} bits, {} bytes",
+                name,
+                field.offset,
+                field_type.get_size()
+            );
+        }
+    }
+
+    let mut compiler = Compiler::create(&database);
+    compiler.compile(prog).unwrap();
+
+    for ins in compiler.get_instructions() {
+        println!("{}", ins);
+    }
+}
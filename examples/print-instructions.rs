@@ -1,5 +1,4 @@
-use bpf_script::compiler::Compiler;
-use bpf_script::types::{Field, TypeDatabase};
+use bpf_script::{Compiler, Field, TypeDatabase};
 
 fn main() {
     let prog = r#"
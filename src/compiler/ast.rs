@@ -0,0 +1,174 @@
+use crate::error::Result as InternalResult;
+
+use peginator::PegParser;
+use peginator_macro::peginate;
+
+peginate!(
+    "
+@export
+ScriptDef = input:InputLine {NewLine exprs:Expression}$;
+
+InputLine = 'fn' '(' [args:TypedArgument {',' args:TypedArgument}] ')' ['->' return_type:TypeDecl];
+TypedArgument = name:Ident ':' type_name:TypeDecl;
+TypeDecl = [is_ref:ReferencePrefix] (array:ArrayDecl | name:Ident);
+ArrayDecl = '[' element:*TypeDecl ';' num_elements:Immediate ']';
+
+Expression = @:Assignment | @:Declaration | @:FunctionCall | @:Return | @:IfStatement | @:WhileStatement | @:Break | @:Continue;
+
+Assignment = left:LValue [':' type_name:TypeDecl] '=' right:RValue;
+Declaration = name:Ident ':' type_name:TypeDecl;
+FunctionCall = !Keyword name:Ident '(' [args:Argument {',' args:Argument}] ')';
+Argument = value:RValue [op:Comparator right:RValue];
+Return = 'return' [value:RValue];
+
+Condition = left:RValue op:Comparator right:RValue;
+BoolExpr = terms:AndExpr {'||' terms:AndExpr};
+AndExpr = conds:BoolTerm {'&&' conds:BoolTerm};
+BoolTerm = @:Condition | @:UnaryOp;
+IfStatement = 'if' cond:BoolExpr '{' {exprs:Expression} '}' ['else' (else_if:*IfStatement | '{' {else_exprs:Expression} '}')];
+WhileStatement = 'while' cond:BoolExpr '{' {exprs:Expression} '}';
+Break = 'break';
+Continue = 'continue';
+
+RValue = left:RValueInner [op:Operation right:RValueInner];
+RValueInner = @:SizeOf | @:OffsetOf | @:Volatile | @:Comparison | @:Parenthesized | @:FunctionCall | @:UnaryOp | @:Immediate | @:StringLiteral | @:LValue;
+SizeOf = 'sizeof' '(' type_name:TypeDecl ')';
+Volatile = 'volatile' '(' value:*RValue ')';
+Comparison = '(' cond:*Condition ')';
+Parenthesized = '(' value:*RValue ')';
+UnaryOp = op:UnaryOperator value:*RValueInner;
+OffsetOf = 'offsetof' '(' type_name:Ident ',' field:Ident ')';
+LValue = [prefix:Prefix] name:Ident {derefs:DeReference};
+
+DeReference = @:FieldAccess | @:ArrayIndex;
+
+FieldAccess = '.' name:Ident;
+ArrayIndex = '[' element:*RValue ']';
+
+@string
+@no_skip_ws
+Immediate = ['-'] (('0x' | '0X') {'0'..'9' | 'a'..'f' | 'A'..'F'}+ | ('0b' | '0B') {'0'..'1'}+ | {'0'..'9'}+ ['.' {'0'..'9'}+]);
+
+@string
+@no_skip_ws
+StringLiteral = '\"' {'\\\\' char | !'\"' char} '\"';
+
+Comparator = @:Equals | @:NotEquals | @:LessOrEqual | @:GreaterOrEqual | @:LessThan | @:GreaterThan;
+Equals = '==';
+NotEquals = '!=';
+LessThan = '<';
+GreaterThan = '>';
+LessOrEqual = '<=';
+GreaterOrEqual = '>=';
+ReferencePrefix = '&' [is_mut:MutKeyword];
+DeReferencePrefix = '*';
+
+Operation = @:Plus | @:Minus | @:Times | @:LeftShift | @:RightShift | @:And | @:Or | @:Xor;
+Plus = '+';
+Minus = '-';
+Times = '*';
+LeftShift = '<<';
+RightShift = '>>';
+And = '&' !'&';
+Or = '|' !'|';
+Xor = '^';
+
+UnaryOperator = @:BitNot | @:LogicalNot;
+BitNot = '~';
+LogicalNot = '!';
+
+Prefix = @:ReferencePrefix | @:DeReferencePrefix;
+
+@string
+@no_skip_ws
+Ident = {IdentChar}+;
+
+@no_skip_ws
+IdentChar = 'a'..'z' | 'A'..'Z' | '_' | '0'..'9';
+
+@no_skip_ws
+Keyword = ('return' | 'if' | 'while' | 'break' | 'continue') !IdentChar;
+
+@no_skip_ws
+MutKeyword = 'mut' !IdentChar;
+
+@string
+@no_skip_ws
+NewLine = {'\r' | '\n' | '\r\n'};
+"
+);
+
+/// Returns the script with its `//` line comments and `/* */` block comments
+/// blanked out. Comment characters are replaced by spaces, keeping newlines,
+/// so positions reported by the parser still match the original script.
+/// Comment markers inside string literals are left alone.
+///
+/// # Arguments
+///
+/// * `script_text` - The script text.
+fn strip_comments(script_text: &str) -> String {
+    let mut stripped = String::with_capacity(script_text.len());
+    let mut chars = script_text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            stripped.push(c);
+            match c {
+                '\\' => stripped.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                stripped.push(c);
+            }
+            ('/', Some('/')) => {
+                stripped.push(' ');
+                while let Some(c) = chars.next_if(|c| *c != '\n') {
+                    stripped.push_str(&" ".repeat(c.len_utf8()));
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                stripped.push_str("  ");
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    match c {
+                        '\n' => stripped.push('\n'),
+                        c => stripped.push_str(&" ".repeat(c.len_utf8())),
+                    }
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            _ => stripped.push(c),
+        }
+    }
+
+    stripped
+}
+
+/// Parses a script into its syntax tree without compiling it, e.g. for tools
+/// that inspect scripts. Comments are ignored.
+///
+/// # Arguments
+///
+/// * `script_text` - The script to parse, as a string.
+///
+/// # Example
+/// ```
+/// use bpf_script::compiler::{parse, Expression};
+///
+/// let ast = parse("fn(a: u32)\n return a").expect("Failed to parse.");
+/// assert_eq!(ast.input.args[0].name, "a");
+/// assert!(matches!(ast.exprs[0], Expression::Return(_)));
+/// ```
+pub fn parse(script_text: &str) -> InternalResult<ScriptDef> {
+    Ok(ScriptDef::parse(&strip_comments(script_text))?)
+}
@@ -1,13 +1,31 @@
 use bpf_ins::MemoryOpLoadType;
+use std::collections::HashMap;
+
+/// A table of helpers that aren't part of the built-in Linux set, mapping each
+/// helper's name to its numeric id and its argument load types.
+pub type HelperTable = HashMap<String, (u32, [MemoryOpLoadType; 5])>;
+
+/// Whether a helper reads from or writes to the memory passed in an argument.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgDirection {
+    /// The helper reads the argument (or it's passed by value).
+    In,
+
+    /// The helper writes to the memory the argument points to.
+    Out,
+}
 
 /// Enum for BPF helper function IDs.
-#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Helpers {
     MapLookupElem = 1,
     MapUpdateElem = 2,
     MapDeleteElem = 3,
     ProbeRead = 4,
+    KtimeGetNs = 5,
     TracePrintk = 6,
+    GetPrandomU32 = 7,
+    GetSmpProcessorId = 8,
     SkbStoreBytes = 9,
     L3CsumReplace = 10,
     L4CsumReplace = 11,
@@ -24,6 +42,7 @@ pub enum Helpers {
     PerfEventOutput = 25,
     SkbLoadBytes = 26,
     GetStackid = 27,
+    CsumDiff = 28,
     SkbGetTunnelOpt = 29,
     SkbSetTunnelOpt = 30,
     SkbChangeProto = 31,
@@ -99,9 +118,14 @@ pub enum Helpers {
     GetNsCurrentPidTgid = 120,
     XdpOutput = 121,
     SkAssign = 124,
+    KtimeGetBootNs = 125,
     SeqPrintf = 126,
     SeqWrite = 127,
     RingbufOutput = 130,
+    RingbufReserve = 131,
+    RingbufSubmit = 132,
+    RingbufDiscard = 133,
+    RingbufQuery = 134,
     CsumLevel = 135,
     GetTaskStack = 141,
     LoadHdrOpt = 142,
@@ -119,11 +143,145 @@ pub enum Helpers {
     CheckMtu = 163,
     ForEachMapElem = 164,
     Snprintf = 165,
+    GetFuncArg = 183,
+    GetFuncRet = 184,
 }
 
+/// Table of every helper alongside the C name of the helper without the `bpf_` prefix.
+/// Both name lookup and enumeration are driven from this table.
+static HELPER_NAMES: [(&str, Helpers); 127] = [
+    ("map_lookup_elem", Helpers::MapLookupElem),
+    ("map_update_elem", Helpers::MapUpdateElem),
+    ("map_delete_elem", Helpers::MapDeleteElem),
+    ("probe_read", Helpers::ProbeRead),
+    ("ktime_get_ns", Helpers::KtimeGetNs),
+    ("trace_printk", Helpers::TracePrintk),
+    ("get_prandom_u32", Helpers::GetPrandomU32),
+    ("get_smp_processor_id", Helpers::GetSmpProcessorId),
+    ("skb_store_bytes", Helpers::SkbStoreBytes),
+    ("l3_csum_replace", Helpers::L3CsumReplace),
+    ("l4_csum_replace", Helpers::L4CsumReplace),
+    ("tail_call", Helpers::TailCall),
+    ("clone_redirect", Helpers::CloneRedirect),
+    ("get_current_pid_tgid", Helpers::GetCurrentPidTgid),
+    ("get_current_uid_gid", Helpers::GetCurrentUidGid),
+    ("get_current_comm", Helpers::GetCurrentComm),
+    ("skb_vlan_push", Helpers::SkbVlanPush),
+    ("skb_vlan_pop", Helpers::SkbVlanPop),
+    ("skb_get_tunnel_key", Helpers::SkbGetTunnelKey),
+    ("skb_set_tunnel_key", Helpers::SkbSetTunnelKey),
+    ("redirect", Helpers::Redirect),
+    ("perf_event_output", Helpers::PerfEventOutput),
+    ("skb_load_bytes", Helpers::SkbLoadBytes),
+    ("get_stackid", Helpers::GetStackid),
+    ("csum_diff", Helpers::CsumDiff),
+    ("skb_get_tunnel_opt", Helpers::SkbGetTunnelOpt),
+    ("skb_set_tunnel_opt", Helpers::SkbSetTunnelOpt),
+    ("skb_change_proto", Helpers::SkbChangeProto),
+    ("skb_change_type", Helpers::SkbChangeType),
+    ("skb_under_cgroup", Helpers::SkbUnderCgroup),
+    ("probe_write_user", Helpers::ProbeWriteUser),
+    ("current_task_under_cgroup", Helpers::CurrentTaskUnderCgroup),
+    ("skb_change_tail", Helpers::SkbChangeTail),
+    ("skb_pull_data", Helpers::SkbPullData),
+    ("get_numa_node_id", Helpers::GetNumaNodeId),
+    ("skb_change_head", Helpers::SkbChangeHead),
+    ("xdp_adjust_head", Helpers::XdpAdjustHead),
+    ("probe_read_str", Helpers::ProbeReadStr),
+    ("set_hash", Helpers::SetHash),
+    ("setsockopt", Helpers::Setsockopt),
+    ("skb_adjust_room", Helpers::SkbAdjustRoom),
+    ("redirect_map", Helpers::RedirectMap),
+    ("sk_redirect_map", Helpers::SkRedirectMap),
+    ("sock_map_update", Helpers::SockMapUpdate),
+    ("xdp_adjust_meta", Helpers::XdpAdjustMeta),
+    ("perf_event_read_value", Helpers::PerfEventReadValue),
+    ("perf_prog_read_value", Helpers::PerfProgReadValue),
+    ("getsockopt", Helpers::Getsockopt),
+    ("override_return", Helpers::OverrideReturn),
+    ("sock_ops_cb_flags_set", Helpers::SockOpsCbFlagsSet),
+    ("msg_redirect_map", Helpers::MsgRedirectMap),
+    ("msg_apply_bytes", Helpers::MsgApplyBytes),
+    ("msg_cork_bytes", Helpers::MsgCorkBytes),
+    ("msg_pull_data", Helpers::MsgPullData),
+    ("bind", Helpers::Bind),
+    ("xdp_adjust_tail", Helpers::XdpAdjustTail),
+    ("skb_get_xfrm_state", Helpers::SkbGetXfrmState),
+    ("get_stack", Helpers::GetStack),
+    ("skb_load_bytes_relative", Helpers::SkbLoadBytesRelative),
+    ("fib_lookup", Helpers::FibLookup),
+    ("sock_hash_update", Helpers::SockHashUpdate),
+    ("msg_redirect_hash", Helpers::MsgRedirectHash),
+    ("sk_redirect_hash", Helpers::SkRedirectHash),
+    ("lwt_push_encap", Helpers::LwtPushEncap),
+    ("lwt_seg6_store_bytes", Helpers::LwtSeg6StoreBytes),
+    ("lwt_seg6_adjust_srh", Helpers::LwtSeg6AdjustSrh),
+    ("lwt_seg6_action", Helpers::LwtSeg6Action),
+    ("rc_repeat", Helpers::RcRepeat),
+    ("rc_keydown", Helpers::RcKeydown),
+    ("sk_select_reuseport", Helpers::SkSelectReuseport),
+    ("sk_release", Helpers::SkRelease),
+    ("map_push_elem", Helpers::MapPushElem),
+    ("map_pop_elem", Helpers::MapPopElem),
+    ("map_peek_elem", Helpers::MapPeekElem),
+    ("msg_push_data", Helpers::MsgPushData),
+    ("msg_pop_data", Helpers::MsgPopData),
+    ("rc_pointer_rel", Helpers::RcPointerRel),
+    ("spin_lock", Helpers::SpinLock),
+    ("spin_unlock", Helpers::SpinUnlock),
+    ("skb_ecn_set_ce", Helpers::SkbEcnSetCe),
+    ("tcp_check_syncookie", Helpers::TcpCheckSyncookie),
+    ("sysctl_get_name", Helpers::SysctlGetName),
+    ("sysctl_get_current_value", Helpers::SysctlGetCurrentValue),
+    ("sysctl_get_new_value", Helpers::SysctlGetNewValue),
+    ("sysctl_set_new_value", Helpers::SysctlSetNewValue),
+    ("strtol", Helpers::Strtol),
+    ("strtoul", Helpers::Strtoul),
+    ("sk_storage_delete", Helpers::SkStorageDelete),
+    ("send_signal", Helpers::SendSignal),
+    ("skb_output", Helpers::SkbOutput),
+    ("probe_read_user", Helpers::ProbeReadUser),
+    ("probe_read_kernel", Helpers::ProbeReadKernel),
+    ("probe_read_user_str", Helpers::ProbeReadUserStr),
+    ("probe_read_kernel_str", Helpers::ProbeReadKernelStr),
+    ("tcp_send_ack", Helpers::TcpSendAck),
+    ("send_signal_thread", Helpers::SendSignalThread),
+    ("read_branch_records", Helpers::ReadBranchRecords),
+    ("get_ns_current_pid_tgid", Helpers::GetNsCurrentPidTgid),
+    ("xdp_output", Helpers::XdpOutput),
+    ("sk_assign", Helpers::SkAssign),
+    ("ktime_get_boot_ns", Helpers::KtimeGetBootNs),
+    ("seq_printf", Helpers::SeqPrintf),
+    ("seq_write", Helpers::SeqWrite),
+    ("ringbuf_output", Helpers::RingbufOutput),
+    ("ringbuf_reserve", Helpers::RingbufReserve),
+    ("ringbuf_submit", Helpers::RingbufSubmit),
+    ("ringbuf_discard", Helpers::RingbufDiscard),
+    ("ringbuf_query", Helpers::RingbufQuery),
+    ("csum_level", Helpers::CsumLevel),
+    ("get_task_stack", Helpers::GetTaskStack),
+    ("load_hdr_opt", Helpers::LoadHdrOpt),
+    ("store_hdr_opt", Helpers::StoreHdrOpt),
+    ("reserve_hdr_opt", Helpers::ReserveHdrOpt),
+    ("d_path", Helpers::DPath),
+    ("copy_from_user", Helpers::CopyFromUser),
+    ("snprintf_btf", Helpers::SnprintfBtf),
+    ("seq_printf_btf", Helpers::SeqPrintfBtf),
+    ("redirect_neigh", Helpers::RedirectNeigh),
+    ("redirect_peer", Helpers::RedirectPeer),
+    ("task_storage_delete", Helpers::TaskStorageDelete),
+    ("bprm_opts_set", Helpers::BprmOptsSet),
+    ("ima_inode_hash", Helpers::ImaInodeHash),
+    ("check_mtu", Helpers::CheckMtu),
+    ("for_each_map_elem", Helpers::ForEachMapElem),
+    ("snprintf", Helpers::Snprintf),
+    ("get_func_arg", Helpers::GetFuncArg),
+    ("get_func_ret", Helpers::GetFuncRet),
+];
+
 impl Helpers {
     /// Returns the argument types for a given helper function.
-    pub fn get_arg_types(&self) -> &[MemoryOpLoadType] {
+    pub fn get_arg_types(&self) -> &'static [MemoryOpLoadType] {
         match self {
             Helpers::MapLookupElem => &[
                 MemoryOpLoadType::Map,
@@ -174,6 +332,13 @@ impl Helpers {
                 MemoryOpLoadType::Void,
                 MemoryOpLoadType::Void,
             ],
+            Helpers::RingbufReserve | Helpers::RingbufQuery => &[
+                MemoryOpLoadType::Map,
+                MemoryOpLoadType::Void,
+                MemoryOpLoadType::Void,
+                MemoryOpLoadType::Void,
+                MemoryOpLoadType::Void,
+            ],
             _ => &[
                 MemoryOpLoadType::Void,
                 MemoryOpLoadType::Void,
@@ -184,244 +349,114 @@ impl Helpers {
         }
     }
 
+    /// Returns the direction of each argument for a given helper function. Arguments
+    /// pointing to memory the helper fills in are `Out`, everything else is `In`.
+    pub fn get_arg_directions(&self) -> &'static [ArgDirection] {
+        use ArgDirection::{In, Out};
+
+        match self {
+            Helpers::ProbeRead
+            | Helpers::ProbeReadStr
+            | Helpers::ProbeReadUser
+            | Helpers::ProbeReadKernel
+            | Helpers::ProbeReadUserStr
+            | Helpers::ProbeReadKernelStr
+            | Helpers::GetCurrentComm
+            | Helpers::CopyFromUser
+            | Helpers::Snprintf => &[Out, In, In, In, In],
+            Helpers::MapPopElem
+            | Helpers::MapPeekElem
+            | Helpers::GetStack
+            | Helpers::GetTaskStack
+            | Helpers::PerfProgReadValue
+            | Helpers::DPath
+            | Helpers::SysctlGetName
+            | Helpers::SysctlGetCurrentValue
+            | Helpers::SysctlGetNewValue
+            | Helpers::SkbGetTunnelKey
+            | Helpers::SkbGetTunnelOpt
+            | Helpers::ReadBranchRecords
+            | Helpers::GetFuncRet => &[In, Out, In, In, In],
+            Helpers::PerfEventReadValue
+            | Helpers::SkbLoadBytes
+            | Helpers::SkbLoadBytesRelative
+            | Helpers::GetNsCurrentPidTgid
+            | Helpers::GetFuncArg => &[In, In, Out, In, In],
+            Helpers::Getsockopt | Helpers::Strtol | Helpers::Strtoul => &[In, In, In, Out, In],
+            _ => &[In, In, In, In, In],
+        }
+    }
+
+    /// Returns the kind of value the helper returns: `MapValue` for helpers that
+    /// return a pointer to a map value, `Void` for helpers that return a plain
+    /// scalar.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_ins::MemoryOpLoadType;
+    /// use bpf_script::compiler::Helpers;
+    ///
+    /// assert!(matches!(
+    ///     Helpers::MapLookupElem.get_return_type(),
+    ///     MemoryOpLoadType::MapValue
+    /// ));
+    /// ```
+    pub fn get_return_type(&self) -> MemoryOpLoadType {
+        match self {
+            Helpers::MapLookupElem => MemoryOpLoadType::MapValue,
+            _ => MemoryOpLoadType::Void,
+        }
+    }
+
+    /// Returns true if the helper returns a signed value, which is the case for
+    /// every helper that can fail with a negative error code. Only helpers that
+    /// return plain ids, timestamps, random numbers or pointers are unsigned.
+    pub fn is_return_signed(&self) -> bool {
+        !matches!(
+            self,
+            Helpers::MapLookupElem
+                | Helpers::RingbufReserve
+                | Helpers::RingbufQuery
+                | Helpers::GetCurrentPidTgid
+                | Helpers::GetCurrentUidGid
+                | Helpers::KtimeGetNs
+                | Helpers::KtimeGetBootNs
+                | Helpers::GetPrandomU32
+                | Helpers::GetSmpProcessorId
+        )
+    }
+
     /// Returns a Helper from the string representation of a helper function.
     ///
     /// # Arguments
     ///
     /// * `name` - The C name of the helper without the `bpf_` prefix.
     pub fn from_string(name: &str) -> Option<Self> {
-        Some(if name.eq("map_update_elem") {
-            Helpers::MapUpdateElem
-        } else if name.eq("map_delete_elem") {
-            Helpers::MapDeleteElem
-        } else if name.eq("probe_read") {
-            Helpers::ProbeRead
-        } else if name.eq("trace_printk") {
-            Helpers::TracePrintk
-        } else if name.eq("skb_store_bytes") {
-            Helpers::SkbStoreBytes
-        } else if name.eq("l3_csum_replace") {
-            Helpers::L3CsumReplace
-        } else if name.eq("l4_csum_replace") {
-            Helpers::L4CsumReplace
-        } else if name.eq("tail_call") {
-            Helpers::TailCall
-        } else if name.eq("clone_redirect") {
-            Helpers::CloneRedirect
-        } else if name.eq("get_current_pid_tgid") {
-            Helpers::GetCurrentPidTgid
-        } else if name.eq("get_current_uid_gid") {
-            Helpers::GetCurrentUidGid
-        } else if name.eq("get_current_comm") {
-            Helpers::GetCurrentComm
-        } else if name.eq("skb_vlan_push") {
-            Helpers::SkbVlanPush
-        } else if name.eq("skb_vlan_pop") {
-            Helpers::SkbVlanPop
-        } else if name.eq("skb_get_tunnel_key") {
-            Helpers::SkbGetTunnelKey
-        } else if name.eq("skb_set_tunnel_key") {
-            Helpers::SkbSetTunnelKey
-        } else if name.eq("redirect") {
-            Helpers::Redirect
-        } else if name.eq("perf_event_output") {
-            Helpers::PerfEventOutput
-        } else if name.eq("skb_load_bytes") {
-            Helpers::SkbLoadBytes
-        } else if name.eq("get_stackid") {
-            Helpers::GetStackid
-        } else if name.eq("skb_get_tunnel_opt") {
-            Helpers::SkbGetTunnelOpt
-        } else if name.eq("skb_set_tunnel_opt") {
-            Helpers::SkbSetTunnelOpt
-        } else if name.eq("skb_change_proto") {
-            Helpers::SkbChangeProto
-        } else if name.eq("skb_change_type") {
-            Helpers::SkbChangeType
-        } else if name.eq("skb_under_cgroup") {
-            Helpers::SkbUnderCgroup
-        } else if name.eq("probe_write_user") {
-            Helpers::ProbeWriteUser
-        } else if name.eq("current_task_under_cgroup") {
-            Helpers::CurrentTaskUnderCgroup
-        } else if name.eq("skb_change_tail") {
-            Helpers::SkbChangeTail
-        } else if name.eq("skb_pull_data") {
-            Helpers::SkbPullData
-        } else if name.eq("get_numa_node_id") {
-            Helpers::GetNumaNodeId
-        } else if name.eq("skb_change_head") {
-            Helpers::SkbChangeHead
-        } else if name.eq("xdp_adjust_head") {
-            Helpers::XdpAdjustHead
-        } else if name.eq("probe_read_str") {
-            Helpers::ProbeReadStr
-        } else if name.eq("set_hash") {
-            Helpers::SetHash
-        } else if name.eq("setsockopt") {
-            Helpers::Setsockopt
-        } else if name.eq("skb_adjust_room") {
-            Helpers::SkbAdjustRoom
-        } else if name.eq("redirect_map") {
-            Helpers::RedirectMap
-        } else if name.eq("sk_redirect_map") {
-            Helpers::SkRedirectMap
-        } else if name.eq("sock_map_update") {
-            Helpers::SockMapUpdate
-        } else if name.eq("xdp_adjust_meta") {
-            Helpers::XdpAdjustMeta
-        } else if name.eq("perf_event_read_value") {
-            Helpers::PerfEventReadValue
-        } else if name.eq("perf_prog_read_value") {
-            Helpers::PerfProgReadValue
-        } else if name.eq("getsockopt") {
-            Helpers::Getsockopt
-        } else if name.eq("override_return") {
-            Helpers::OverrideReturn
-        } else if name.eq("sock_ops_cb_flags_set") {
-            Helpers::SockOpsCbFlagsSet
-        } else if name.eq("msg_redirect_map") {
-            Helpers::MsgRedirectMap
-        } else if name.eq("msg_apply_bytes") {
-            Helpers::MsgApplyBytes
-        } else if name.eq("msg_cork_bytes") {
-            Helpers::MsgCorkBytes
-        } else if name.eq("msg_pull_data") {
-            Helpers::MsgPullData
-        } else if name.eq("bind") {
-            Helpers::Bind
-        } else if name.eq("xdp_adjust_tail") {
-            Helpers::XdpAdjustTail
-        } else if name.eq("skb_get_xfrm_state") {
-            Helpers::SkbGetXfrmState
-        } else if name.eq("get_stack") {
-            Helpers::GetStack
-        } else if name.eq("skb_load_bytes_relative") {
-            Helpers::SkbLoadBytesRelative
-        } else if name.eq("fib_lookup") {
-            Helpers::FibLookup
-        } else if name.eq("sock_hash_update") {
-            Helpers::SockHashUpdate
-        } else if name.eq("msg_redirect_hash") {
-            Helpers::MsgRedirectHash
-        } else if name.eq("sk_redirect_hash") {
-            Helpers::SkRedirectHash
-        } else if name.eq("lwt_push_encap") {
-            Helpers::LwtPushEncap
-        } else if name.eq("lwt_seg6_store_bytes") {
-            Helpers::LwtSeg6StoreBytes
-        } else if name.eq("lwt_seg6_adjust_srh") {
-            Helpers::LwtSeg6AdjustSrh
-        } else if name.eq("lwt_seg6_action") {
-            Helpers::LwtSeg6Action
-        } else if name.eq("rc_repeat") {
-            Helpers::RcRepeat
-        } else if name.eq("rc_keydown") {
-            Helpers::RcKeydown
-        } else if name.eq("sk_select_reuseport") {
-            Helpers::SkSelectReuseport
-        } else if name.eq("sk_release") {
-            Helpers::SkRelease
-        } else if name.eq("map_push_elem") {
-            Helpers::MapPushElem
-        } else if name.eq("map_pop_elem") {
-            Helpers::MapPopElem
-        } else if name.eq("map_peek_elem") {
-            Helpers::MapPeekElem
-        } else if name.eq("msg_push_data") {
-            Helpers::MsgPushData
-        } else if name.eq("msg_pop_data") {
-            Helpers::MsgPopData
-        } else if name.eq("rc_pointer_rel") {
-            Helpers::RcPointerRel
-        } else if name.eq("spin_lock") {
-            Helpers::SpinLock
-        } else if name.eq("spin_unlock") {
-            Helpers::SpinUnlock
-        } else if name.eq("skb_ecn_set_ce") {
-            Helpers::SkbEcnSetCe
-        } else if name.eq("tcp_check_syncookie") {
-            Helpers::TcpCheckSyncookie
-        } else if name.eq("sysctl_get_name") {
-            Helpers::SysctlGetName
-        } else if name.eq("sysctl_get_current_value") {
-            Helpers::SysctlGetCurrentValue
-        } else if name.eq("sysctl_get_new_value") {
-            Helpers::SysctlGetNewValue
-        } else if name.eq("sysctl_set_new_value") {
-            Helpers::SysctlSetNewValue
-        } else if name.eq("strtol") {
-            Helpers::Strtol
-        } else if name.eq("strtoul") {
-            Helpers::Strtoul
-        } else if name.eq("sk_storage_delete") {
-            Helpers::SkStorageDelete
-        } else if name.eq("send_signal") {
-            Helpers::SendSignal
-        } else if name.eq("skb_output") {
-            Helpers::SkbOutput
-        } else if name.eq("probe_read_user") {
-            Helpers::ProbeReadUser
-        } else if name.eq("probe_read_kernel") {
-            Helpers::ProbeReadKernel
-        } else if name.eq("probe_read_user_str") {
-            Helpers::ProbeReadUserStr
-        } else if name.eq("probe_read_kernel_str") {
-            Helpers::ProbeReadKernelStr
-        } else if name.eq("tcp_send_ack") {
-            Helpers::TcpSendAck
-        } else if name.eq("send_signal_thread") {
-            Helpers::SendSignalThread
-        } else if name.eq("read_branch_records") {
-            Helpers::ReadBranchRecords
-        } else if name.eq("get_ns_current_pid_tgid") {
-            Helpers::GetNsCurrentPidTgid
-        } else if name.eq("xdp_output") {
-            Helpers::XdpOutput
-        } else if name.eq("sk_assign") {
-            Helpers::SkAssign
-        } else if name.eq("seq_printf") {
-            Helpers::SeqPrintf
-        } else if name.eq("seq_write") {
-            Helpers::SeqWrite
-        } else if name.eq("ringbuf_output") {
-            Helpers::RingbufOutput
-        } else if name.eq("csum_level") {
-            Helpers::CsumLevel
-        } else if name.eq("get_task_stack") {
-            Helpers::GetTaskStack
-        } else if name.eq("load_hdr_opt") {
-            Helpers::LoadHdrOpt
-        } else if name.eq("store_hdr_opt") {
-            Helpers::StoreHdrOpt
-        } else if name.eq("reserve_hdr_opt") {
-            Helpers::ReserveHdrOpt
-        } else if name.eq("d_path") {
-            Helpers::DPath
-        } else if name.eq("copy_from_user") {
-            Helpers::CopyFromUser
-        } else if name.eq("snprintf_btf") {
-            Helpers::SnprintfBtf
-        } else if name.eq("seq_printf_btf") {
-            Helpers::SeqPrintfBtf
-        } else if name.eq("redirect_neigh") {
-            Helpers::RedirectNeigh
-        } else if name.eq("redirect_peer") {
-            Helpers::RedirectPeer
-        } else if name.eq("task_storage_delete") {
-            Helpers::TaskStorageDelete
-        } else if name.eq("bprm_opts_set") {
-            Helpers::BprmOptsSet
-        } else if name.eq("ima_inode_hash") {
-            Helpers::ImaInodeHash
-        } else if name.eq("check_mtu") {
-            Helpers::CheckMtu
-        } else if name.eq("for_each_map_elem") {
-            Helpers::ForEachMapElem
-        } else if name.eq("snprintf") {
-            Helpers::Snprintf
-        } else {
-            return None;
-        })
+        HELPER_NAMES
+            .iter()
+            .find(|(helper_name, _)| *helper_name == name)
+            .map(|(_, helper)| *helper)
+    }
+
+    /// Returns the numeric id of the helper, as passed to the `call` instruction.
+    pub fn id(&self) -> u32 {
+        *self as u32
+    }
+
+    /// Returns every supported helper as a tuple of its name (without the `bpf_`
+    /// prefix), its numeric id and its argument load types.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Helpers;
+    ///
+    /// for (name, id, arg_types) in Helpers::all() {
+    ///     println!("{} (#{}): {:?}", name, id, arg_types);
+    /// }
+    /// ```
+    pub fn all() -> impl Iterator<Item = (&'static str, u32, &'static [MemoryOpLoadType])> {
+        HELPER_NAMES
+            .iter()
+            .map(|(name, helper)| (*name, helper.id(), helper.get_arg_types()))
     }
 }
@@ -0,0 +1,71 @@
+/// Enum for BPF map types, matching the kernel's `bpf_map_type`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapType {
+    Unspec = 0,
+    Hash = 1,
+    Array = 2,
+    ProgArray = 3,
+    PerfEventArray = 4,
+    PerCpuHash = 5,
+    PerCpuArray = 6,
+    StackTrace = 7,
+    CgroupArray = 8,
+    LruHash = 9,
+    LruPerCpuHash = 10,
+    LpmTrie = 11,
+    ArrayOfMaps = 12,
+    HashOfMaps = 13,
+    DevMap = 14,
+    SockMap = 15,
+    CpuMap = 16,
+    XskMap = 17,
+    SockHash = 18,
+    CgroupStorage = 19,
+    ReuseportSockArray = 20,
+    PerCpuCgroupStorage = 21,
+    Queue = 22,
+    Stack = 23,
+    SkStorage = 24,
+    DevMapHash = 25,
+    StructOps = 26,
+    RingBuf = 27,
+    InodeStorage = 28,
+    TaskStorage = 29,
+    BloomFilter = 30,
+}
+
+impl MapType {
+    /// Returns true if the map is indexed by a 32-bit integer rather than an
+    /// arbitrary key.
+    pub fn is_array(&self) -> bool {
+        matches!(
+            self,
+            MapType::Array
+                | MapType::ProgArray
+                | MapType::PerfEventArray
+                | MapType::PerCpuArray
+                | MapType::CgroupArray
+                | MapType::ArrayOfMaps
+        )
+    }
+
+    /// Returns true if the map holds a separate value for each CPU.
+    pub fn is_per_cpu(&self) -> bool {
+        matches!(
+            self,
+            MapType::PerCpuHash
+                | MapType::PerCpuArray
+                | MapType::LruPerCpuHash
+                | MapType::PerCpuCgroupStorage
+        )
+    }
+
+    /// Returns true if the map has no keys at all and can't be used with the
+    /// keyed map helpers (lookup, update, delete).
+    pub fn is_keyless(&self) -> bool {
+        matches!(
+            self,
+            MapType::Queue | MapType::Stack | MapType::RingBuf | MapType::BloomFilter
+        )
+    }
+}
@@ -1,5 +1,11 @@
+pub mod ast;
 mod helpers;
+mod maps;
 mod script;
+mod sink;
 
-use helpers::Helpers;
-pub use script::Compiler;
+pub use ast::{parse, Expression, LValue, ScriptDef};
+pub use helpers::{ArgDirection, HelperTable, Helpers};
+pub use maps::MapType;
+pub use script::{CompileReport, Compiler, LineInfo, RETURN_REGISTER};
+pub use sink::InstructionSink;
@@ -1,112 +1,194 @@
-use crate::compiler::Helpers;
+use crate::compiler::ast::*;
+use crate::compiler::{ArgDirection, HelperTable, Helpers, InstructionSink, MapType};
 use crate::error::{Error, Result as InternalResult, SemanticsErrorContext};
-use crate::optimizer::optimize;
+use crate::optimizer::{optimize, remove_dead_code, retarget_jump};
 use crate::types::*;
 
-use bpf_ins::{ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, Register};
-use peginator::PegParser;
-use peginator_macro::peginate;
-
-use std::collections::HashMap;
+use bpf_ins::{
+    ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, Opcode, OpcodeClass,
+    Register,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Range;
 use std::str::FromStr;
 
-peginate!(
-    "
-@export
-ScriptDef = input:InputLine {NewLine exprs:Expression}$;
+macro_rules! semantics_bail {
+    ($line: expr, $($message:expr),+) => {
+        return Err(Error::Semantics {
+            line: $line,
+            message: format!($($message),+)
+        });
+    };
+}
 
-InputLine = 'fn' '(' [args:TypedArgument {',' args:TypedArgument}] ')';
-TypedArgument = name:Ident ':' type_name:TypeDecl;
-TypeDecl = [is_ref:ReferencePrefix] name:Ident;
+/// The register holding a program's return value when it exits.
+pub const RETURN_REGISTER: Register = Register::R0;
 
-Expression = @:Assignment | @:FunctionCall | @:Return | @:IfStatement;
+/// A summary of a compiled program, returned by `Compiler::compile_with_report`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileReport {
+    /// The compiled instructions.
+    pub instructions: Vec<Instruction>,
 
-Assignment = left:LValue [':' type_name:TypeDecl] '=' right:RValue;
-FunctionCall = name:Ident '(' [args:RValue {',' args:RValue}] ')';
-Return = 'return' [value:RValue];
+    /// The number of instruction slots the kernel sees for the program.
+    pub instruction_count: usize,
 
-Condition = left:RValue WhiteSpace op:Comparator WhiteSpace right:RValue;
-IfStatement = 'if' cond:Condition '{' {exprs:Expression} '}' ['else' '{' {else_exprs:Expression} '}'];
+    /// The most stack space, in bytes, in use at any point of the program.
+    pub stack_size: u32,
 
-RValue = left:RValueInner [op:Operation right:RValueInner];
-RValueInner = @:FunctionCall | @:Immediate | @:LValue;
-LValue = [prefix:Prefix] name:Ident {derefs:DeReference};
+    /// The ids of the helpers the program calls, in ascending order.
+    pub helpers: Vec<u32>,
 
-DeReference = @:FieldAccess | @:ArrayIndex;
+    /// The declared return type of the program, if any.
+    pub return_type: Option<Type>,
 
-FieldAccess = '.' name:Ident;
-ArrayIndex = '[' element:Immediate ']';
+    /// The deepest nesting of conditional blocks in the program.
+    pub max_branch_depth: usize,
 
-@string
-Immediate = ['-'] {'0'..'9'}+;
+    /// The warnings produced while compiling, as (line, message) pairs.
+    pub warnings: Vec<(u32, String)>,
+}
 
-Comparator = @:Equals | @:NotEquals | @:LessThan | @:GreaterThan | @:LessOrEqual | @:GreaterOrEqual;
-Equals = '==';
-NotEquals = '!=';
-LessThan = '<';
-GreaterThan = '>';
-LessOrEqual = '<=';
-GreaterOrEqual = '>=';
-ReferencePrefix = '&';
-DeReferencePrefix = '*';
+/// An entry of a program's line info, mapping instructions to the line of the
+/// script they were compiled from. The layout matches the kernel's
+/// `struct bpf_line_info`; the string table offsets are left at 0 for the
+/// caller to fill in when building the BTF for the program.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LineInfo {
+    /// The instruction slot at which the line starts.
+    pub insn_off: u32,
 
-Operation = @:Plus | @:Minus | @:Times | @:LeftShift | @:RightShift | @:And | @:Or;
-Plus = '+';
-Minus = '-';
-Times = '*';
-LeftShift = '<<';
-RightShift = '>>';
-And = '&';
-Or = '|';
+    /// The offset of the file name in the BTF string table.
+    pub file_name_off: u32,
 
-Prefix = @:ReferencePrefix | @:DeReferencePrefix;
+    /// The offset of the line's source text in the BTF string table.
+    pub line_off: u32,
 
-@string
-@no_skip_ws
-Ident = {'a'..'z' | 'A'..'Z' | '_' | '0'..'9'}+;
+    /// The line number in the upper 22 bits and the column in the lower 10.
+    pub line_col: u32,
+}
 
-@string
-@no_skip_ws
-WhiteSpace = {' ' | '\t'};
+impl LineInfo {
+    /// Returns the line number of the entry.
+    pub fn line(&self) -> u32 {
+        self.line_col >> 10
+    }
+}
 
-@string
-@no_skip_ws
-NewLine = {'\r' | '\n' | '\r\n'};
-"
-);
+/// Integer types that can be parsed from a string in a given base.
+trait FromStrRadix: Sized {
+    fn from_str_radix(s: &str, radix: u32) -> std::result::Result<Self, std::num::ParseIntError>;
+}
 
-macro_rules! semantics_bail {
-    ($line: expr, $($message:expr),+) => {
-        return Err(Error::Semantics {
-            line: $line,
-            message: format!($($message),+)
-        });
+macro_rules! impl_from_str_radix {
+    ($($t:ty),*) => {
+        $(impl FromStrRadix for $t {
+            fn from_str_radix(s: &str, radix: u32) -> std::result::Result<Self, std::num::ParseIntError> {
+                <$t>::from_str_radix(s, radix)
+            }
+        })*
     };
 }
 
+impl_from_str_radix!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+/// Returns the number of single character insertions, deletions and substitutions
+/// needed to turn one string into another.
+///
+/// # Arguments
+///
+/// * `a` - The first string.
+/// * `b` - The second string.
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, a_char) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Returns a "did you mean" hint naming the candidate closest to an unknown name,
+/// or an empty string if none of them is close enough. Internal names, which start
+/// with a `.`, are never suggested.
+///
+/// # Arguments
+///
+/// * `name` - The unknown name.
+/// * `candidates` - The known names.
+fn get_suggestion<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> String {
+    let max_distance = (name.chars().count() / 3).max(1);
+    let closest = candidates
+        .filter(|candidate| !candidate.starts_with('.'))
+        .map(|candidate| (levenshtein_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min();
+
+    match closest {
+        Some((_, candidate)) => format!("; did you mean \"{}\"?", candidate),
+        None => String::new(),
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 enum VariableLocation {
-    SpecialImmediate(u32),
+    SpecialImmediate(i64),
     Stack(i16),
 }
 
+#[derive(Clone, Debug)]
+struct MapInfo {
+    map_type: MapType,
+    value_type: Option<Type>,
+}
+
+#[derive(Clone, Debug)]
+struct LoopInfo {
+    start_index: usize,
+    break_jumps: Vec<usize>,
+}
+
 #[derive(Clone, Debug)]
 struct VariableInfo {
     var_type: Type,
     location: VariableLocation,
+    initialized: bool,
 }
 
-pub struct Compiler<'a> {
+pub struct Compiler<'a, S: InstructionSink = Vec<Instruction>> {
     types: &'a TypeDatabase,
     variables: HashMap<String, VariableInfo>,
-    instructions: Vec<Instruction>,
+    instructions: S,
     stack: u32,
     expr_num: u32,
+    return_type: Option<Type>,
+    warnings: Vec<(u32, String)>,
+    maps: HashMap<String, MapInfo>,
+    optimized_len: usize,
+    custom_helpers: HelperTable,
+    builtin_helpers: bool,
+    default_return: i64,
+    branch_depth: usize,
+    max_branch_depth: usize,
+    max_stack: u32,
+    stack_limit: u32,
+    helpers_used: BTreeSet<u32>,
+    instruction_lines: Vec<u32>,
+    loop_limit: Option<u32>,
+    loops: Vec<LoopInfo>,
+    volatile_ranges: Vec<Range<usize>>,
 }
 
 impl<'a> Compiler<'a> {
-    const MAX_STACK_SIZE: u32 = 4096;
-
     /// Create a new compiler instance.
     ///
     /// # Arguments
@@ -122,1016 +204,4475 @@ impl<'a> Compiler<'a> {
     /// let mut compiler = Compiler::create(&database);
     /// ```
     pub fn create(types: &'a TypeDatabase) -> Self {
+        Self::with_sink(types, vec![])
+    }
+
+    /// Create a new compiler instance whose scripts can only call the helpers in
+    /// the given table; the built-in Linux helpers aren't available. This is
+    /// useful for targets with their own helper numbering, e.g. a userspace VM.
+    ///
+    /// # Arguments
+    ///
+    /// * `types` - The BTF type library to use when resolving types.
+    /// * `helper_table` - The helpers available to scripts.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_ins::MemoryOpLoadType;
+    /// use bpf_script::compiler::{Compiler, HelperTable};
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut helpers = HelperTable::new();
+    /// helpers.insert("vm_log".to_string(), (1, [MemoryOpLoadType::Void; 5]));
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::with_helpers(&database, helpers);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         vm_log(42)
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn with_helpers(types: &'a TypeDatabase, helper_table: HelperTable) -> Self {
+        let mut compiler = Self::create(types);
+        compiler.custom_helpers = helper_table;
+        compiler.builtin_helpers = false;
+        compiler
+    }
+
+    /// Compiles a script and returns its bytecode, for when the compiler isn't
+    /// needed afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `types` - The BTF type library to use when resolving types.
+    /// * `script_text` - The script to compile, as a string.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let bytecode = Compiler::compile_to_bytecode(&database, "fn(a: u32)\n return a")
+    ///     .expect("Failed to compile.");
+    /// assert!(!bytecode.is_empty());
+    /// ```
+    pub fn compile_to_bytecode(
+        types: &'a TypeDatabase,
+        script_text: &str,
+    ) -> InternalResult<Vec<u64>> {
+        let mut compiler = Self::create(types);
+        compiler.compile(script_text)?;
+        Ok(compiler.get_bytecode())
+    }
+
+    /// Compiles a script and returns its instructions, for when the compiler isn't
+    /// needed afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `types` - The BTF type library to use when resolving types.
+    /// * `script_text` - The script to compile, as a string.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let instructions = Compiler::compile_to_instructions(&database, "fn(a: u32)\n return a")
+    ///     .expect("Failed to compile.");
+    /// assert_eq!(instructions.last(), Some(&bpf_ins::Instruction::exit()));
+    /// ```
+    pub fn compile_to_instructions(
+        types: &'a TypeDatabase,
+        script_text: &str,
+    ) -> InternalResult<Vec<Instruction>> {
+        let mut compiler = Self::create(types);
+        compiler.compile(script_text)?;
+        Ok(compiler.get_instructions().to_vec())
+    }
+
+    /// Returns the internally held instructions after `compile` has been called,
+    /// so they can be rewritten before being encoded by `get_bytecode`. Jump
+    /// offsets are relative to the jump, so inserting or removing instructions
+    /// between a jump and its target breaks the program unless the offset is
+    /// adjusted. The line map returned by `get_line_map` isn't updated either.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_ins::{Instruction, Register};
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return 1
+    /// "#).expect("Failed to compile.");
+    /// compiler.get_instructions_mut()[0] = Instruction::mov64(Register::R0, 2);
+    /// assert_eq!(compiler.get_instructions()[0], Instruction::mov64(Register::R0, 2));
+    /// ```
+    pub fn get_instructions_mut(&mut self) -> &mut Vec<Instruction> {
+        &mut self.instructions
+    }
+}
+
+impl<'a, S: InstructionSink> Compiler<'a, S> {
+    const MAX_STACK_SIZE: u32 = 4096;
+    const DEFAULT_STACK_SIZE: u32 = 512;
+
+    /// Create a new compiler instance that emits instructions into a custom sink.
+    ///
+    /// # Arguments
+    ///
+    /// * `types` - The BTF type library to use when resolving types.
+    /// * `sink` - The sink receiving emitted instructions.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_ins::Instruction;
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let sink: Vec<Instruction> = vec![];
+    /// let mut compiler = Compiler::with_sink(&database, sink);
+    /// ```
+    pub fn with_sink(types: &'a TypeDatabase, sink: S) -> Self {
         Self {
             types,
             variables: HashMap::new(),
-            instructions: vec![],
+            instructions: sink,
             stack: 0,
             expr_num: 1,
+            return_type: None,
+            warnings: vec![],
+            maps: HashMap::new(),
+            optimized_len: 0,
+            custom_helpers: HashMap::new(),
+            builtin_helpers: true,
+            default_return: 0,
+            branch_depth: 0,
+            max_branch_depth: 0,
+            max_stack: 0,
+            stack_limit: Self::DEFAULT_STACK_SIZE,
+            helpers_used: BTreeSet::new(),
+            instruction_lines: vec![],
+            loop_limit: None,
+            loops: vec![],
+            volatile_ranges: vec![],
         }
     }
 
-    /// Used to capture variables from the outer scope into the BPF
-    /// program being compiled. This is mostly used to capture map
-    /// identifers to pass to BPF helpers and for other integer values
-    /// that need to be captured. In the future, this will be extended
-    /// to capture arbitrary types making sharing between Rust and BPF
-    /// more seamless.
+    /// Registers a helper that isn't one of the built-in Linux helpers, e.g. a
+    /// helper from a newer kernel. Registered helpers take precedence over
+    /// built-in helpers with the same name. If the type database has a function
+    /// prototype with the same name, e.g. from BTF, calls are checked against it.
     ///
     /// # Arguments
     ///
-    /// `name` - The name of the variable when referenced from the script.
-    /// `value` - The value of the variable.
+    /// * `name` - The name scripts call the helper by.
+    /// * `id` - The id of the helper.
+    /// * `arg_types` - The load types of the helper's arguments.
     ///
     /// # Example
     /// ```
+    /// use bpf_ins::MemoryOpLoadType;
     /// use bpf_script::compiler::Compiler;
     /// use bpf_script::types::TypeDatabase;
     ///
     /// let mut database = TypeDatabase::default();
     /// let mut compiler = Compiler::create(&database);
-    /// compiler.capture("outer", 0xdeadbeef);
+    /// compiler.register_helper("my_kfunc", 200, [MemoryOpLoadType::Void; 5]);
     /// compiler.compile(r#"
     ///     fn()
-    ///         return outer
+    ///         my_kfunc(1)
     /// "#).expect("Failed to compile.");
     /// ```
-    pub fn capture(&mut self, name: &str, value: i64) {
-        let info = VariableInfo {
-            var_type: BaseType::Integer(Integer {
-                used_bits: 64,
-                bits: 64,
-                is_signed: false,
-            })
-            .into(),
-            location: VariableLocation::SpecialImmediate(value as u32),
-        };
-        self.variables.insert(name.to_string(), info);
+    pub fn register_helper(&mut self, name: &str, id: u32, arg_types: [MemoryOpLoadType; 5]) {
+        self.custom_helpers
+            .insert(name.to_string(), (id, arg_types));
     }
 
-    /// Helper function for resolving a type by `TypeDecl` and printing an error
-    /// with line information, if it's not found.
+    /// Sets the value returned by programs that don't end with a return
+    /// statement, and by a `return` without a value. Defaults to 0.
     ///
     /// # Arguments
     ///
-    /// * `decl` - The type declaration from the parsed ast.
-    fn type_from_decl(&mut self, decl: &TypeDecl) -> InternalResult<Type> {
-        let mut ty = self
-            .types
-            .get_type_by_name(&decl.name)
-            .context(
-                self.expr_num,
-                &format!("Type with name \"{}\" doesn't exist", decl.name),
-            )?
-            .clone();
-
-        if matches!(decl.is_ref, Some(ReferencePrefix)) {
-            ty.num_refs += 1;
-        }
-        Ok(ty)
+    /// * `value` - The default return value.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.set_default_return(1);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         pid_tgid = get_current_pid_tgid()
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_default_return(&mut self, value: i64) {
+        self.default_return = value;
     }
 
-    /// Helper function for finding a scoped variable by name and printing an error
-    /// with line information, if it's not found.
+    /// Limits the number of iterations of each while loop. When set, every loop
+    /// keeps a hidden counter on the stack and ends once it reaches the limit,
+    /// which lets the verifier prove the loop terminates. Without a limit, the
+    /// default, loops are left to the verifier, which accepts bounded loops on
+    /// kernel 5.3 and later.
     ///
     /// # Arguments
     ///
-    /// * `name` - The name of the variable to retrieve.
-    fn get_variable_by_name(&mut self, name: &str) -> InternalResult<VariableInfo> {
-        if let Some(info) = self.variables.get(name) {
-            return Ok(info.clone());
-        }
-
-        semantics_bail!(self.expr_num, "No variable with name \"{}\"", name);
+    /// * `iterations` - The maximum number of iterations, if any.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u64"), 8, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.set_loop_limit(Some(16));
+    /// compiler.compile(r#"
+    ///     fn(a: u64)
+    ///         while a > 0 {
+    ///             a = a - 1
+    ///         }
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_loop_limit(&mut self, iterations: Option<u32>) {
+        self.loop_limit = iterations;
     }
 
-    /// Helper function for parsing an immediate value and printin an error with line
-    /// information, if it's not found.
+    /// Sets the number of bytes of stack the program may use. Defaults to 512,
+    /// the limit the kernel enforces on BPF programs; larger limits are only
+    /// useful for targets with a bigger stack, e.g. a userspace VM. Fails if the
+    /// limit is above 4096 bytes or below the stack already in use.
     ///
     /// # Arguments
     ///
-    /// * `s` - The string representation of the immediate value.
-    fn parse_immediate<T: FromStr>(&mut self, s: &str) -> InternalResult<T> {
-        if let Ok(imm) = s.parse::<T>() {
-            return Ok(imm);
+    /// * `bytes` - The maximum stack size in bytes.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u8"), 1, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.set_max_stack(1024).expect("Failed to set stack size.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         buffer: [u8; 768] = 0
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_max_stack(&mut self, bytes: u32) -> InternalResult<()> {
+        if bytes > Self::MAX_STACK_SIZE {
+            semantics_bail!(
+                self.expr_num,
+                "The stack size can't exceed {} bytes",
+                Self::MAX_STACK_SIZE
+            );
         }
 
-        semantics_bail!(self.expr_num, "Failed to parse immediate value \"{}\"", s);
-    }
+        if bytes < self.stack {
+            semantics_bail!(
+                self.expr_num,
+                "The stack size can't be less than the {} bytes already in use",
+                self.stack
+            );
+        }
 
-    /// Get the current stack offset.
-    fn get_stack(&self) -> i16 {
-        -(self.stack as i16)
+        self.stack_limit = bytes;
+        Ok(())
     }
 
-    /// Push the stack value by a given size and return the new offset. Verifies the
-    /// new location doesn't overflow the stack and returns and error with line information,
-    /// if it does.
+    /// Reserves the top `bytes` of the stack so that the compiler's own stack
+    /// allocations start below them. This is useful when the compiled program
+    /// is appended to hand-written instructions that already use the stack.
+    /// Must be called before anything else is placed on the stack.
     ///
     /// # Arguments
     ///
-    /// * `size` - The number of bytes to push the stack.
-    fn push_stack(&mut self, size: u32) -> InternalResult<i16> {
-        if self.stack + size > Self::MAX_STACK_SIZE {
+    /// * `bytes` - The number of bytes reserved at the top of the stack.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.set_initial_stack(16).expect("Failed to reserve stack.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         pid_tgid = get_current_pid_tgid()
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn set_initial_stack(&mut self, bytes: u32) -> InternalResult<()> {
+        if self.stack != 0 {
             semantics_bail!(
                 self.expr_num,
-                "Stack size exceeded {} bytes with this assignment",
-                Self::MAX_STACK_SIZE
+                "The initial stack must be set before anything is placed on the stack"
             );
         }
 
-        self.stack += size;
-        Ok(self.get_stack())
+        self.push_stack(bytes)?;
+        Ok(())
     }
 
-    /// Emits instructions to initialize a portion of the stack, works like an
-    /// abstract memset.
+    /// Used to capture variables from the outer scope into the BPF
+    /// program being compiled. This is mostly used to capture map
+    /// identifers to pass to BPF helpers and for other integer values
+    /// that need to be captured. In the future, this will be extended
+    /// to capture arbitrary types making sharing between Rust and BPF
+    /// more seamless.
     ///
     /// # Arguments
     ///
-    /// * `offset` - The offset to begin initializing.
-    /// * `value` - The value to initialize _each byte_.
-    /// * `size` - The number of bytes to initialize.
-    fn emit_init_stack_range(&mut self, mut offset: i16, value: i8, mut size: u32) {
-        let value = value as i64;
-        let v64 = value
-            | value << 8
-            | value << 16
-            | value << 24
-            | value << 32
-            | value << 40
-            | value << 48
-            | value << 56;
-        let mut remaining = size;
-        for _ in 0..size / 8 {
-            self.instructions
+    /// `name` - The name of the variable when referenced from the script.
+    /// `value` - The value of the variable.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.capture("outer", 0xdeadbeef);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return outer
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture(&mut self, name: &str, value: i64) {
+        let info = VariableInfo {
+            var_type: BaseType::Integer(Integer {
+                used_bits: 64,
+                bits: 64,
+                is_signed: false,
+            })
+            .into(),
+            location: VariableLocation::SpecialImmediate(value),
+            initialized: true,
+        };
+        self.variables.insert(name.to_string(), info);
+    }
+
+    /// Captures a value like `capture` does, but gives it the named type instead
+    /// of a 64-bit unsigned integer. Fails if the type isn't an integer or enum,
+    /// or if the value doesn't fit in it. Captures are loaded with a 64-bit
+    /// immediate load (`loadtype`), so values wider than 32 bits aren't
+    /// truncated.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the variable when referenced from the script.
+    /// `value` - The value of the variable.
+    /// `type_name` - The name of the variable's type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// i32::add_to_database(&mut database).expect("Failed to add type.");
+    ///
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler
+    ///     .capture_typed("offset", -8, "i32")
+    ///     .expect("Failed to capture value.");
+    /// assert!(compiler.capture_typed("big", 1 << 40, "i32").is_err());
+    /// ```
+    pub fn capture_typed(&mut self, name: &str, value: i64, type_name: &str) -> InternalResult<()> {
+        let var_type = self
+            .types
+            .get_type_by_name(type_name)
+            .ok_or(Error::InvalidTypeName)?
+            .clone();
+
+        let (bits, is_signed) = match &var_type.base_type {
+            BaseType::Integer(integer) if !var_type.is_pointer() => {
+                (integer.bits, integer.is_signed)
+            }
+            BaseType::Enum(_) if !var_type.is_pointer() => (var_type.get_size() * 8, true),
+            _ => {
+                semantics_bail!(
+                    self.expr_num,
+                    "Capture \"{}\" must have an integer type, not \"{}\"",
+                    name,
+                    type_name
+                );
+            }
+        };
+
+        let fits = match (bits, is_signed) {
+            (64.., _) => true,
+            (bits, true) => (-(1 << (bits - 1))..(1 << (bits - 1))).contains(&value),
+            (bits, false) => (0..(1 << bits)).contains(&value),
+        };
+        if !fits {
+            semantics_bail!(
+                self.expr_num,
+                "Capture \"{}\" value {} doesn't fit in \"{}\"",
+                name,
+                value,
+                type_name
+            );
+        }
+
+        let info = VariableInfo {
+            var_type,
+            location: VariableLocation::SpecialImmediate(value),
+            initialized: true,
+        };
+        self.variables.insert(name.to_string(), info);
+
+        Ok(())
+    }
+
+    /// Captures a map identifier like `capture` does, but also records the
+    /// type of map so that calls to the map helpers can be validated.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the map when referenced from the script.
+    /// `value` - The map identifier.
+    /// `map_type` - The type of the map.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::{Compiler, MapType};
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u32::add_to_database(&mut database).expect("Failed to add type.");
+    ///
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.capture_map("counts", 3, MapType::Array);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         index: u32 = 0
+    ///         return map_lookup_elem(counts, &index)
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_map(&mut self, name: &str, value: i64, map_type: MapType) {
+        self.capture(name, value);
+        self.maps.insert(
+            name.to_string(),
+            MapInfo {
+                map_type,
+                value_type: None,
+            },
+        );
+    }
+
+    /// Captures a map identifier like `capture_map` does, but also records the
+    /// type of the map's values. This allows `map_get(map, &key)` to be used in
+    /// the script, which looks up the key and copies the value into a variable of
+    /// that type, or zeroes the variable if the key isn't present.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the map when referenced from the script.
+    /// `value` - The map identifier.
+    /// `map_type` - The type of the map.
+    /// `value_type` - The name of the type of the map's values.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::{Compiler, MapType};
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// u64::add_to_database(&mut database).expect("Failed to add type.");
+    ///
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler
+    ///     .capture_map_with_value_type("counts", 3, MapType::Array, "u64")
+    ///     .expect("Failed to capture map.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         index: u32 = 0
+    ///         count = map_get(counts, &index)
+    ///         return count
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_map_with_value_type(
+        &mut self,
+        name: &str,
+        value: i64,
+        map_type: MapType,
+        value_type: &str,
+    ) -> InternalResult<()> {
+        let value_type = self
+            .types
+            .get_type_by_name(value_type)
+            .ok_or(Error::InvalidTypeName)?
+            .clone();
+
+        self.capture(name, value);
+        self.maps.insert(
+            name.to_string(),
+            MapInfo {
+                map_type,
+                value_type: Some(value_type),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Reserves a named scratch buffer of `size` bytes on the stack, which the
+    /// script can then reference as a `[u8; size]` variable, e.g. to pass its
+    /// address to helpers that fill in a buffer. The buffer starts out
+    /// uninitialized. The type database must contain a `u8` type.
+    ///
+    /// # Arguments
+    ///
+    /// `name` - The name of the buffer when referenced from the script.
+    /// `size` - The size of the buffer in bytes.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u8::add_to_database(&mut database).expect("Failed to add type.");
+    ///
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.reserve_buffer("comm", 16).expect("Failed to reserve buffer.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         get_current_comm(&comm, 16)
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn reserve_buffer(&mut self, name: &str, size: u32) -> InternalResult<()> {
+        if self.variables.contains_key(name) {
+            semantics_bail!(self.expr_num, "Variable \"{}\" is already declared", name);
+        }
+
+        let element_type_id = self
+            .types
+            .get_type_id_by_name("u8")
+            .ok_or(Error::InvalidTypeName)?;
+        let array = Array::create(self.types, element_type_id, size)?;
+        let offset = self.push_stack(size)?;
+        self.variables.insert(
+            name.to_string(),
+            VariableInfo {
+                var_type: BaseType::Array(array).into(),
+                location: VariableLocation::Stack(offset),
+                initialized: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Captures a struct value from the outer scope and makes each of its fields
+    /// available to the script as a variable of its own, e.g. to pass a
+    /// configuration struct into the program. The value is copied onto the stack
+    /// when the program starts.
+    ///
+    /// # Arguments
+    ///
+    /// `type_name` - The name of the struct type describing the value.
+    /// `value` - The bytes of the value, laid out as `type_name` describes.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let u32_id = u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// database
+    ///     .add_struct_by_ids(Some("config"), &[("pid", u32_id), ("uid", u32_id)])
+    ///     .expect("Failed to add type.");
+    ///
+    /// let config = [1234u32.to_le_bytes(), 1000u32.to_le_bytes()].concat();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler
+    ///     .capture_struct_flat("config", &config)
+    ///     .expect("Failed to capture struct.");
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return pid
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn capture_struct_flat(&mut self, type_name: &str, value: &[u8]) -> InternalResult<()> {
+        let types = self.types;
+        let struct_type = types
+            .get_type_by_name(type_name)
+            .ok_or(Error::InvalidTypeName)?;
+        let structure = match &struct_type.base_type {
+            BaseType::Struct(structure) if !struct_type.is_pointer() => structure,
+            _ => {
+                semantics_bail!(self.expr_num, "\"{}\" is not a struct type", type_name);
+            }
+        };
+
+        if value.len() != structure.get_size() as usize {
+            semantics_bail!(
+                self.expr_num,
+                "Captured value is {} bytes but \"{}\" is {} bytes",
+                value.len(),
+                type_name,
+                structure.get_size()
+            );
+        }
+
+        for (name, field) in &structure.fields {
+            if self.variables.contains_key(name) {
+                semantics_bail!(self.expr_num, "Variable \"{}\" is already declared", name);
+            }
+            if field.offset % 8 != 0 {
+                semantics_bail!(self.expr_num, "Bit-field accesses not supported");
+            }
+        }
+
+        let offset = self.emit_push_bytes(value)?;
+        for (name, field) in &structure.fields {
+            let field_type = types
+                .get_type_by_id(field.type_id)
+                .ok_or(Error::InvalidTypeId)?;
+            let field_offset: i16 = (field.offset / 8).try_into()?;
+            self.variables.insert(
+                name.clone(),
+                VariableInfo {
+                    var_type: field_type.clone(),
+                    location: VariableLocation::Stack(offset + field_offset),
+                    initialized: true,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Clears the compiled program so that the compiler can be reused for
+    /// another script, as if it had just been created. The type database,
+    /// settings (default return, loop limit, stack limit, helpers) and values captured with
+    /// `capture`, `capture_typed` and the `capture_map` functions survive a
+    /// reset; use `clear_captures` to drop those as well. Anything placed on
+    /// the stack is dropped, including buffers from `reserve_buffer`, structs
+    /// from `capture_struct_flat` and the space from `set_initial_stack`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.capture("outer", 7);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    ///
+    /// compiler.reset();
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return outer
+    /// "#).expect("Failed to compile.");
+    /// ```
+    pub fn reset(&mut self) {
+        self.variables
+            .retain(|_, info| matches!(info.location, VariableLocation::SpecialImmediate(_)));
+        self.instructions.replace(vec![]);
+        self.stack = 0;
+        self.expr_num = 1;
+        self.return_type = None;
+        self.warnings.clear();
+        self.optimized_len = 0;
+        self.branch_depth = 0;
+        self.max_branch_depth = 0;
+        self.max_stack = 0;
+        self.helpers_used.clear();
+        self.instruction_lines.clear();
+        self.loops.clear();
+        self.volatile_ranges.clear();
+    }
+
+    /// Drops every value captured with `capture`, `capture_typed` and the
+    /// `capture_map` functions. Usually paired with `reset`.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.capture("outer", 7);
+    /// compiler.clear_captures();
+    /// assert!(compiler.compile(r#"
+    ///     fn()
+    ///         return outer
+    /// "#).is_err());
+    /// ```
+    pub fn clear_captures(&mut self) {
+        self.variables
+            .retain(|_, info| !matches!(info.location, VariableLocation::SpecialImmediate(_)));
+        self.maps.clear();
+    }
+
+    /// Helper function for resolving a type by `TypeDecl` and printing an error
+    /// with line information, if it's not found.
+    ///
+    /// # Arguments
+    ///
+    /// * `decl` - The type declaration from the parsed ast.
+    fn type_from_decl(&mut self, decl: &TypeDecl) -> InternalResult<Type> {
+        let mut ty = match (&decl.array, &decl.name) {
+            (Some(array), _) => self.type_from_array_decl(array)?,
+            (None, Some(name)) => {
+                let type_id = self.get_type_id_by_name(name)?;
+                self.types
+                    .get_type_by_id(type_id)
+                    .context(self.expr_num, "Internal error; type id has no type")?
+                    .clone()
+            }
+            (None, None) => {
+                semantics_bail!(self.expr_num, "Internal error; empty type declaration");
+            }
+        };
+
+        if let Some(reference) = &decl.is_ref {
+            if reference.is_mut.is_some() {
+                semantics_bail!(
+                    self.expr_num,
+                    "\"&mut\" can only be used when taking an address, not in a type"
+                );
+            }
+            ty.num_refs += 1;
+        }
+        Ok(ty)
+    }
+
+    /// Helper function for resolving an array type declaration, e.g. `[u8; 16]`. The
+    /// element type must be a named (non-reference) type in the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `decl` - The array declaration from the parsed ast.
+    fn type_from_array_decl(&mut self, decl: &ArrayDecl) -> InternalResult<Type> {
+        let element_name = match (&decl.element.is_ref, &decl.element.name) {
+            (None, Some(name)) => name,
+            _ => {
+                semantics_bail!(
+                    self.expr_num,
+                    "Array elements must be named types in the type database"
+                );
+            }
+        };
+
+        let element_type_id = self.get_type_id_by_name(element_name)?;
+        let num_elements = self.parse_immediate::<u32>(&decl.num_elements)?;
+        let array = Array::create(self.types, element_type_id, num_elements)?;
+        Ok(BaseType::Array(array).into())
+    }
+
+    /// Helper function for finding a type's id by name and printing an error with
+    /// line information, and the closest known name, if it's not found.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    fn get_type_id_by_name(&mut self, name: &str) -> InternalResult<usize> {
+        if let Some(type_id) = self.types.get_type_id_by_name(name) {
+            return Ok(type_id);
+        }
+
+        let suggestion = get_suggestion(name, self.types.type_names());
+        semantics_bail!(
+            self.expr_num,
+            "Type with name \"{}\" doesn't exist{}",
+            name,
+            suggestion
+        );
+    }
+
+    /// Records a warning with line information. Warnings don't stop compilation.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The warning message.
+    fn warn(&mut self, message: String) {
+        self.warnings.push((self.expr_num, message));
+    }
+
+    /// Helper function for finding a scoped variable by name and printing an error
+    /// with line information, if it's not found.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the variable to retrieve.
+    fn get_variable_by_name(&mut self, name: &str) -> InternalResult<VariableInfo> {
+        if let Some(info) = self.variables.get(name) {
+            return Ok(info.clone());
+        }
+
+        let suggestion = get_suggestion(name, self.variables.keys().map(String::as_str));
+        semantics_bail!(
+            self.expr_num,
+            "No variable with name \"{}\"{}",
+            name,
+            suggestion
+        );
+    }
+
+    /// Returns the value and type of the enum constant an lvalue names, if it's a
+    /// bare identifier that isn't a variable. Fails if more than one enum in the
+    /// type database defines the constant.
+    ///
+    /// # Arguments
+    ///
+    /// * `lval` - The lvalue information.
+    fn get_enum_constant(&mut self, lval: &LValue) -> InternalResult<Option<(i64, Type)>> {
+        if lval.prefix.is_some()
+            || !lval.derefs.is_empty()
+            || self.variables.contains_key(&lval.name)
+        {
+            return Ok(None);
+        }
+
+        match &self.types.find_enum_constant(&lval.name)[..] {
+            [] => Ok(None),
+            [(enum_type, value)] => Ok(Some((*value, (*enum_type).clone()))),
+            found => {
+                semantics_bail!(
+                    self.expr_num,
+                    "Enum constant \"{}\" is ambiguous; {} enums define it",
+                    lval.name,
+                    found.len()
+                );
+            }
+        }
+    }
+
+    /// Helper function for parsing an immediate value and printin an error with line
+    /// information, if it's not found. Values prefixed with `0x` are parsed as
+    /// hexadecimal and values prefixed with `0b` as binary.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string representation of the immediate value.
+    fn parse_immediate<T: FromStrRadix>(&mut self, s: &str) -> InternalResult<T> {
+        let (sign, digits) = match s.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", s),
+        };
+        let (radix, digits) = match digits.get(..2) {
+            Some("0x" | "0X") => (16, &digits[2..]),
+            Some("0b" | "0B") => (2, &digits[2..]),
+            _ => (10, digits),
+        };
+
+        if let Ok(imm) = T::from_str_radix(&format!("{}{}", sign, digits), radix) {
+            return Ok(imm);
+        }
+
+        semantics_bail!(self.expr_num, "Failed to parse immediate value \"{}\"", s);
+    }
+
+    /// Parses a decimal floating-point immediate, e.g. "1.5" or "-2".
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The immediate string.
+    fn parse_float_immediate<T: FromStr>(&mut self, s: &str) -> InternalResult<T> {
+        let is_decimal = s
+            .strip_prefix('-')
+            .unwrap_or(s)
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.');
+
+        if is_decimal {
+            if let Ok(imm) = s.parse() {
+                return Ok(imm);
+            }
+        }
+
+        semantics_bail!(self.expr_num, "Failed to parse float value \"{}\"", s);
+    }
+
+    /// Get the current stack offset.
+    fn get_stack(&self) -> i16 {
+        -(self.stack as i16)
+    }
+
+    /// Push the stack value by a given size and return the new offset. Verifies the
+    /// new location doesn't overflow the stack and returns and error with line information,
+    /// if it does.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The number of bytes to push the stack.
+    fn push_stack(&mut self, size: u32) -> InternalResult<i16> {
+        if self.stack + size > self.stack_limit {
+            semantics_bail!(
+                self.expr_num,
+                "Stack size exceeded {} bytes",
+                self.stack_limit
+            );
+        }
+
+        self.stack += size;
+        self.max_stack = self.max_stack.max(self.stack);
+        Ok(self.get_stack())
+    }
+
+    /// Emits instructions to initialize a portion of the stack, works like an
+    /// abstract memset.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset to begin initializing.
+    /// * `value` - The value to initialize _each byte_.
+    /// * `size` - The number of bytes to initialize.
+    fn emit_init_stack_range(&mut self, mut offset: i16, value: i8, mut size: u32) {
+        let value = value as i64;
+        let v64 = value
+            | value << 8
+            | value << 16
+            | value << 24
+            | value << 32
+            | value << 40
+            | value << 48
+            | value << 56;
+        let mut remaining = size;
+        for _ in 0..size / 8 {
+            self.instructions
                 .push(Instruction::store64(Register::R10, offset, v64));
             remaining -= 8;
             offset += 8;
         }
-        size = remaining;
+        size = remaining;
+
+        for _ in 0..size / 4 {
+            self.instructions
+                .push(Instruction::store32(Register::R10, offset, v64 as i32));
+            remaining -= 4;
+            offset += 4;
+        }
+        size = remaining;
+
+        for _ in 0..size / 2 {
+            self.instructions
+                .push(Instruction::store16(Register::R10, offset, v64 as i16));
+            remaining -= 2;
+            offset += 2;
+        }
+        size = remaining;
+
+        for _ in 0..size {
+            self.instructions
+                .push(Instruction::store8(Register::R10, offset, v64 as i8));
+            offset += 1;
+        }
+    }
+
+    /// Emits instructions that push the immediate value to the stack as the given type.
+    ///
+    /// # Arguments
+    ///
+    /// * `imm_str` - The string representation of the immediate value.
+    /// * `cast_type` - The destination type.
+    /// * `use_offset` - An optional offset at which the value is placed.
+    fn emit_push_immediate(
+        &mut self,
+        imm_str: &str,
+        cast_type: &Type,
+        use_offset: Option<i16>,
+    ) -> InternalResult<(i16, Type)> {
+        let size = cast_type.get_size();
+        if size == 0 && !matches!(cast_type.base_type, BaseType::Void) {
+            semantics_bail!(self.expr_num, "Can't assign to zero-sized type");
+        }
+
+        let offset = match use_offset {
+            Some(off) => off,
+            None => self.push_stack(size)?,
+        };
+
+        if cast_type.is_pointer() {
+            let imm = self.parse_immediate::<u8>(imm_str)?;
+            self.instructions
+                .push(Instruction::store8(Register::R10, offset, imm as i8));
+            return Ok((offset, cast_type.clone()));
+        }
+
+        // No type was given so a 64-bit unsigned integer is inferred
+        if matches!(cast_type.base_type, BaseType::Void) {
+            let imm = self.parse_immediate::<i64>(imm_str)?;
+            self.emit_store64_constant(offset, imm)?;
+            let new_type = BaseType::Integer(Integer {
+                used_bits: 64,
+                bits: 64,
+                is_signed: false,
+            });
+            return Ok((offset, new_type.into()));
+        }
+
+        if let BaseType::Integer(integer) = &cast_type.base_type {
+            if !integer.is_signed && imm_str.starts_with('-') {
+                semantics_bail!(
+                    self.expr_num,
+                    "Negative value \"{}\" can't be assigned to an unsigned type",
+                    imm_str
+                );
+            }
+
+            match (size, integer.is_signed) {
+                (1, false) => {
+                    let imm = self.parse_immediate::<u8>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store8(Register::R10, offset, imm as i8));
+                }
+                (1, true) => {
+                    let imm = self.parse_immediate::<i8>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store8(Register::R10, offset, imm));
+                }
+                (2, false) => {
+                    let imm = self.parse_immediate::<u16>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store16(Register::R10, offset, imm as i16));
+                }
+                (2, true) => {
+                    let imm = self.parse_immediate::<i16>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store16(Register::R10, offset, imm));
+                }
+                (4, false) => {
+                    let imm = self.parse_immediate::<u32>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store32(Register::R10, offset, imm as i32));
+                }
+                (4, true) => {
+                    let imm = self.parse_immediate::<i32>(imm_str)?;
+                    self.instructions
+                        .push(Instruction::store32(Register::R10, offset, imm));
+                }
+                (8, false) => {
+                    let imm = self.parse_immediate::<u64>(imm_str)?;
+                    self.emit_store64_constant(offset, imm as i64)?;
+                }
+                (8, true) => {
+                    let imm = self.parse_immediate::<i64>(imm_str)?;
+                    self.emit_store64_constant(offset, imm)?;
+                }
+                (bits, _) => {
+                    semantics_bail!(self.expr_num, "{}-bit integers not supported", bits);
+                }
+            };
+        } else if let BaseType::Float(float) = &cast_type.base_type {
+            // BPF has no floating-point instructions, the value is stored as is.
+            match float.bits {
+                32 => {
+                    let imm = self.parse_float_immediate::<f32>(imm_str)?;
+                    self.instructions.push(Instruction::store32(
+                        Register::R10,
+                        offset,
+                        imm.to_bits() as i32,
+                    ));
+                }
+                64 => {
+                    // most doubles don't fit the 32-bit immediate of a store.
+                    let imm = self.parse_float_immediate::<f64>(imm_str)?;
+                    self.emit_set_register_from_constant(Register::R6, imm.to_bits() as i64);
+                    self.emit_push_register(Register::R6, Some(offset))?;
+                }
+                bits => {
+                    semantics_bail!(self.expr_num, "{}-bit floats not supported", bits);
+                }
+            }
+        } else {
+            let imm = self.parse_immediate::<i8>(imm_str)?;
+            self.emit_init_stack_range(offset, imm, size);
+        }
+
+        Ok((offset, cast_type.clone()))
+    }
+
+    /// Emits instructions that store a 64-bit constant to the stack. The immediate of
+    /// a store is 32 bits and sign-extended, so constants that don't fit it are
+    /// set in a register with a wide load and stored from there.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The stack offset to which the constant is stored.
+    /// * `value` - The constant value.
+    fn emit_store64_constant(&mut self, offset: i16, value: i64) -> InternalResult<()> {
+        match i32::try_from(value) {
+            Ok(imm) => {
+                self.instructions
+                    .push(Instruction::store64(Register::R10, offset, imm.into()))
+            }
+            Err(_) => {
+                self.emit_set_register_from_constant(Register::R6, value);
+                self.emit_push_register(Register::R6, Some(offset))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits instructions that push a register to the stack. If an offset is given,
+    /// the register is pushed to that offset.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to for which a push is emitted.
+    /// * `offset` - The stack offset to which the register is pushed.
+    fn emit_push_register(&mut self, reg: Register, offset: Option<i16>) -> InternalResult<i16> {
+        let offset = if let Some(offset) = offset {
+            offset
+        } else {
+            self.push_stack(8)?
+        };
+
+        self.instructions
+            .push(Instruction::storex64(Register::R10, offset, reg));
+        Ok(offset)
+    }
+
+    /// Emits instructions that dereference a register to the stack using its
+    /// currently held type. This always emits a `bpf_probe_read` call because
+    /// only certain memory can be directly dereferenced by BPF instructions but
+    /// all memory can be read through the helper.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the address to dereference.
+    /// * `deref_type` - The type of dereference.
+    /// * `offset` - The offset in the stack to which the value is copied.
+    fn emit_deref_register_to_stack(&mut self, reg: Register, deref_type: &Type, offset: i16) {
+        self.instructions
+            .push(Instruction::movx64(Register::R1, Register::R10));
+        self.instructions
+            .push(Instruction::add64(Register::R1, offset.into()));
+        self.instructions.push(Instruction::mov64(
+            Register::R2,
+            deref_type.get_size() as i32,
+        ));
+        self.instructions
+            .push(Instruction::movx64(Register::R3, reg));
+        self.instructions
+            .push(Instruction::call(Helpers::ProbeRead.id()));
+    }
+
+    /// Emits instructions that push an lvalue to the stack. Lvalues in this
+    /// language are anything that occurs on the left side of an assignment.
+    /// Currently, this is just stored variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `lval` - The lvalue description.
+    /// * `cast_type` - The destination type, this can differ on re-assignments.
+    /// * `use_offset` - The (optional) offset at which the value should be stored.
+    fn emit_push_lvalue(
+        &mut self,
+        lval: &LValue,
+        cast_type: &Type,
+        use_offset: Option<i16>,
+    ) -> InternalResult<(i16, Type)> {
+        // enum constants are pushed like immediates, enums are stored as integers
+        // of the same size.
+        if let Some((value, enum_type)) = self.get_enum_constant(lval)? {
+            let var_type = match cast_type.base_type {
+                BaseType::Void => enum_type,
+                _ => cast_type.clone(),
+            };
+            let store_type = match &var_type.base_type {
+                BaseType::Enum(_) if !var_type.is_pointer() => BaseType::Integer(Integer {
+                    used_bits: var_type.get_size() * 8,
+                    bits: var_type.get_size() * 8,
+                    is_signed: true,
+                })
+                .into(),
+                _ => var_type.clone(),
+            };
+            let (offset, _) =
+                self.emit_push_immediate(&value.to_string(), &store_type, use_offset)?;
+            return Ok((offset, var_type));
+        }
+
+        if !matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
+            self.check_variable_initialized(&lval.name)?;
+        }
+
+        // This emits instructions to set R6 to a pointer to the lvalue, the type
+        // of the lvalue is returned by the function into `var_type`.
+        let mut var_type = self.emit_set_register_to_lvalue_addr(Register::R6, lval)?;
+
+        // When dereferencing, the pointer is loaded so R6 points to the value it
+        // points to.
+        if matches!(lval.prefix, Some(Prefix::DeReferencePrefix(_))) {
+            if !var_type.is_pointer() {
+                semantics_bail!(self.expr_num, "Cannot dereference a non-pointer type");
+            }
+            var_type.num_refs -= 1;
+            self.instructions
+                .push(Instruction::loadx64(Register::R6, Register::R6, 0));
+        }
+
+        // If the cast type is `void` we "deduce" the type to be the type of the lvalue.
+        let mut real_type = if matches!(cast_type.base_type, BaseType::Void) {
+            var_type.clone()
+        } else {
+            cast_type.clone()
+        };
+
+        // The effective type must match the type of the lvalue in size.
+        if real_type.get_size() != var_type.get_size() {
+            semantics_bail!(self.expr_num, "Cannot assign two types of different sizes");
+        }
+
+        // Makes enough space on the stack to hold the value.
+        let offset = match use_offset {
+            Some(off) => off,
+            None => self.push_stack(real_type.get_size())?,
+        };
+
+        // Lastly, handle the prefix, either reference (&), dereference (*), or nothing.
+        match lval.prefix {
+            None | Some(Prefix::DeReferencePrefix(_)) => {
+                self.emit_deref_register_to_stack(Register::R6, &real_type, offset)
+            }
+            Some(Prefix::ReferencePrefix(_)) => {
+                real_type.num_refs += 1;
+                self.instructions
+                    .push(Instruction::storex64(Register::R10, offset, Register::R6));
+            }
+        }
+
+        Ok((offset, real_type.clone()))
+    }
+
+    /// Emits instructions that perform the arithmetic for the given rvalue. Registers
+    /// 6 and 7 are used to perform the operation, result is stored in R6.
+    ///
+    /// # Arguments
+    ///
+    /// * `left` - The RValue on the left of the operation.
+    /// * `operation` - The arithmetic operation.
+    /// * `right` - The RValue on the right of the operation.
+    fn emit_rvalue_arithmetic(
+        &mut self,
+        left: &RValueInner,
+        operation: &Operation,
+        right: &RValueInner,
+    ) -> InternalResult<Type> {
+        let left_as_rval = RValue {
+            left: left.clone(),
+            op: None,
+            right: None,
+        };
+        let right_as_rval = RValue {
+            left: right.clone(),
+            op: None,
+            right: None,
+        };
+
+        /*
+         * nested arithmetic is evaluated in R6 and R7 as well, so an operand that
+         * uses them is evaluated first. if both operands do, the right one is
+         * spilled to the stack while the left one is evaluated.
+         */
+        let (left_type, right_type) = if !Self::uses_arithmetic_registers(right) {
+            let left_type =
+                self.emit_set_register_from_rvalue(Register::R6, &left_as_rval, None)?;
+            let right_type =
+                self.emit_set_register_from_rvalue(Register::R7, &right_as_rval, None)?;
+            (left_type, right_type)
+        } else if !Self::uses_arithmetic_registers(left) {
+            let right_type =
+                self.emit_set_register_from_rvalue(Register::R7, &right_as_rval, None)?;
+            let left_type =
+                self.emit_set_register_from_rvalue(Register::R6, &left_as_rval, None)?;
+            (left_type, right_type)
+        } else {
+            let right_type =
+                self.emit_set_register_from_rvalue(Register::R7, &right_as_rval, None)?;
+            let offset = self.push_stack(8)?;
+            self.instructions
+                .push(Instruction::storex64(Register::R10, offset, Register::R7));
+            let left_type =
+                self.emit_set_register_from_rvalue(Register::R6, &left_as_rval, None)?;
+            self.instructions
+                .push(Instruction::loadx64(Register::R7, Register::R10, offset));
+            (left_type, right_type)
+        };
+        if Self::is_float(&left_type) || Self::is_float(&right_type) {
+            semantics_bail!(
+                self.expr_num,
+                "Arithmetic can't be performed on floating-point values"
+            );
+        }
+
+        if left_type != right_type {
+            semantics_bail!(
+                self.expr_num,
+                "Arithmetic can only be performed on the same types"
+            );
+        }
+
+        let operation = match operation {
+            Operation::Plus(_) => ArithmeticOperation::Add,
+            Operation::Minus(_) => ArithmeticOperation::Sub,
+            Operation::Times(_) => ArithmeticOperation::Mul,
+            Operation::LeftShift(_) => ArithmeticOperation::Lhs,
+            /*
+             * right shifts of signed values keep the sign. values narrower than
+             * 64 bits are zero-extended in the register, so they're sign-extended
+             * before shifting.
+             */
+            Operation::RightShift(_) if Self::is_signed(&left_type) => {
+                self.emit_sign_extend(Register::R6, &left_type);
+                ArithmeticOperation::Ash
+            }
+            Operation::RightShift(_) => ArithmeticOperation::Rhs,
+            Operation::And(_) => ArithmeticOperation::And,
+            Operation::Or(_) => ArithmeticOperation::Or,
+            Operation::Xor(_) => ArithmeticOperation::Xor,
+        };
+
+        self.instructions
+            .push(Instruction::alux64(Register::R6, Register::R7, operation));
+
+        Ok(right_type)
+    }
+
+    /// Emits instructions that apply a unary operator to a value and leave the
+    /// result in a register. Only the bitwise complement, `~`, produces a value;
+    /// a logical not, `!`, is only valid as a condition.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the result.
+    /// * `unary` - The unary operation.
+    fn emit_unary(&mut self, reg: Register, unary: &UnaryOp) -> InternalResult<Type> {
+        if matches!(unary.op, UnaryOperator::LogicalNot(_)) {
+            semantics_bail!(self.expr_num, "\"!\" can only be used in conditions");
+        }
+
+        let value = RValue {
+            left: (*unary.value).clone(),
+            op: None,
+            right: None,
+        };
+        let value_type = self.emit_set_register_from_rvalue(reg, &value, None)?;
+        let is_integer = matches!(
+            value_type.base_type,
+            BaseType::Integer(_) | BaseType::Enum(_)
+        );
+        if !is_integer || value_type.is_pointer() {
+            semantics_bail!(self.expr_num, "\"~\" can only be applied to integers");
+        }
+
+        /*
+         * registers holding values narrower than 64 bits are zero-extended, so
+         * only the bits of the value are flipped. 32-bit operations zero the
+         * upper half themselves.
+         */
+        let instruction = match value_type.get_size() {
+            1 => Instruction::alu64(reg, 0xff, ArithmeticOperation::Xor),
+            2 => Instruction::alu64(reg, 0xffff, ArithmeticOperation::Xor),
+            4 => Instruction::alu32(reg, -1, ArithmeticOperation::Xor),
+            _ => Instruction::alu64(reg, -1, ArithmeticOperation::Xor),
+        };
+        self.instructions.push(instruction);
+
+        Ok(value_type)
+    }
+
+    /// Returns whether evaluating an operand involves arithmetic, which clobbers
+    /// R6 and R7.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The operand to check.
+    fn uses_arithmetic_registers(inner: &RValueInner) -> bool {
+        let rvalue_uses_registers = |rval: &RValue| {
+            rval.op.is_some()
+                || Self::uses_arithmetic_registers(&rval.left)
+                || rval
+                    .right
+                    .as_ref()
+                    .is_some_and(Self::uses_arithmetic_registers)
+        };
+
+        match inner {
+            RValueInner::Volatile(volatile) => rvalue_uses_registers(&volatile.value),
+            RValueInner::Parenthesized(group) => rvalue_uses_registers(&group.value),
+            RValueInner::UnaryOp(unary) => Self::uses_arithmetic_registers(&unary.value),
+            // variable indices are added to the address with a scratch register.
+            RValueInner::LValue(lval) => lval.derefs.iter().any(|deref| {
+                matches!(deref, DeReference::ArrayIndex(index)
+                    if !matches!(index.element.left, RValueInner::Immediate(_)))
+            }),
+            RValueInner::Comparison(comparison) => {
+                rvalue_uses_registers(&comparison.cond.left)
+                    || rvalue_uses_registers(&comparison.cond.right)
+            }
+            _ => false,
+        }
+    }
+
+    /// Emits instructions that push an rvalue to the stack. RValues in this language
+    /// are anything that occur on the right hand side of an assignment: immediates,
+    /// lvalues, function calls, etc.
+    ///
+    /// # Arguments
+    ///
+    /// * `rval` - The rvalue to be pushed to the stack.
+    /// * `cast_type` - The type of the value, this can be different when casting.
+    /// * `use_offset` - An optional offset to which the value is pushed.
+    fn emit_push_rvalue(
+        &mut self,
+        rval: &RValue,
+        cast_type: &Type,
+        use_offset: Option<i16>,
+    ) -> InternalResult<(i16, Type)> {
+        if let (Some(op), Some(right)) = (&rval.op, &rval.right) {
+            let var_type = self.emit_rvalue_arithmetic(&rval.left, op, right)?;
+            if !matches!(cast_type.base_type, BaseType::Void) && var_type != *cast_type {
+                semantics_bail!(
+                    self.expr_num,
+                    "Cannot store result of arithmetic in this type"
+                );
+            }
+            let offset = self.emit_push_register(Register::R6, use_offset)?;
+            return Ok((offset, var_type));
+        }
+
+        match &rval.left {
+            RValueInner::Immediate(imm_str) => {
+                self.emit_push_immediate(imm_str, cast_type, use_offset)
+            }
+            RValueInner::SizeOf(size_of) => {
+                let imm_str = self.get_size_of(size_of)?.to_string();
+                self.emit_push_immediate(&imm_str, cast_type, use_offset)
+            }
+            RValueInner::OffsetOf(offset_of) => {
+                let imm_str = self.get_offset_of(offset_of)?.to_string();
+                self.emit_push_immediate(&imm_str, cast_type, use_offset)
+            }
+            RValueInner::LValue(lval) => self.emit_push_lvalue(lval, cast_type, use_offset),
+            RValueInner::Volatile(volatile) => {
+                let start = self.instructions.current_index();
+                let result = self.emit_push_rvalue(&volatile.value, cast_type, use_offset);
+                self.keep_unoptimized(start);
+                result
+            }
+            RValueInner::Parenthesized(group) => {
+                self.emit_push_rvalue(&group.value, cast_type, use_offset)
+            }
+            RValueInner::Comparison(comparison) => {
+                let var_type =
+                    self.emit_set_register_from_comparison(Register::R6, &comparison.cond)?;
+                if !matches!(cast_type.base_type, BaseType::Void) && var_type != *cast_type {
+                    semantics_bail!(
+                        self.expr_num,
+                        "Cannot store result of comparison in this type"
+                    );
+                }
+                let offset = self.emit_push_register(Register::R6, use_offset)?;
+                Ok((offset, var_type))
+            }
+            RValueInner::UnaryOp(unary) => {
+                let var_type = self.emit_unary(Register::R6, unary)?;
+                if !matches!(cast_type.base_type, BaseType::Void) && var_type != *cast_type {
+                    semantics_bail!(self.expr_num, "Cannot store result of \"~\" in this type");
+                }
+                let offset = self.emit_push_register(Register::R6, use_offset)?;
+                Ok((offset, var_type))
+            }
+            RValueInner::StringLiteral(literal) => {
+                if !matches!(cast_type.base_type, BaseType::Void) {
+                    semantics_bail!(
+                        self.expr_num,
+                        "String literals can't be stored in a typed variable"
+                    );
+                }
+                let var_type = self.emit_set_register_from_string(Register::R6, literal)?;
+                let offset = self.emit_push_register(Register::R6, use_offset)?;
+                Ok((offset, var_type))
+            }
+            RValueInner::FunctionCall(call) if call.name == "map_get" => {
+                self.emit_push_map_get(call, cast_type, use_offset)
+            }
+            RValueInner::FunctionCall(call) => {
+                let ret_type = self.emit_call(call)?;
+                let var_type = match &cast_type.base_type {
+                    BaseType::Void => &ret_type,
+                    _ if cast_type.is_pointer() => {
+                        if !ret_type.is_pointer() && !self.call_returns_pointer(call) {
+                            semantics_bail!(
+                                self.expr_num,
+                                "\"{}\" doesn't return a pointer",
+                                call.name
+                            );
+                        }
+                        cast_type
+                    }
+                    BaseType::Integer(integer) => {
+                        if integer.get_size() != 8 {
+                            semantics_bail!(
+                                self.expr_num,
+                                "Function return values can only be stored in 64-bit types"
+                            );
+                        }
+                        cast_type
+                    }
+                    _ => {
+                        semantics_bail!(
+                            self.expr_num,
+                            "Function return values can only be stored in integer types"
+                        );
+                    }
+                };
+                let offset = self.emit_push_register(Register::R0, use_offset)?;
+                Ok((offset, var_type.clone()))
+            }
+        }
+    }
+
+    /// Emits a `map_get(map, &key)`: looks up the key and, if it's present, copies
+    /// the value into a stack variable of the map's value type, otherwise zeroes
+    /// the variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The `map_get` call.
+    /// * `cast_type` - The declared type of the destination, if any.
+    /// * `use_offset` - An optional offset at which to store the value.
+    fn emit_push_map_get(
+        &mut self,
+        call: &FunctionCall,
+        cast_type: &Type,
+        use_offset: Option<i16>,
+    ) -> InternalResult<(i16, Type)> {
+        let args = Self::get_call_args(call);
+        let value_type = match args.first().map(|arg| &arg.left) {
+            Some(RValueInner::LValue(lval)) => match self.maps.get(&lval.name) {
+                Some(MapInfo {
+                    value_type: Some(value_type),
+                    ..
+                }) => value_type.clone(),
+                _ => {
+                    semantics_bail!(
+                        self.expr_num,
+                        "\"{}\" isn't a map captured with a value type",
+                        lval.name
+                    );
+                }
+            },
+            _ => {
+                semantics_bail!(self.expr_num, "map_get requires a captured map");
+            }
+        };
+
+        if !matches!(cast_type.base_type, BaseType::Void) && *cast_type != value_type {
+            semantics_bail!(
+                self.expr_num,
+                "map_get values can only be stored in the map's value type"
+            );
+        }
+
+        let size = value_type.get_size();
+        let offset = match use_offset {
+            Some(offset) => offset,
+            None => self.push_stack(size)?,
+        };
+
+        let lookup = FunctionCall {
+            name: "map_lookup_elem".to_string(),
+            args: call.args.clone(),
+        };
+        self.emit_call(&lookup)?;
+        self.emit_optimize();
+
+        /*
+         * if r0 != 0 goto copy
+         * <zero the value>
+         * goto end
+         * copy: <copy the value out of the map>
+         * end:
+         */
+        let found_index = self.instructions.current_index();
+        self.instructions.push(Instruction::jmp_if(
+            Register::R0,
+            JumpOperation::IfNotEqual,
+            0,
+            0,
+        ));
+        self.emit_init_stack_range(offset, 0, size);
+        let end_index = self.instructions.current_index();
+        self.instructions.push(Instruction::jmp_abs(0));
+
+        let jump = self.get_jump_offset(found_index)?;
+        self.instructions.patch(
+            found_index,
+            Instruction::jmp_if(Register::R0, JumpOperation::IfNotEqual, 0, jump),
+        );
+
+        let mut copied = 0;
+        while copied < size {
+            let src = copied as i16;
+            let dst = offset + src;
+            let (chunk, load, store) = match size - copied {
+                8.. => (
+                    8,
+                    Instruction::loadx64(Register::R1, Register::R0, src),
+                    Instruction::storex64(Register::R10, dst, Register::R1),
+                ),
+                4..=7 => (
+                    4,
+                    Instruction::loadx32(Register::R1, Register::R0, src),
+                    Instruction::storex32(Register::R10, dst, Register::R1),
+                ),
+                2..=3 => (
+                    2,
+                    Instruction::loadx16(Register::R1, Register::R0, src),
+                    Instruction::storex16(Register::R10, dst, Register::R1),
+                ),
+                _ => (
+                    1,
+                    Instruction::loadx8(Register::R1, Register::R0, src),
+                    Instruction::storex8(Register::R10, dst, Register::R1),
+                ),
+            };
+            self.instructions.push(load);
+            self.instructions.push(store);
+            copied += chunk;
+        }
+
+        let jump = self.get_jump_offset(end_index)?;
+        self.instructions
+            .patch(end_index, Instruction::jmp_abs(jump));
+
+        /*
+         * the jumps above are final; keep later optimizer runs from changing the
+         * code between them.
+         */
+        self.optimized_len = self.instructions.current_index();
+
+        Ok((offset, value_type))
+    }
+
+    /// Returns the offset and type from a structure and field name.
+    ///
+    /// # Arguments
+    ///
+    /// * `structure` - The structure to access.
+    /// * `field_name` - The field within the structure.
+    fn get_field_access(
+        &mut self,
+        structure: &Type,
+        field_name: &str,
+    ) -> InternalResult<(u32, &'a Type)> {
+        let fields = match structure.base_type.fields() {
+            Some(fields) => fields,
+            None => {
+                semantics_bail!(self.expr_num, "Can't field-deref a non-structure type");
+            }
+        };
+
+        let field = fields.get(field_name).context(
+            self.expr_num,
+            &format!("Field \"{}\" doesn't exist on type", field_name),
+        )?;
+
+        if field.offset % 8 != 0 {
+            semantics_bail!(self.expr_num, "Bit-field accesses not supported");
+        }
+
+        let types = self.types;
+        let field_type = types
+            .get_type_by_id(field.type_id)
+            .context(self.expr_num, "Internal error; type id invalid")?;
+        Ok((field.offset / 8, field_type))
+    }
+
+    /// Returns the number of elements and the element type of an array.
+    ///
+    /// # Arguments
+    ///
+    /// * `array` - The array type.
+    fn get_array_element(&mut self, array: &Type) -> InternalResult<(u32, &'a Type)> {
+        let array = if let BaseType::Array(array) = &array.base_type {
+            array
+        } else {
+            semantics_bail!(self.expr_num, "Can't array-deref a non-array type");
+        };
+
+        let types = self.types;
+        let element_type = types
+            .get_type_by_id(array.element_type_id)
+            .context(self.expr_num, "Internal error; type id invalid")?;
+        Ok((array.num_elements, element_type))
+    }
+
+    /// Returns the offset and type given an array and a constant index.
+    ///
+    /// # Arguments
+    ///
+    /// * `array` - The array to access.
+    /// * `index` - The index into the array.
+    fn get_array_index(&mut self, array: &Type, index: &RValue) -> InternalResult<(u32, &'a Type)> {
+        let (num_elements, element_type) = self.get_array_element(array)?;
+
+        let index = match self.get_constant_value(index)? {
+            Some(index) => index,
+            None => {
+                semantics_bail!(self.expr_num, "Array index must be a constant here");
+            }
+        };
+
+        let index = match u32::try_from(index) {
+            Ok(index) if index < num_elements => index,
+            _ => {
+                semantics_bail!(
+                    self.expr_num,
+                    "Out-of-bounds array access {}/{}",
+                    index,
+                    num_elements
+                );
+            }
+        };
+
+        let offset = element_type.get_size() * index;
+        Ok((offset, element_type))
+    }
+
+    /// Returns the size, in bytes, of the type given to a `sizeof` expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `size_of` - The `sizeof` expression.
+    fn get_size_of(&mut self, size_of: &SizeOf) -> InternalResult<u32> {
+        let ty = self.type_from_decl(&size_of.type_name)?;
+        Ok(ty.get_size())
+    }
+
+    /// Returns the byte offset of the field given to an `offsetof` expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset_of` - The `offsetof` expression.
+    fn get_offset_of(&mut self, offset_of: &OffsetOf) -> InternalResult<u32> {
+        let type_id = self.get_type_id_by_name(&offset_of.type_name)?;
+        let ty = self
+            .types
+            .get_type_by_id(type_id)
+            .context(self.expr_num, "Internal error; type id has no type")?
+            .clone();
+
+        if ty.is_pointer() || ty.base_type.fields().is_none() {
+            semantics_bail!(
+                self.expr_num,
+                "offsetof requires a structure type, \"{}\" is not one",
+                offset_of.type_name
+            );
+        }
+
+        let (offset, _) = self.get_field_access(&ty, &offset_of.field)?;
+        Ok(offset)
+    }
+
+    /// Given a type and deref slice, returns the offset of the deref and its type.
+    ///
+    /// # Arguments
+    ///
+    /// * `ty` - The type being dereferenced.
+    /// * `derefs` - The list of derefs to apply to the type.
+    fn get_deref_offset(
+        &mut self,
+        ty: &Type,
+        derefs: &[DeReference],
+    ) -> InternalResult<(i16, Type)> {
+        let mut offset = 0;
+        let mut cur_type = ty;
+        for deref in derefs.iter() {
+            if cur_type.is_pointer() {
+                semantics_bail!(
+                    self.expr_num,
+                    "Can't deref an offset through an indirection"
+                );
+            }
+
+            let (off, ty) = match deref {
+                DeReference::FieldAccess(ma) => self.get_field_access(cur_type, &ma.name)?,
+                DeReference::ArrayIndex(ai) => self.get_array_index(cur_type, &ai.element)?,
+            };
+
+            offset += off;
+            cur_type = ty;
+        }
+
+        let offset: i16 = offset
+            .try_into()
+            .context(self.expr_num, "Type is too large to deref")?;
+        Ok((offset, cur_type.clone()))
+    }
+
+    /// Emit instructions for an assignment expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `assign` - Information about the assignment.
+    fn emit_assign(&mut self, assign: &Assignment) -> InternalResult<()> {
+        if matches!(assign.left.prefix, Some(Prefix::DeReferencePrefix(_)))
+            || self.derefs_through_pointer(&assign.left)?
+        {
+            return self.emit_assign_through_pointer(assign);
+        }
+
+        /*
+         * an element indexed by a variable has no fixed stack offset, so it's
+         * assigned through its address like a pointer.
+         */
+        if self.indexes_by_variable(&assign.left)? {
+            self.emit_assign_through_pointer(assign)?;
+            if let Some(info) = self.variables.get_mut(&assign.left.name) {
+                info.initialized = true;
+            }
+            return Ok(());
+        }
+
+        let mut new_variable = true;
+        let (cast_type, use_offset) =
+            if let Ok(info) = &self.get_variable_by_name(&assign.left.name) {
+                if assign.type_name.is_some() {
+                    semantics_bail!(
+                        self.expr_num,
+                        "Can't re-type \"{}\" after first assignment",
+                        assign.left.name
+                    );
+                } else if let VariableLocation::Stack(off) = info.location {
+                    let (rel_off, offset_type) =
+                        self.get_deref_offset(&info.var_type, &assign.left.derefs)?;
+                    new_variable = false;
+                    (offset_type, Some(off + rel_off))
+                } else {
+                    semantics_bail!(
+                        self.expr_num,
+                        "Variable \"{}\" cannot be re-assigned",
+                        assign.left.name
+                    );
+                }
+            } else if let Some(type_name) = &assign.type_name {
+                let assign_type = self.type_from_decl(type_name)?;
+                (assign_type, None)
+            } else {
+                (Default::default(), None)
+            };
+
+        let (offset, new_type) = self.emit_push_rvalue(&assign.right, &cast_type, use_offset)?;
+
+        if new_variable {
+            self.variables.insert(
+                assign.left.name.clone(),
+                VariableInfo {
+                    var_type: new_type,
+                    location: VariableLocation::Stack(offset),
+                    initialized: true,
+                },
+            );
+        } else if let Some(info) = self.variables.get_mut(&assign.left.name) {
+            info.initialized = true;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether accessing an existing variable's field or element goes
+    /// through a pointer, e.g. `v.count` where `v` is a pointer to a structure.
+    ///
+    /// # Arguments
+    ///
+    /// * `lval` - The lvalue information.
+    fn derefs_through_pointer(&mut self, lval: &LValue) -> InternalResult<bool> {
+        let info = match self.variables.get(&lval.name) {
+            Some(info) if matches!(info.location, VariableLocation::Stack(_)) => info.clone(),
+            _ => return Ok(false),
+        };
+
+        let mut cur_type = &info.var_type;
+        for deref in &lval.derefs {
+            if cur_type.is_pointer() {
+                return Ok(true);
+            }
+
+            cur_type = match deref {
+                DeReference::FieldAccess(ma) => self.get_field_access(cur_type, &ma.name)?.1,
+                DeReference::ArrayIndex(_) => self.get_array_element(cur_type)?.1,
+            };
+        }
+
+        Ok(false)
+    }
+
+    /// Returns whether an lvalue indexes an array with a value that isn't known at
+    /// compile time, e.g. `arr[i]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `lval` - The lvalue information.
+    fn indexes_by_variable(&mut self, lval: &LValue) -> InternalResult<bool> {
+        for deref in &lval.derefs {
+            if let DeReference::ArrayIndex(index) = deref {
+                if self.get_constant_value(&index.element)?.is_none() {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Emits instructions for an assignment through a pointer, e.g. `*v = *v + 1`
+    /// or `v.count = 1`, or to an element indexed by a variable, e.g. `arr[i] = 1`.
+    /// The value is evaluated into R6, the address being assigned to is set in R7
+    /// and the value is stored with the size of the pointed-to type.
+    ///
+    /// # Arguments
+    ///
+    /// * `assign` - Information about the assignment.
+    fn emit_assign_through_pointer(&mut self, assign: &Assignment) -> InternalResult<()> {
+        if assign.type_name.is_some() {
+            semantics_bail!(
+                self.expr_num,
+                "Can't declare a type when assigning through a pointer"
+            );
+        }
+
+        let pointer = LValue {
+            prefix: None,
+            name: assign.left.name.clone(),
+            derefs: assign.left.derefs.clone(),
+        };
+
+        let is_constant = self.get_constant_value(&assign.right)?.is_some();
+        let value_type = self.emit_set_register_from_rvalue(Register::R6, &assign.right, None)?;
+
+        // evaluating a variable index may use R6, so the value is spilled meanwhile.
+        let stack = self.stack;
+        let spill = if self.indexes_by_variable(&pointer)? {
+            let offset = self.push_stack(8)?;
+            self.instructions
+                .push(Instruction::storex64(Register::R10, offset, Register::R6));
+            Some(offset)
+        } else {
+            None
+        };
+
+        let pointee_type = if matches!(assign.left.prefix, Some(Prefix::DeReferencePrefix(_))) {
+            let mut pointee_type =
+                self.emit_set_register_from_lvalue(Register::R7, &pointer, None)?;
+            if !pointee_type.is_pointer() {
+                semantics_bail!(self.expr_num, "Cannot dereference a non-pointer type");
+            }
+            pointee_type.num_refs -= 1;
+            pointee_type
+        } else {
+            self.emit_set_register_to_lvalue_addr(Register::R7, &pointer)?
+        };
+
+        if let Some(offset) = spill {
+            self.instructions
+                .push(Instruction::loadx64(Register::R6, Register::R10, offset));
+            self.stack = stack;
+        }
+
+        if !is_constant && value_type != pointee_type {
+            semantics_bail!(
+                self.expr_num,
+                "Cannot store a value of a different type through \"{}\"",
+                assign.left.name
+            );
+        }
+
+        let store = match pointee_type.get_size() {
+            1 => Instruction::storex8(Register::R7, 0, Register::R6),
+            2 => Instruction::storex16(Register::R7, 0, Register::R6),
+            4 => Instruction::storex32(Register::R7, 0, Register::R6),
+            8 => Instruction::storex64(Register::R7, 0, Register::R6),
+            size => {
+                semantics_bail!(
+                    self.expr_num,
+                    "The value pointed to by \"{}\" is {} bytes and can't be assigned",
+                    assign.left.name,
+                    size
+                );
+            }
+        };
+        self.instructions.push(store);
+
+        Ok(())
+    }
+
+    /// Handles a declaration without an initializer. Space is reserved on the
+    /// stack but no instructions are emitted, so the variable starts out
+    /// uninitialized.
+    ///
+    /// # Arguments
+    ///
+    /// * `declaration` - Information about the declaration.
+    fn emit_declaration(&mut self, declaration: &Declaration) -> InternalResult<()> {
+        if self.variables.contains_key(&declaration.name) {
+            semantics_bail!(
+                self.expr_num,
+                "Variable \"{}\" is already declared",
+                declaration.name
+            );
+        }
+
+        let var_type = self.type_from_decl(&declaration.type_name)?;
+        if var_type.get_size() == 0 {
+            semantics_bail!(self.expr_num, "Can't declare a zero-sized type");
+        }
+
+        let offset = self.push_stack(var_type.get_size())?;
+        self.variables.insert(
+            declaration.name.clone(),
+            VariableInfo {
+                var_type,
+                location: VariableLocation::Stack(offset),
+                initialized: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// From an address held in a register and a structure type, emits instructions that set
+    /// the register value to the address of the field being accessed.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the address of the structure.
+    /// * `structure` - The structure's type.
+    /// * `field_access` - Information about the field being accessed.
+    fn emit_field_access(
+        &mut self,
+        reg: Register,
+        structure: &Type,
+        field_access: &FieldAccess,
+    ) -> InternalResult<&'a Type> {
+        let (offset, field_type) = self.get_field_access(structure, &field_access.name)?;
+        if offset > 0 {
+            self.instructions
+                .push(Instruction::add64(reg, offset as i32));
+        }
+        Ok(field_type)
+    }
+
+    /// From an address held in a register and an array type, emits instructions that set
+    /// the register value to the address of the element being accessed. Indices that
+    /// aren't constant are bounds-checked at runtime; the program returns 0 if they're
+    /// out of bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the address of the array.
+    /// * `array` - The array's type.
+    /// * `index` - Information about the index being accessed.
+    fn emit_index_array(
+        &mut self,
+        reg: Register,
+        array: &Type,
+        index: &ArrayIndex,
+    ) -> InternalResult<&'a Type> {
+        if self.get_constant_value(&index.element)?.is_some() {
+            let (offset, element_type) = self.get_array_index(array, &index.element)?;
+            if offset > 0 {
+                self.instructions
+                    .push(Instruction::add64(reg, offset as i32));
+            }
+            return Ok(element_type);
+        }
+
+        if Self::rvalue_contains_call(&index.element) {
+            semantics_bail!(self.expr_num, "Array indices can't contain function calls");
+        }
+
+        let (num_elements, element_type) = self.get_array_element(array)?;
+        let num_elements = i32::try_from(num_elements)
+            .context(self.expr_num, "Array is too large to index by a variable")?;
+        let element_size = i32::try_from(element_type.get_size())
+            .context(self.expr_num, "Array elements are too large to index")?;
+
+        // the address is spilled while the index is evaluated into the same register.
+        let stack = self.stack;
+        let offset = self.push_stack(8)?;
+        self.instructions
+            .push(Instruction::storex64(Register::R10, offset, reg));
+        let index_type = self.emit_set_register_from_rvalue(reg, &index.element, None)?;
+        if index_type.is_pointer()
+            || !matches!(
+                index_type.base_type,
+                BaseType::Integer(_) | BaseType::Enum(_)
+            )
+        {
+            semantics_bail!(self.expr_num, "Array indices must be integers");
+        }
+
+        /*
+         * if reg < num_elements goto access
+         * r0 = 0
+         * exit
+         * access: reg *= element_size
+         * temp = <address>
+         * reg += temp
+         */
+        self.instructions.push(Instruction::jmp_if(
+            reg,
+            JumpOperation::IfLessThan,
+            num_elements.into(),
+            2,
+        ));
+        self.instructions
+            .push(Instruction::mov64(RETURN_REGISTER, 0));
+        self.instructions.push(Instruction::exit());
+        self.optimized_len = self.instructions.current_index();
+
+        if element_size > 1 {
+            self.instructions.push(Instruction::alu64(
+                reg,
+                element_size,
+                ArithmeticOperation::Mul,
+            ));
+        }
+
+        // R7 is free unless it's the register being set, then R9 is.
+        let temp = if reg == Register::R7 {
+            Register::R9
+        } else {
+            Register::R7
+        };
+        self.instructions
+            .push(Instruction::loadx64(temp, Register::R10, offset));
+        self.instructions.push(Instruction::addx64(reg, temp));
+
+        // the spill slot is only live during the access, so it's reused by the next one.
+        self.stack = stack;
+
+        Ok(element_type)
+    }
+
+    /// Given a register holding a `var_type` address, and a list of derefs, emits instructions
+    /// that apply these derefs to the register. After the instructions are executed, `reg` will
+    /// hold the address to the deref.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the address to be dereferenced.
+    /// * `var_type` - The type of variable being pointed to by `reg`.
+    /// * `derefs` - A list of derefs to apply.
+    fn emit_apply_derefs_to_reg(
+        &mut self,
+        reg: Register,
+        var_type: &Type,
+        derefs: &[DeReference],
+    ) -> InternalResult<Type> {
+        if derefs.is_empty() {
+            return Ok(var_type.clone());
+        }
+
+        // If the current var_type is a pointer then this deref is through a pointer.
+        // Before emiting instructions to access the structure or field, the address
+        // needs to be loaded into the register.
+        if var_type.is_pointer() {
+            self.instructions.push(Instruction::loadx64(reg, reg, 0));
+        }
+
+        let next_type = match &derefs[0] {
+            DeReference::FieldAccess(ma) => self.emit_field_access(reg, var_type, ma)?,
+            DeReference::ArrayIndex(ai) => self.emit_index_array(reg, var_type, ai)?,
+        };
+
+        self.emit_apply_derefs_to_reg(reg, next_type, &derefs[1..])
+    }
+
+    /// Given a register and lvalue information, emits instructions that set the
+    /// register to the address of the lvalue being accessed. On success, the final
+    /// type of the lval access is returned and `reg` will contain the address pointing
+    /// to this type.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register to be set.
+    /// * `lval` - The lvalue information.
+    fn emit_set_register_to_lvalue_addr(
+        &mut self,
+        reg: Register,
+        lval: &LValue,
+    ) -> InternalResult<Type> {
+        let info = self.get_variable_by_name(&lval.name)?;
+
+        match info.location {
+            VariableLocation::SpecialImmediate(_) => {
+                semantics_bail!(
+                    self.expr_num,
+                    "Variable \"{}\" is a capture; captures can't be assigned to",
+                    lval.name
+                );
+            }
+            VariableLocation::Stack(o) => {
+                self.instructions
+                    .push(Instruction::movx64(reg, Register::R10));
+                self.instructions.push(Instruction::add64(reg, o.into()));
+            }
+        }
+
+        self.emit_apply_derefs_to_reg(reg, &info.var_type, &lval.derefs)
+    }
+
+    /// Given a register and lvalue information, emits instructions that set the
+    /// register to the value of this lvalue access. This is different from
+    /// `emit_set_register_to_lvalue_addr` in that the register receives the final
+    /// dereferenced type, _not_ an address pointing to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the value.
+    /// * `lval` - The lvalue information.
+    /// * `load_type` - The BPF load type to use when setting the register value.
+    fn emit_set_register_from_lvalue(
+        &mut self,
+        reg: Register,
+        lval: &LValue,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> InternalResult<Type> {
+        if let Some((value, enum_type)) = self.get_enum_constant(lval)? {
+            match load_type {
+                Some(load_type) => self
+                    .instructions
+                    .push(Instruction::loadtype(reg, value, load_type)),
+                None => self.emit_set_register_from_constant(reg, value),
+            }
+            return Ok(enum_type);
+        }
+
+        let info = self.get_variable_by_name(&lval.name)?;
+        if let VariableLocation::SpecialImmediate(v) = info.location {
+            if !lval.derefs.is_empty() {
+                semantics_bail!(
+                    self.expr_num,
+                    "Can't dereference \"{}\"; it's a capture",
+                    lval.name
+                );
+            }
+
+            let load_type = load_type.unwrap_or(MemoryOpLoadType::Void);
+            self.instructions
+                .push(Instruction::loadtype(reg, v, load_type));
+            return Ok(info.var_type);
+        }
+
+        if !matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
+            self.check_variable_initialized(&lval.name)?;
+        }
+
+        let mut var_type = self.emit_set_register_to_lvalue_addr(reg, lval)?;
+
+        /*
+         * the register is already holding a pointer to the lvalue so, if a reference
+         * was specified, nothing else needs to be done.
+         */
+        if matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
+            var_type.num_refs += 1;
+            return Ok(var_type);
+        }
+
+        /*
+         * register is pointing to a value of type `var_type`, load it into the register,
+         * if it fits.
+         */
+        match var_type.get_size() {
+            1 => self.instructions.push(Instruction::loadx8(reg, reg, 0)),
+            2 => self.instructions.push(Instruction::loadx16(reg, reg, 0)),
+            4 => self.instructions.push(Instruction::loadx32(reg, reg, 0)),
+            8 => self.instructions.push(Instruction::loadx64(reg, reg, 0)),
+            size => {
+                semantics_bail!(
+                    self.expr_num,
+                    "The variable \"{}\" is {} bytes and is too large to be passed in a register",
+                    lval.name,
+                    size
+                );
+            }
+        }
+
+        /*
+         * the register is now holding `var_type`. if another dereference was requested
+         * then make sure the type being held by the register is a pointer.
+         */
+        if matches!(lval.prefix, Some(Prefix::DeReferencePrefix(_))) {
+            if !var_type.is_pointer() {
+                semantics_bail!(self.expr_num, "Cannot dereference a non-pointer type");
+            }
+
+            var_type.num_refs -= 1;
+            let load = match var_type.get_size() {
+                1 => Instruction::loadx8(reg, reg, 0),
+                2 => Instruction::loadx16(reg, reg, 0),
+                4 => Instruction::loadx32(reg, reg, 0),
+                8 => Instruction::loadx64(reg, reg, 0),
+                size => {
+                    semantics_bail!(
+                        self.expr_num,
+                        "The value pointed to by \"{}\" is {} bytes and is too large to be loaded in a register",
+                        lval.name,
+                        size
+                    );
+                }
+            };
+            self.instructions.push(load);
+        }
+
+        Ok(var_type)
+    }
+
+    /// Given a register and an immediate value, emits instructions that set the
+    /// register to the immediate.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the value.
+    /// * `imm_str` - The string representation of the immediate value.
+    /// * `load_type` - The BPF load type to use when setting the register value.
+    fn emit_set_register_from_immediate(
+        &mut self,
+        reg: Register,
+        imm_str: &str,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> InternalResult<Type> {
+        if let Some(load_type) = load_type {
+            let imm = self.parse_immediate(imm_str)?;
+            self.instructions
+                .push(Instruction::loadtype(reg, imm, load_type));
+        } else {
+            let imm = self.parse_immediate(imm_str)?;
+            self.emit_set_register_from_constant(reg, imm);
+        }
+
+        let var_type: Type = BaseType::Integer(Integer {
+            used_bits: 64,
+            bits: 64,
+            is_signed: false,
+        })
+        .into();
+
+        Ok(var_type)
+    }
+
+    /// Converts a string literal from the script into the bytes it represents,
+    /// resolving escape sequences and appending the NUL terminator.
+    ///
+    /// # Arguments
+    ///
+    /// * `literal` - The string literal, including its quotes.
+    fn parse_string_literal(&mut self, literal: &str) -> InternalResult<Vec<u8>> {
+        let inner = &literal[1..literal.len() - 1];
+        let mut bytes = Vec::with_capacity(inner.len() + 1);
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+
+            match chars.next() {
+                Some('n') => bytes.push(b'\n'),
+                Some('t') => bytes.push(b'\t'),
+                Some('r') => bytes.push(b'\r'),
+                Some('0') => bytes.push(0),
+                Some('\\') => bytes.push(b'\\'),
+                Some('"') => bytes.push(b'"'),
+                Some(c) => {
+                    semantics_bail!(self.expr_num, "Unknown escape sequence \"\\{}\"", c);
+                }
+                None => {
+                    semantics_bail!(self.expr_num, "Unterminated escape sequence");
+                }
+            }
+        }
+
+        bytes.push(0);
+        Ok(bytes)
+    }
+
+    /// Emits instructions that lay the given bytes onto the stack and returns
+    /// their offset. The bytes are padded with zeroes to a multiple of 4 so they
+    /// can be written with aligned word stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to store.
+    fn emit_push_bytes(&mut self, bytes: &[u8]) -> InternalResult<i16> {
+        let size = (bytes.len() as u32 + 3) & !3;
+        let offset = self.push_stack(size)?;
+
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let chunk_offset = offset + (i * 4) as i16;
+            self.instructions.push(Instruction::store32(
+                Register::R10,
+                chunk_offset,
+                i32::from_le_bytes(word),
+            ));
+        }
+
+        Ok(offset)
+    }
+
+    /// Stores a string literal on the stack and sets the register to its address.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the address.
+    /// * `literal` - The string literal, including its quotes.
+    fn emit_set_register_from_string(
+        &mut self,
+        reg: Register,
+        literal: &str,
+    ) -> InternalResult<Type> {
+        let bytes = self.parse_string_literal(literal)?;
+        let offset = self.emit_push_bytes(&bytes)?;
+        self.instructions
+            .push(Instruction::movx64(reg, Register::R10));
+        self.instructions
+            .push(Instruction::add64(reg, offset.into()));
+
+        Ok(Type {
+            base_type: BaseType::Integer(Integer {
+                used_bits: 8,
+                bits: 8,
+                is_signed: false,
+            }),
+            num_refs: 1,
+        })
+    }
+
+    /// Given a register and rvalue information, emits instructions that set the
+    /// register to the value of this lvalue access. This can either be an lvalue,
+    /// in which case `emit_set_register_from_lvalue` is called, an immediate, or
+    /// a function call.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the value.
+    /// * `lval` - The lvalue information.
+    /// * `load_type` - The BPF load type to use when setting the register value.
+    fn emit_set_register_from_rvalue(
+        &mut self,
+        reg: Register,
+        rval: &RValue,
+        load_type: Option<MemoryOpLoadType>,
+    ) -> InternalResult<Type> {
+        if let (Some(op), Some(right)) = (&rval.op, &rval.right) {
+            let var_type = self.emit_rvalue_arithmetic(&rval.left, op, right)?;
+            if !matches!(reg, Register::R6) {
+                self.instructions
+                    .push(Instruction::movx64(reg, Register::R6));
+            }
+            return Ok(var_type);
+        }
+
+        match &rval.left {
+            RValueInner::Immediate(imm_str) => {
+                self.emit_set_register_from_immediate(reg, imm_str, load_type)
+            }
+            RValueInner::SizeOf(size_of) => {
+                let imm_str = self.get_size_of(size_of)?.to_string();
+                self.emit_set_register_from_immediate(reg, &imm_str, load_type)
+            }
+            RValueInner::OffsetOf(offset_of) => {
+                let imm_str = self.get_offset_of(offset_of)?.to_string();
+                self.emit_set_register_from_immediate(reg, &imm_str, load_type)
+            }
+            RValueInner::LValue(lval) => self.emit_set_register_from_lvalue(reg, lval, load_type),
+            RValueInner::Volatile(volatile) => {
+                let start = self.instructions.current_index();
+                let result = self.emit_set_register_from_rvalue(reg, &volatile.value, load_type);
+                self.keep_unoptimized(start);
+                result
+            }
+            RValueInner::Parenthesized(group) => {
+                self.emit_set_register_from_rvalue(reg, &group.value, load_type)
+            }
+            RValueInner::Comparison(comparison) => {
+                self.emit_set_register_from_comparison(reg, &comparison.cond)
+            }
+            RValueInner::UnaryOp(unary) => self.emit_unary(reg, unary),
+            RValueInner::StringLiteral(literal) => self.emit_set_register_from_string(reg, literal),
+            RValueInner::FunctionCall(call) => {
+                let ret_type = self.emit_call(call)?;
+                if !matches!(reg, Register::R0) {
+                    self.instructions
+                        .push(Instruction::movx64(reg, Register::R0));
+                }
+                Ok(ret_type)
+            }
+        }
+    }
+
+    /// Emits instructions that set the register to 1 if the comparison holds and
+    /// to 0 otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the value.
+    /// * `cond` - The comparison.
+    fn emit_set_register_from_comparison(
+        &mut self,
+        reg: Register,
+        cond: &Condition,
+    ) -> InternalResult<Type> {
+        if Self::rvalue_contains_call(&cond.left) || Self::rvalue_contains_call(&cond.right) {
+            semantics_bail!(
+                self.expr_num,
+                "Comparisons used as values can't contain function calls"
+            );
+        }
+
+        /*
+         * if reg <op> temp goto one
+         * reg = 0
+         * goto end
+         * one: reg = 1
+         * end:
+         */
+        let temp = if reg == Register::R0 {
+            Register::R1
+        } else {
+            Register::R0
+        };
+        self.emit_condition_jump(cond, reg, temp, 2)?;
+        self.instructions.push(Instruction::mov64(reg, 0));
+        self.instructions.push(Instruction::jmp_abs(1));
+        self.instructions.push(Instruction::mov64(reg, 1));
+        self.optimized_len = self.instructions.current_index();
+
+        Ok(BaseType::Integer(Integer {
+            used_bits: 64,
+            bits: 64,
+            is_signed: false,
+        })
+        .into())
+    }
+
+    /// Warns when the address of an uninitialized variable is passed to a helper
+    /// argument that the helper reads from; the verifier rejects such programs.
+    /// Passing it to an argument that the helper writes, or passing it as
+    /// `&mut`, marks it as initialized. Without a known direction, a plain `&`
+    /// is assumed to be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper_name` - The name of the helper being called.
+    /// * `arg` - The argument being passed.
+    /// * `direction` - Whether the helper reads or writes the argument, if known.
+    fn check_helper_arg_initialized(
+        &mut self,
+        helper_name: &str,
+        arg: &RValue,
+        direction: Option<ArgDirection>,
+    ) {
+        let (lval, is_mut) = match (&arg.left, &arg.op) {
+            (
+                RValueInner::LValue(
+                    lval @ LValue {
+                        prefix: Some(Prefix::ReferencePrefix(reference)),
+                        ..
+                    },
+                ),
+                None,
+            ) => (lval, reference.is_mut.is_some()),
+            _ => return,
+        };
+
+        let initialized = match self.variables.get_mut(&lval.name) {
+            Some(info) if is_mut || direction == Some(ArgDirection::Out) => {
+                info.initialized = true;
+                return;
+            }
+            Some(info) => info.initialized,
+            None => return,
+        };
+
+        if !initialized {
+            self.warn(format!(
+                "Uninitialized variable \"{}\" is passed to \"{}\" which reads it",
+                lval.name, helper_name
+            ));
+        }
+    }
 
-        for _ in 0..size / 4 {
-            self.instructions
-                .push(Instruction::store32(Register::R10, offset, v64 as i32));
-            remaining -= 4;
-            offset += 4;
+    /// Warns when a capture that wasn't captured with `capture_map` is passed to
+    /// a helper argument that expects a map; the loaded value is only a valid map
+    /// reference if the capture really is a map identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper_name` - The name of the helper being called.
+    /// * `arg` - The argument being passed.
+    /// * `load_type` - The load type the helper expects for the argument.
+    fn check_helper_map_arg(
+        &mut self,
+        helper_name: &str,
+        arg: &RValue,
+        load_type: Option<MemoryOpLoadType>,
+    ) {
+        if !matches!(load_type, Some(MemoryOpLoadType::Map)) {
+            return;
         }
-        size = remaining;
 
-        for _ in 0..size / 2 {
-            self.instructions
-                .push(Instruction::store16(Register::R10, offset, v64 as i16));
-            remaining -= 2;
-            offset += 2;
+        let lval = match (&arg.left, &arg.op) {
+            (RValueInner::LValue(lval), None) if lval.prefix.is_none() => lval,
+            _ => return,
+        };
+
+        let is_capture = matches!(
+            self.variables.get(&lval.name).map(|info| info.location),
+            Some(VariableLocation::SpecialImmediate(_))
+        );
+        if is_capture && !self.maps.contains_key(&lval.name) {
+            self.warn(format!(
+                "Capture \"{}\" is passed to \"{}\" as a map but wasn't captured with capture_map",
+                lval.name, helper_name
+            ));
         }
-        size = remaining;
+    }
 
-        for _ in 0..size {
-            self.instructions
-                .push(Instruction::store8(Register::R10, offset, v64 as i8));
-            remaining -= 1;
-            offset += 1;
+    /// Validates the map and key arguments passed to the keyed map helpers
+    /// against the type of the captured map, if it was captured with
+    /// `capture_map`.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - The helper being called.
+    /// * `args` - The arguments passed to the helper.
+    fn check_map_call(&mut self, helper: Helpers, args: &[RValue]) -> InternalResult<()> {
+        if !matches!(
+            helper,
+            Helpers::MapLookupElem | Helpers::MapUpdateElem | Helpers::MapDeleteElem
+        ) {
+            return Ok(());
+        }
+
+        let (map_name, map_type) = match args.first().map(|arg| (&arg.left, &arg.op)) {
+            Some((RValueInner::LValue(lval), None)) => match self.maps.get(&lval.name) {
+                Some(info) => (lval.name.clone(), info.map_type),
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        if map_type.is_keyless() {
+            semantics_bail!(
+                self.expr_num,
+                "Map \"{}\" is a {:?} map and doesn't support keyed access",
+                map_name,
+                map_type
+            );
+        }
+
+        if !map_type.is_array() {
+            return Ok(());
+        }
+
+        let key = match args.get(1).map(|arg| (&arg.left, &arg.op)) {
+            Some((RValueInner::LValue(lval), None))
+                if matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) =>
+            {
+                lval
+            }
+            _ => return Ok(()),
+        };
+
+        let info = self.get_variable_by_name(&key.name)?;
+        let (_, key_type) = self.get_deref_offset(&info.var_type, &key.derefs)?;
+        let is_integer = matches!(key_type.base_type, BaseType::Integer(_) | BaseType::Enum(_))
+            && key_type.num_refs == 0
+            && key_type.get_size() == 4;
+        if !is_integer {
+            semantics_bail!(
+                self.expr_num,
+                "Array map \"{}\" takes a 32-bit integer index key, \"{}\" is not one",
+                map_name,
+                key.name
+            );
         }
+
+        Ok(())
     }
 
-    /// Emits instructions that push the immediate value to the stack as the given type.
+    /// Returns whether a condition operand is a struct or array value (not a
+    /// pointer to one). Aggregates can't be loaded into a register and have no
+    /// meaningful ordering, so they can't be compared.
     ///
     /// # Arguments
     ///
-    /// * `imm_str` - The string representation of the immediate value.
-    /// * `cast_type` - The destination type.
-    /// * `use_offset` - An optional offset at which the value is placed.
-    fn emit_push_immediate(
-        &mut self,
-        imm_str: &str,
-        cast_type: &Type,
-        use_offset: Option<i16>,
-    ) -> InternalResult<(i16, Type)> {
-        let size = cast_type.get_size();
-        if size == 0 && !matches!(cast_type.base_type, BaseType::Void) {
-            semantics_bail!(self.expr_num, "Can't assign to zero-sized type");
+    /// * `rval` - The condition operand.
+    fn is_aggregate_operand(&mut self, rval: &RValue) -> InternalResult<bool> {
+        let lval = match (&rval.left, &rval.op) {
+            (RValueInner::LValue(lval), None) => lval,
+            (RValueInner::Parenthesized(group), None) => {
+                return self.is_aggregate_operand(&group.value)
+            }
+            _ => return Ok(false),
+        };
+
+        if self.get_enum_constant(lval)?.is_some() {
+            return Ok(false);
         }
 
-        let offset = match use_offset {
-            Some(off) => off,
-            None => self.push_stack(size)?,
+        let info = self.get_variable_by_name(&lval.name)?;
+        if matches!(info.location, VariableLocation::SpecialImmediate(_)) {
+            return Ok(false);
+        }
+
+        let mut cur_type = &info.var_type;
+        for deref in &lval.derefs {
+            cur_type = match deref {
+                DeReference::FieldAccess(ma) => self.get_field_access(cur_type, &ma.name)?.1,
+                DeReference::ArrayIndex(_) => self.get_array_element(cur_type)?.1,
+            };
+        }
+
+        let num_refs = match lval.prefix {
+            Some(Prefix::ReferencePrefix(_)) => return Ok(false),
+            Some(Prefix::DeReferencePrefix(_)) => cur_type.num_refs.saturating_sub(1),
+            None => cur_type.num_refs,
         };
 
-        if cast_type.is_pointer() {
-            let imm = self.parse_immediate::<u8>(imm_str)?;
-            self.instructions
-                .push(Instruction::store8(Register::R10, offset, imm as i8));
-            return Ok((offset, cast_type.clone()));
+        Ok(num_refs == 0
+            && matches!(
+                cur_type.base_type,
+                BaseType::Struct(_) | BaseType::Union(_) | BaseType::Array(_)
+            ))
+    }
+
+    /// Returns whether a type is a floating-point value (not a pointer to one).
+    /// Floats are loaded as opaque integers of the same size, so only their
+    /// raw bits can be compared.
+    ///
+    /// # Arguments
+    ///
+    /// * `ty` - The type to check.
+    fn is_float(ty: &Type) -> bool {
+        matches!(ty.base_type, BaseType::Float(_)) && !ty.is_pointer()
+    }
+
+    /// Returns whether a type is a signed integer (not a pointer to one), in which
+    /// case comparisons against it must use the signed jump operations.
+    ///
+    /// # Arguments
+    ///
+    /// * `ty` - The type to check.
+    fn is_signed(ty: &Type) -> bool {
+        match &ty.base_type {
+            BaseType::Integer(integer) => integer.is_signed && !ty.is_pointer(),
+            _ => false,
         }
+    }
 
-        // No type was given so a 64-bit unsigned integer is inferred
-        if matches!(cast_type.base_type, BaseType::Void) {
-            let imm = self.parse_immediate::<i64>(imm_str)?;
-            self.instructions
-                .push(Instruction::store64(Register::R10, offset, imm));
-            let new_type = BaseType::Integer(Integer {
-                used_bits: 64,
-                bits: 64,
-                is_signed: false,
-            });
-            return Ok((offset, new_type.into()));
+    /// Emits instructions that sign-extend a signed integer narrower than 64 bits.
+    /// Such values are zero-extended when loaded, so they must be sign-extended
+    /// before any operation that treats the whole register as signed.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register holding the value.
+    /// * `ty` - The type of the value.
+    fn emit_sign_extend(&mut self, reg: Register, ty: &Type) {
+        let unused_bits = 64 - (ty.get_size() * 8) as i32;
+        if !Self::is_signed(ty) || unused_bits <= 0 {
+            return;
         }
 
-        if let BaseType::Integer(integer) = &cast_type.base_type {
-            match (size, integer.is_signed) {
-                (1, false) => {
-                    let imm = self.parse_immediate::<u8>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store8(Register::R10, offset, imm as i8));
-                }
-                (1, true) => {
-                    let imm = self.parse_immediate::<i8>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store8(Register::R10, offset, imm));
-                }
-                (2, false) => {
-                    let imm = self.parse_immediate::<u16>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store16(Register::R10, offset, imm as i16));
-                }
-                (2, true) => {
-                    let imm = self.parse_immediate::<i16>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store16(Register::R10, offset, imm));
-                }
-                (4, false) => {
-                    let imm = self.parse_immediate::<u32>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store32(Register::R10, offset, imm as i32));
+        self.instructions.push(Instruction::alu64(
+            reg,
+            unused_bits,
+            ArithmeticOperation::Lhs,
+        ));
+        self.instructions.push(Instruction::alu64(
+            reg,
+            unused_bits,
+            ArithmeticOperation::Ash,
+        ));
+    }
+
+    /// Returns the arguments of a call as rvalues. A comparison passed as an
+    /// argument becomes a comparison rvalue, evaluating to 0 or 1.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - Information about the call.
+    fn get_call_args(call: &FunctionCall) -> Vec<RValue> {
+        call.args
+            .iter()
+            .map(|arg| match (&arg.op, &arg.right) {
+                (Some(op), Some(right)) => RValue {
+                    left: RValueInner::Comparison(Comparison {
+                        cond: Box::new(Condition {
+                            left: arg.value.clone(),
+                            op: op.clone(),
+                            right: right.clone(),
+                        }),
+                    }),
+                    op: None,
+                    right: None,
+                },
+                _ => arg.value.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns whether evaluating an rvalue involves a function call.
+    ///
+    /// # Arguments
+    ///
+    /// * `rval` - The rvalue to check.
+    fn rvalue_contains_call(rval: &RValue) -> bool {
+        Self::inner_contains_call(&rval.left)
+            || rval.right.as_ref().is_some_and(Self::inner_contains_call)
+    }
+
+    /// Returns whether evaluating an operand involves a function call.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The operand to check.
+    fn inner_contains_call(inner: &RValueInner) -> bool {
+        match inner {
+            RValueInner::FunctionCall(_) => true,
+            RValueInner::Volatile(volatile) => Self::rvalue_contains_call(&volatile.value),
+            RValueInner::Parenthesized(group) => Self::rvalue_contains_call(&group.value),
+            RValueInner::UnaryOp(unary) => Self::inner_contains_call(&unary.value),
+            RValueInner::LValue(lval) => lval.derefs.iter().any(|deref| {
+                matches!(deref, DeReference::ArrayIndex(index)
+                    if Self::rvalue_contains_call(&index.element))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Returns whether a call is to a built-in helper that returns a pointer, e.g.
+    /// `map_lookup_elem`, whose result can then be stored in a pointer variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - Information about the call.
+    fn call_returns_pointer(&self, call: &FunctionCall) -> bool {
+        if self.custom_helpers.contains_key(&call.name) || !self.builtin_helpers {
+            return false;
+        }
+
+        Helpers::from_string(&call.name).is_some_and(|helper| {
+            matches!(
+                helper.get_return_type(),
+                MemoryOpLoadType::MapValue | MemoryOpLoadType::BtfId
+            )
+        })
+    }
+
+    /// Returns the width, in bits, of the byte-swap intrinsic with the given name,
+    /// or `None` if the name isn't a byte-swap intrinsic. BPF targets are assumed
+    /// to be little-endian, so converting to and from network order is a swap.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the function being called.
+    fn get_byte_swap_width(name: &str) -> Option<u32> {
+        match name {
+            "ntohs" | "htons" | "bswap16" => Some(16),
+            "ntohl" | "htonl" | "bswap32" => Some(32),
+            "bswap64" => Some(64),
+            _ => None,
+        }
+    }
+
+    /// Emits a byte-swap intrinsic, e.g. `ntohs(x)`. Like a helper call, the
+    /// result is left in R0.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - Information about the call.
+    /// * `bits` - The width of the value being swapped.
+    fn emit_byte_swap(&mut self, call: &FunctionCall, bits: u32) -> InternalResult<Type> {
+        if call.args.len() != 1 {
+            semantics_bail!(
+                self.expr_num,
+                "\"{}\" takes exactly one argument",
+                call.name
+            );
+        }
+
+        let args = Self::get_call_args(call);
+        let arg_type = self.emit_set_register_from_rvalue(Register::R0, &args[0], None)?;
+        if !matches!(arg_type.base_type, BaseType::Integer(_)) || arg_type.is_pointer() {
+            semantics_bail!(
+                self.expr_num,
+                "\"{}\" can only be applied to integers",
+                call.name
+            );
+        }
+
+        /*
+         * bpf-ins has no constructor for the byte-swap instruction, so it's built
+         * from its encoding: BPF_ALU | BPF_END | BPF_TO_BE, with the width in imm.
+         */
+        let raw = 0xdc | (Register::R0 as u64) << 8 | (bits as u64) << 32;
+        let swap = Instruction::decode(&[raw]).map_err(|_| Error::InternalError)?;
+        self.instructions.push(swap);
+
+        Ok(BaseType::Integer(Integer {
+            used_bits: 64,
+            bits: 64,
+            is_signed: false,
+        })
+        .into())
+    }
+
+    /// Returns the arguments to pass to a helper. `trace_printk` takes a format
+    /// string followed by its size; when the format is a string literal the size
+    /// is filled in automatically, e.g. `trace_printk("%d\n", x)`. A constant size
+    /// may still be given explicitly, e.g. `trace_printk("hi\n", 4)`, as long as
+    /// it covers the NUL terminator and doesn't exceed the stored string.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - The helper being called.
+    /// * `args` - The arguments as written in the script.
+    fn expand_call_args(
+        &mut self,
+        helper: Helpers,
+        args: &[RValue],
+    ) -> InternalResult<Vec<RValue>> {
+        let literal = match (helper, args.first()) {
+            (
+                Helpers::TracePrintk,
+                Some(RValue {
+                    left: RValueInner::StringLiteral(literal),
+                    op: None,
+                    ..
+                }),
+            ) => literal,
+            _ => return Ok(args.to_vec()),
+        };
+
+        let format = self.parse_string_literal(literal)?;
+        let mut num_specifiers = 0;
+        let mut i = 0;
+        while i < format.len() {
+            if format[i] == b'%' {
+                if format.get(i + 1) == Some(&b'%') {
+                    i += 1;
+                } else {
+                    num_specifiers += 1;
                 }
-                (4, true) => {
-                    let imm = self.parse_immediate::<i32>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store32(Register::R10, offset, imm));
+            }
+            i += 1;
+        }
+
+        if args.len() == num_specifiers + 2 {
+            if let Some(size) = self.get_constant_value(&args[1])? {
+                if size < 0 || size as usize > format.len() {
+                    semantics_bail!(
+                        self.expr_num,
+                        "Format size {} exceeds the {} bytes of the format string",
+                        size,
+                        format.len()
+                    );
                 }
-                (8, false) => {
-                    let imm = self.parse_immediate::<u64>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store64(Register::R10, offset, imm as i64));
+
+                // the helper fails unless the format is NUL-terminated within the size.
+                let min_size = format.iter().position(|b| *b == 0).unwrap_or(format.len()) + 1;
+                if (size as usize) < min_size {
+                    semantics_bail!(
+                        self.expr_num,
+                        "Format size {} cuts off the NUL terminator; it must be at least {}",
+                        size,
+                        min_size
+                    );
                 }
-                (8, true) => {
-                    let imm = self.parse_immediate::<i64>(imm_str)?;
-                    self.instructions
-                        .push(Instruction::store64(Register::R10, offset, imm));
+                return Ok(args.to_vec());
+            }
+        }
+
+        if num_specifiers != args.len() - 1 {
+            semantics_bail!(
+                self.expr_num,
+                "Format string expects {} arguments but {} were given",
+                num_specifiers,
+                args.len() - 1
+            );
+        }
+
+        let mut expanded = args.to_vec();
+        expanded.insert(
+            1,
+            RValue {
+                left: RValueInner::Immediate(format.len().to_string()),
+                op: None,
+                right: None,
+            },
+        );
+        Ok(expanded)
+    }
+
+    /// Emits instructions that perform a call.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - Information about the call.
+    fn emit_call(&mut self, call: &FunctionCall) -> InternalResult<Type> {
+        if let Some(bits) = Self::get_byte_swap_width(&call.name) {
+            return self.emit_byte_swap(call, bits);
+        }
+
+        if call.name == "map_get" {
+            semantics_bail!(
+                self.expr_num,
+                "map_get produces a value and can only be assigned to a variable"
+            );
+        }
+
+        let custom = self.custom_helpers.get(&call.name).copied();
+        let helper = match custom {
+            Some(_) => None,
+            None if self.builtin_helpers => Helpers::from_string(&call.name),
+            None => None,
+        };
+
+        let (id, types, directions, is_signed) = match (custom, helper) {
+            (Some((id, arg_types)), _) => (id, arg_types.to_vec(), vec![], false),
+            (None, Some(helper)) => (
+                helper.id(),
+                helper.get_arg_types().to_vec(),
+                helper.get_arg_directions().to_vec(),
+                helper.is_return_signed(),
+            ),
+            (None, None) => {
+                semantics_bail!(self.expr_num, "Unknown function \"{}\"", call.name);
+            }
+        };
+
+        const ARG_REGISTERS: [Register; 5] = [
+            Register::R1,
+            Register::R2,
+            Register::R3,
+            Register::R4,
+            Register::R5,
+        ];
+
+        let args = match helper {
+            Some(helper) => {
+                let args = self.expand_call_args(helper, &Self::get_call_args(call))?;
+                self.check_map_call(helper, &args)?;
+                args
+            }
+            None => Self::get_call_args(call),
+        };
+        if args.len() > ARG_REGISTERS.len() {
+            semantics_bail!(self.expr_num, "Function call exceeds 5 arguments");
+        }
+
+        // helpers registered at runtime are checked against their prototype, if
+        // the type database has one; built-in helpers have their own signatures.
+        let prototype = match custom {
+            Some(_) => self.get_function_prototype(&call.name),
+            None => None,
+        };
+        if let Some(param_type_ids) = &prototype {
+            if args.len() != param_type_ids.len() {
+                semantics_bail!(
+                    self.expr_num,
+                    "Function \"{}\" takes {} arguments but {} were given",
+                    call.name,
+                    param_type_ids.len(),
+                    args.len()
+                );
+            }
+        }
+        let mut arg_types = vec![None; args.len()];
+
+        // Arguments containing calls are evaluated first, since a call clobbers
+        // R0-R5. All but the last of them are spilled to the stack; the last one
+        // can go straight into its register.
+        let call_args: Vec<usize> = (0..args.len())
+            .filter(|i| Self::rvalue_contains_call(&args[*i]))
+            .collect();
+        let mut spilled = HashMap::new();
+        for (n, i) in call_args.iter().enumerate() {
+            let arg_type = types.get(*i).copied();
+            if n + 1 == call_args.len() {
+                arg_types[*i] = Some(self.emit_set_register_from_rvalue(
+                    ARG_REGISTERS[*i],
+                    &args[*i],
+                    arg_type,
+                )?);
+            } else {
+                arg_types[*i] =
+                    Some(self.emit_set_register_from_rvalue(Register::R0, &args[*i], arg_type)?);
+                let offset = self.push_stack(8)?;
+                self.instructions
+                    .push(Instruction::storex64(Register::R10, offset, Register::R0));
+                spilled.insert(*i, offset);
+            }
+        }
+
+        for (i, arg) in args.iter().enumerate() {
+            self.check_helper_arg_initialized(&call.name, arg, directions.get(i).copied());
+            self.check_helper_map_arg(&call.name, arg, types.get(i).copied());
+
+            if let Some(offset) = spilled.get(&i) {
+                self.instructions.push(Instruction::loadx64(
+                    ARG_REGISTERS[i],
+                    Register::R10,
+                    *offset,
+                ));
+            } else if !call_args.contains(&i) {
+                arg_types[i] = Some(self.emit_set_register_from_rvalue(
+                    ARG_REGISTERS[i],
+                    arg,
+                    types.get(i).copied(),
+                )?);
+            }
+        }
+
+        if let Some(param_type_ids) = &prototype {
+            for (i, (arg, arg_type)) in args.iter().zip(&arg_types).enumerate() {
+                if let Some(arg_type) = arg_type {
+                    self.check_prototype_arg(&call.name, i, param_type_ids[i], arg, arg_type)?;
                 }
-                (bits, _) => {
-                    semantics_bail!(self.expr_num, "{}-bit integers not supported", bits);
+            }
+        }
+        self.instructions.push(Instruction::call(id));
+        self.helpers_used.insert(id);
+
+        // a lookup in a map captured with a value type returns a pointer to
+        // that type, so the value can be read and updated in place.
+        let return_type = helper.map(|helper| helper.get_return_type());
+        if matches!(return_type, Some(MemoryOpLoadType::MapValue)) {
+            if let Some(RValueInner::LValue(lval)) = args.first().map(|arg| &arg.left) {
+                if let Some(MapInfo {
+                    value_type: Some(value_type),
+                    ..
+                }) = self.maps.get(&lval.name)
+                {
+                    let mut var_type = value_type.clone();
+                    var_type.num_refs += 1;
+                    return Ok(var_type);
                 }
-            };
-        } else {
-            let imm = self.parse_immediate::<i8>(imm_str)?;
-            self.emit_init_stack_range(offset, imm, size);
+            }
         }
 
-        Ok((offset, cast_type.clone()))
+        let var_type: Type = BaseType::Integer(Integer {
+            used_bits: 64,
+            bits: 64,
+            is_signed,
+        })
+        .into();
+
+        Ok(var_type)
     }
 
-    /// Emits instructions that push a register to the stack. If an offset is given,
-    /// the register is pushed to that offset.
+    /// Returns the parameter type ids of a function, if the type database has a
+    /// prototype for it, e.g. one imported from BTF.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register to for which a push is emitted.
-    /// * `offset` - The stack offset to which the register is pushed.
-    fn emit_push_register(&mut self, reg: Register, offset: Option<i16>) -> InternalResult<i16> {
-        let offset = if let Some(offset) = offset {
-            offset
+    /// * `name` - The name of the function.
+    fn get_function_prototype(&self, name: &str) -> Option<Vec<usize>> {
+        match self.types.get_type_by_name(name) {
+            Some(Type {
+                base_type: BaseType::Function(function),
+                num_refs: 0,
+            }) => Some(function.param_type_ids.clone()),
+            _ => None,
+        }
+    }
+
+    /// Fails if a call argument's type doesn't match the parameter type in the
+    /// function's prototype. Integers (and enums) of any width can be passed for
+    /// one another, since arguments are passed in 64-bit registers; pointers
+    /// must point to the same type, unless the parameter is a void pointer, and
+    /// a constant 0 can be passed as a null pointer.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the function.
+    /// * `index` - The index of the argument.
+    /// * `param_type_id` - The type id of the parameter.
+    /// * `arg` - The argument.
+    /// * `arg_type` - The type of the argument.
+    fn check_prototype_arg(
+        &mut self,
+        name: &str,
+        index: usize,
+        param_type_id: usize,
+        arg: &RValue,
+        arg_type: &Type,
+    ) -> InternalResult<()> {
+        let types = self.types;
+        let param_type = types
+            .get_type_by_id(param_type_id)
+            .context(self.expr_num, "Internal error; type id invalid")?;
+
+        let matches = if param_type.is_pointer() {
+            match arg_type.is_pointer() {
+                true if matches!(param_type.base_type, BaseType::Void) => true,
+                true => arg_type == param_type,
+                false => self.get_constant_value(arg)? == Some(0),
+            }
+        } else if arg_type.is_pointer() {
+            false
         } else {
-            self.push_stack(8)?
+            match (&param_type.base_type, &arg_type.base_type) {
+                (
+                    BaseType::Integer(_) | BaseType::Enum(_),
+                    BaseType::Integer(_) | BaseType::Enum(_),
+                ) => true,
+                (param_base, arg_base) => param_base == arg_base,
+            }
         };
 
-        self.instructions
-            .push(Instruction::storex64(Register::R10, offset, reg));
-        Ok(offset)
+        if !matches {
+            semantics_bail!(
+                self.expr_num,
+                "Argument {} of \"{}\" doesn't match the type of its parameter",
+                index + 1,
+                name
+            );
+        }
+        Ok(())
     }
 
-    /// Emits instructions that dereference a register to the stack using its
-    /// currently held type. This always emits a `bpf_probe_read` call because
-    /// only certain memory can be directly dereferenced by BPF instructions but
-    /// all memory can be read through the helper.
+    /// Returns the value of an rvalue if it's known at compile time: immediates,
+    /// `sizeof`/`offsetof` expressions and captured values. Returns `None` for
+    /// anything that must be evaluated at runtime, including anything wrapped in
+    /// `volatile(...)`, which opts a value out of compile-time evaluation.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register holding the address to dereference.
-    /// * `deref_type` - The type of dereference.
-    /// * `offset` - The offset in the stack to which the value is copied.
-    fn emit_deref_register_to_stack(&mut self, reg: Register, deref_type: &Type, offset: i16) {
-        self.instructions
-            .push(Instruction::movx64(Register::R1, Register::R10));
-        self.instructions
-            .push(Instruction::add64(Register::R1, offset.into()));
-        self.instructions.push(Instruction::mov64(
-            Register::R2,
-            deref_type.get_size() as i32,
-        ));
-        self.instructions
-            .push(Instruction::movx64(Register::R3, reg));
-        self.instructions
-            .push(Instruction::call(Helpers::ProbeRead as u32));
+    /// * `rval` - The rvalue to evaluate.
+    fn get_constant_value(&mut self, rval: &RValue) -> InternalResult<Option<i64>> {
+        if rval.op.is_some() {
+            return Ok(None);
+        }
+
+        let value = match &rval.left {
+            RValueInner::Immediate(imm_str) => self.parse_immediate::<i64>(imm_str)?,
+            RValueInner::SizeOf(size_of) => self.get_size_of(size_of)?.into(),
+            RValueInner::OffsetOf(offset_of) => self.get_offset_of(offset_of)?.into(),
+            RValueInner::Parenthesized(group) => return self.get_constant_value(&group.value),
+            RValueInner::LValue(lval) if lval.prefix.is_none() && lval.derefs.is_empty() => {
+                match self.variables.get(&lval.name).map(|info| info.location) {
+                    Some(VariableLocation::SpecialImmediate(v)) => v,
+                    Some(_) => return Ok(None),
+                    None => match self.get_enum_constant(lval)? {
+                        Some((value, _)) => value,
+                        None => return Ok(None),
+                    },
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(value))
     }
 
-    /// Emits instructions that push an lvalue to the stack. Lvalues in this
-    /// language are anything that occurs on the left side of an assignment.
-    /// Currently, this is just stored variables.
+    /// Returns the condition for a clause of a boolean expression. A logical not,
+    /// `!value`, is the condition `value == 0`.
     ///
     /// # Arguments
     ///
-    /// * `lval` - The lvalue description.
-    /// * `cast_type` - The destination type, this can differ on re-assignments.
-    /// * `use_offset` - The (optional) offset at which the value should be stored.
-    fn emit_push_lvalue(
-        &mut self,
-        lval: &LValue,
-        cast_type: &Type,
-        use_offset: Option<i16>,
-    ) -> InternalResult<(i16, Type)> {
-        // This emits instructions to set R6 to a pointer to the lvalue, the type
-        // of the lvalue is returned by the function into `var_type`.
-        let var_type = self.emit_set_register_to_lvalue_addr(Register::R6, lval)?;
+    /// * `term` - The clause.
+    fn get_term_condition(&self, term: &BoolTerm) -> InternalResult<Condition> {
+        match term {
+            BoolTerm::Condition(cond) => Ok(cond.clone()),
+            BoolTerm::UnaryOp(UnaryOp {
+                op: UnaryOperator::LogicalNot(_),
+                value,
+            }) => Ok(Condition {
+                left: RValue {
+                    left: (**value).clone(),
+                    op: None,
+                    right: None,
+                },
+                op: Comparator::Equals(Equals),
+                right: RValue {
+                    left: RValueInner::Immediate("0".to_string()),
+                    op: None,
+                    right: None,
+                },
+            }),
+            BoolTerm::UnaryOp(_) => {
+                semantics_bail!(
+                    self.expr_num,
+                    "\"~\" produces a value, not a condition; compare the value instead"
+                );
+            }
+        }
+    }
 
-        // If the cast type is `void` we "deduce" the type to be the type of the lvalue.
-        let mut real_type = if matches!(cast_type.base_type, BaseType::Void) {
-            var_type.clone()
-        } else {
-            cast_type.clone()
-        };
+    /// Returns the declared type of a constant rvalue: the type of a typed capture
+    /// or of an enum constant. Returns `None` for untyped constants, such as
+    /// immediates, and for anything that isn't constant.
+    ///
+    /// # Arguments
+    ///
+    /// * `rval` - The rvalue.
+    fn get_constant_type(&mut self, rval: &RValue) -> InternalResult<Option<Type>> {
+        if rval.op.is_some() {
+            return Ok(None);
+        }
 
-        // The effective type must match the type of the lvalue in size.
-        if real_type.get_size() != var_type.get_size() {
-            semantics_bail!(self.expr_num, "Cannot assign two types of different sizes");
+        match &rval.left {
+            RValueInner::Parenthesized(group) => self.get_constant_type(&group.value),
+            RValueInner::LValue(lval) if lval.prefix.is_none() && lval.derefs.is_empty() => {
+                match self.variables.get(&lval.name) {
+                    Some(info) => match info.location {
+                        VariableLocation::SpecialImmediate(_) => Ok(Some(info.var_type.clone())),
+                        _ => Ok(None),
+                    },
+                    None => Ok(self.get_enum_constant(lval)?.map(|(_, ty)| ty)),
+                }
+            }
+            _ => Ok(None),
         }
+    }
 
-        // Makes enough space on the stack to hold the value.
-        let offset = match use_offset {
-            Some(off) => off,
-            None => self.push_stack(real_type.get_size())?,
+    /// Evaluates a condition at compile time, if both of its operands are constant.
+    /// Operands are compared with the signedness and width of their declared types,
+    /// the same as the emitted jumps.
+    ///
+    /// # Arguments
+    ///
+    /// * `cond` - The condition to evaluate.
+    fn evaluate_constant_condition(&mut self, cond: &Condition) -> InternalResult<Option<bool>> {
+        let (mut left, mut right) = match (
+            self.get_constant_value(&cond.left)?,
+            self.get_constant_value(&cond.right)?,
+        ) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Ok(None),
         };
 
-        // Lastly, handle the prefix, either reference (&), dereference (*), or nothing.
-        match lval.prefix {
-            None => self.emit_deref_register_to_stack(Register::R6, &real_type, offset),
-            Some(Prefix::DeReferencePrefix(_)) => {
-                semantics_bail!(self.expr_num, "Dereferencing is not currently supported");
+        let left_type = self.get_constant_type(&cond.left)?;
+        let right_type = self.get_constant_type(&cond.right)?;
+        let is_signed = left_type.as_ref().is_some_and(Self::is_signed)
+            || right_type.as_ref().is_some_and(Self::is_signed);
+
+        /*
+         * an untyped constant takes its representation from the other operand.
+         */
+        match (&left_type, &right_type) {
+            (None, Some(right_type)) => {
+                left = Self::get_condition_immediate(left, right_type, is_signed);
             }
-            Some(Prefix::ReferencePrefix(_)) => {
-                real_type.num_refs += 1;
-                self.instructions
-                    .push(Instruction::storex64(Register::R10, offset, Register::R6));
+            (Some(left_type), None) => {
+                right = Self::get_condition_immediate(right, left_type, is_signed);
             }
+            _ => {}
         }
 
-        Ok((offset, real_type.clone()))
+        let ordering = if is_signed {
+            left.cmp(&right)
+        } else {
+            (left as u64).cmp(&(right as u64))
+        };
+
+        Ok(Some(match cond.op {
+            Comparator::Equals(_) => ordering.is_eq(),
+            Comparator::NotEquals(_) => ordering.is_ne(),
+            Comparator::GreaterThan(_) => ordering.is_gt(),
+            Comparator::GreaterOrEqual(_) => ordering.is_ge(),
+            Comparator::LessThan(_) => ordering.is_lt(),
+            Comparator::LessOrEqual(_) => ordering.is_le(),
+        }))
     }
 
-    /// Emits instructions that perform the arithmetic for the given rvalue. Registers
-    /// 6 and 7 are used to perform the operation, result is stored in R6.
+    /// Evaluates a boolean expression at compile time. Clauses are evaluated from
+    /// left to right, and the result is only known if every clause up to the one
+    /// that decides it is constant, so calls in later clauses are never skipped
+    /// unless they wouldn't have run anyway.
     ///
     /// # Arguments
     ///
-    /// * `left` - The RValue on the left of the operation.
-    /// * `operation` - The arithmetic operation.
-    /// * `right` - The RValue on the right of the operation.
-    fn emit_rvalue_arithmetic(
-        &mut self,
-        left: &RValueInner,
-        operation: &Operation,
-        right: &RValueInner,
-    ) -> InternalResult<Type> {
-        let left_as_rval = RValue {
-            left: left.clone(),
-            op: None,
-            right: None,
-        };
-        let right_as_rval = RValue {
-            left: right.clone(),
-            op: None,
-            right: None,
-        };
+    /// * `expr` - The boolean expression to evaluate.
+    fn evaluate_constant_bool_expr(&mut self, expr: &BoolExpr) -> InternalResult<Option<bool>> {
+        'terms: for term in &expr.terms {
+            for cond in &term.conds {
+                let cond = self.get_term_condition(cond)?;
+                match self.evaluate_constant_condition(&cond)? {
+                    Some(true) => {}
+                    Some(false) => continue 'terms,
+                    None => return Ok(None),
+                }
+            }
 
-        let left_type = self.emit_set_register_from_rvalue(Register::R6, &left_as_rval, None)?;
-        let right_type = self.emit_set_register_from_rvalue(Register::R7, &right_as_rval, None)?;
-        if left_type != right_type {
-            semantics_bail!(
-                self.expr_num,
-                "Arithmetic can only be performed on the same types"
-            );
+            return Ok(Some(true));
         }
 
-        let operation = match operation {
-            Operation::Plus(_) => ArithmeticOperation::Add,
-            Operation::Minus(_) => ArithmeticOperation::Sub,
-            Operation::Times(_) => ArithmeticOperation::Mul,
-            Operation::LeftShift(_) => ArithmeticOperation::Lhs,
-            Operation::RightShift(_) => ArithmeticOperation::Rhs,
-            Operation::And(_) => ArithmeticOperation::And,
-            Operation::Or(_) => ArithmeticOperation::Or,
-        };
+        Ok(Some(false))
+    }
 
-        self.instructions
-            .push(Instruction::alux64(Register::R6, Register::R7, operation));
+    /// Returns the number of expressions in a body, including those nested in
+    /// conditional blocks. Used to keep expression numbers stable when a body is
+    /// skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `exprs` - The expressions in the body.
+    fn count_expressions(exprs: &[Expression]) -> u32 {
+        exprs
+            .iter()
+            .map(|expr| match expr {
+                Expression::IfStatement(if_statement) => Self::count_if_expressions(if_statement),
+                Expression::WhileStatement(while_statement) => {
+                    1 + Self::count_expressions(&while_statement.exprs)
+                }
+                _ => 1,
+            })
+            .sum()
+    }
 
-        Ok(right_type)
+    /// Returns the number of expressions in an if statement, counting each `else if`
+    /// in the chain as an expression of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `if_statement` - The if statement.
+    fn count_if_expressions(if_statement: &IfStatement) -> u32 {
+        1 + Self::count_expressions(&if_statement.exprs)
+            + Self::count_else_expressions(if_statement)
     }
 
-    /// Emits instructions that push an rvalue to the stack. RValues in this language
-    /// are anything that occur on the right hand side of an assignment: immediates,
-    /// lvalues, function calls, etc.
+    /// Returns the number of expressions in the else branch of an if statement.
     ///
     /// # Arguments
     ///
-    /// * `rval` - The rvalue to be pushed to the stack.
-    /// * `cast_type` - The type of the value, this can be different when casting.
-    /// * `use_offset` - An optional offset to which the value is pushed.
-    fn emit_push_rvalue(
-        &mut self,
-        rval: &RValue,
-        cast_type: &Type,
-        use_offset: Option<i16>,
-    ) -> InternalResult<(i16, Type)> {
-        if let (Some(op), Some(right)) = (&rval.op, &rval.right) {
-            let var_type = self.emit_rvalue_arithmetic(&rval.left, op, right)?;
-            if !matches!(cast_type.base_type, BaseType::Void) && var_type != *cast_type {
-                semantics_bail!(
-                    self.expr_num,
-                    "Cannot store result of arithmetic in this type"
-                );
-            }
-            let offset = self.emit_push_register(Register::R6, use_offset)?;
-            return Ok((offset, var_type));
+    /// * `if_statement` - The if statement.
+    fn count_else_expressions(if_statement: &IfStatement) -> u32 {
+        match &if_statement.else_if {
+            Some(else_if) => Self::count_if_expressions(else_if),
+            None => Self::count_expressions(&if_statement.else_exprs),
         }
+    }
 
-        match &rval.left {
-            RValueInner::Immediate(imm_str) => {
-                self.emit_push_immediate(imm_str, cast_type, use_offset)
+    /// Returns the representation of a constant condition operand that matches the
+    /// other operand. Registers holding unsigned values narrower than 64 bits are
+    /// zero-extended, so in unsigned comparisons a negative constant is truncated
+    /// to the other operand's width, e.g. -1 compared to a u32 is 0xffffffff.
+    /// Signed comparisons use the sign-extended value, since narrow signed
+    /// operands are sign-extended in the register before they're compared.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The constant value.
+    /// * `other_type` - The type of the other operand.
+    /// * `is_signed` - Whether the comparison is signed.
+    fn get_condition_immediate(value: i64, other_type: &Type, is_signed: bool) -> i64 {
+        let bits = match &other_type.base_type {
+            BaseType::Integer(_) | BaseType::Enum(_) if !other_type.is_pointer() => {
+                other_type.get_size() * 8
             }
-            RValueInner::LValue(lval) => self.emit_push_lvalue(lval, cast_type, use_offset),
-            RValueInner::FunctionCall(call) => {
-                let ret_type = self.emit_call(call)?;
-                let var_type = match &cast_type.base_type {
-                    BaseType::Void => &ret_type,
-                    BaseType::Integer(integer) => {
-                        if integer.get_size() != 8 {
-                            semantics_bail!(
-                                self.expr_num,
-                                "Function return values can only be stored in 64-bit types"
-                            );
-                        }
-                        cast_type
-                    }
-                    _ => {
-                        semantics_bail!(
-                            self.expr_num,
-                            "Function return values can only be stored in integer types"
-                        );
-                    }
-                };
-                let offset = self.emit_push_register(Register::R0, use_offset)?;
-                Ok((offset, var_type.clone()))
+            _ => return value,
+        };
+
+        if is_signed || value >= 0 || bits >= 64 {
+            return value;
+        }
+
+        value & ((1 << bits) - 1)
+    }
+
+    /// Emits an instruction that sets a register to a constant, using a wide load
+    /// if the constant doesn't fit a 32-bit immediate.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - The register that receives the value.
+    /// * `value` - The constant value.
+    fn emit_set_register_from_constant(&mut self, reg: Register, value: i64) {
+        match i32::try_from(value) {
+            Ok(imm) => self.instructions.push(Instruction::mov64(reg, imm)),
+            Err(_) => {
+                self.instructions
+                    .push(Instruction::loadtype(reg, value, MemoryOpLoadType::Void))
             }
         }
     }
 
-    /// Returns the offset and type from a structure and field name.
+    /// Emits an if statement whose condition was resolved at compile time. Only the
+    /// taken branch is emitted and no jumps are needed.
     ///
     /// # Arguments
     ///
-    /// * `structure` - The structure to access.
-    /// * `field_name` - The field within the structure.
-    fn get_field_access(
+    /// * `if_statement` - The if statement information.
+    /// * `taken` - Whether the condition holds.
+    fn emit_pruned_if_statement(
         &mut self,
-        structure: &Type,
-        field_name: &str,
-    ) -> InternalResult<(u32, Type)> {
-        let structure = if let BaseType::Struct(structure) = &structure.base_type {
-            structure
+        if_statement: &IfStatement,
+        taken: bool,
+    ) -> InternalResult<()> {
+        if taken {
+            self.emit_scoped_body(&if_statement.exprs)?;
+            self.expr_num += Self::count_else_expressions(if_statement);
         } else {
-            semantics_bail!(self.expr_num, "Can't field-deref a non-structure type");
-        };
+            self.expr_num += Self::count_expressions(&if_statement.exprs);
+            self.emit_else_branch(if_statement)?;
+        }
 
-        let field = structure.fields.get(field_name).context(
-            self.expr_num,
-            &format!("Field \"{}\" doesn't exist on type", field_name),
-        )?;
+        Ok(())
+    }
 
-        if field.offset % 8 != 0 {
-            semantics_bail!(self.expr_num, "Bit-field accesses not supported");
+    /// Emits the else branch of an if statement. An `else if` is emitted as an
+    /// if statement of its own, so the chain continues from there.
+    ///
+    /// # Arguments
+    ///
+    /// * `if_statement` - The if statement information.
+    fn emit_else_branch(&mut self, if_statement: &IfStatement) -> InternalResult<()> {
+        match &if_statement.else_if {
+            Some(else_if) => {
+                self.expr_num += 1;
+                self.emit_if_statement(else_if)
+            }
+            None => self.emit_scoped_body(&if_statement.else_exprs),
         }
-
-        let field_type = self
-            .types
-            .get_type_by_id(field.type_id)
-            .context(self.expr_num, "Internal error; type id invalid")?;
-        Ok((field.offset / 8, field_type.clone()))
     }
 
-    /// Returns the offset and type given an array and index.
+    /// Emits instructions that evaluate the operands of a condition and jump
+    /// `offset` instructions ahead if it holds.
     ///
     /// # Arguments
     ///
-    /// * `array` - The array to access.
-    /// * `index` - The index into the array.
-    fn get_array_index(&mut self, array: &Type, index: &str) -> InternalResult<(u32, Type)> {
-        let array = if let BaseType::Array(array) = &array.base_type {
-            array
-        } else {
-            semantics_bail!(self.expr_num, "Can't array-deref a non-array type");
+    /// * `cond` - The condition.
+    /// * `left_reg` - The register that receives the left operand.
+    /// * `right_reg` - The register that receives the right operand, if needed.
+    /// * `offset` - The jump offset, relative to the next instruction.
+    fn emit_condition_jump(
+        &mut self,
+        cond: &Condition,
+        left_reg: Register,
+        right_reg: Register,
+        offset: i16,
+    ) -> InternalResult<()> {
+        if self.is_aggregate_operand(&cond.left)? || self.is_aggregate_operand(&cond.right)? {
+            semantics_bail!(self.expr_num, "Cannot compare values of struct/array type");
+        }
+
+        let left_const = self.get_constant_value(&cond.left)?;
+        let right_const = self.get_constant_value(&cond.right)?;
+        let constant_type: Type = BaseType::Integer(Integer {
+            used_bits: 64,
+            bits: 64,
+            is_signed: false,
+        })
+        .into();
+
+        /*
+         * a constant operand takes its representation from the other operand, so the
+         * other operand is evaluated first. when the left operand is the constant,
+         * this means the right operand is evaluated before it.
+         */
+        let (left_type, right_type) = match (left_const, right_const) {
+            (Some(_), None) => {
+                let right_type =
+                    self.emit_set_register_from_rvalue(right_reg, &cond.right, None)?;
+                (constant_type, right_type)
+            }
+            (_, Some(_)) => {
+                let left_type = self.emit_set_register_from_rvalue(left_reg, &cond.left, None)?;
+                (left_type, constant_type)
+            }
+            (None, None) => {
+                let left_type = self.emit_set_register_from_rvalue(left_reg, &cond.left, None)?;
+                let right_type =
+                    self.emit_set_register_from_rvalue(right_reg, &cond.right, None)?;
+                (left_type, right_type)
+            }
+        };
+        let is_signed = Self::is_signed(&left_type) || Self::is_signed(&right_type);
+
+        /*
+         * narrow signed operands are zero-extended when loaded, so they're
+         * sign-extended before comparing them as 64-bit values. testing for
+         * (in)equality with a constant that's non-negative in the operand's type
+         * gives the same result either way, so that case is left alone.
+         */
+        let is_equality = matches!(cond.op, Comparator::Equals(_) | Comparator::NotEquals(_));
+        let fits_unextended = |value: Option<i64>, ty: &Type| {
+            let sign_bit = (ty.get_size() * 8).saturating_sub(1);
+            is_equality && value.is_some_and(|v| v >= 0 && (v as u64) < 1 << sign_bit)
         };
+        if left_const.is_none() && !fits_unextended(right_const, &left_type) {
+            self.emit_sign_extend(left_reg, &left_type);
+        }
+        if right_const.is_none() && !fits_unextended(left_const, &right_type) {
+            self.emit_sign_extend(right_reg, &right_type);
+        }
+
+        if let Some(value) = left_const {
+            let imm = Self::get_condition_immediate(value, &right_type, is_signed);
+            if imm == value {
+                self.emit_set_register_from_rvalue(left_reg, &cond.left, None)?;
+            } else {
+                self.emit_set_register_from_constant(left_reg, imm);
+            }
+        }
+
+        /*
+         * when the right operand is a constant that fits the instruction's 32-bit
+         * immediate, compare against it directly instead of materializing it in a
+         * register.
+         */
+        let mut right_imm = None;
+        if let Some(value) = right_const {
+            let imm = Self::get_condition_immediate(value, &left_type, is_signed);
+            match i32::try_from(imm) {
+                Ok(imm) => right_imm = Some(imm.into()),
+                Err(_) if imm == value => {
+                    self.emit_set_register_from_rvalue(right_reg, &cond.right, None)?;
+                }
+                Err(_) => self.emit_set_register_from_constant(right_reg, imm),
+            }
+        }
 
-        let index = self.parse_immediate::<u32>(index)?;
-        if index > array.num_elements {
+        /*
+         * floating-point values are compared as their raw bits, which is only
+         * meaningful for (in)equality.
+         */
+        if (Self::is_float(&left_type) || Self::is_float(&right_type))
+            && !matches!(cond.op, Comparator::Equals(_) | Comparator::NotEquals(_))
+        {
             semantics_bail!(
                 self.expr_num,
-                "Out-of-bounds array access {}/{}",
-                index,
-                array.num_elements
+                "Floating-point values can only be compared for equality"
             );
         }
 
-        let element_type = self
-            .types
-            .get_type_by_id(array.element_type_id)
-            .context(self.expr_num, "Internal error; type id invalid")?;
+        self.emit_optimize();
 
-        let offset = element_type.get_size() * index;
-        Ok((offset, element_type.clone()))
+        let operation = match (&cond.op, is_signed) {
+            (Comparator::Equals(_), _) => JumpOperation::IfEqual,
+            (Comparator::NotEquals(_), _) => JumpOperation::IfNotEqual,
+            (Comparator::GreaterThan(_), false) => JumpOperation::IfGreater,
+            (Comparator::GreaterThan(_), true) => JumpOperation::IfSignedGreater,
+            (Comparator::GreaterOrEqual(_), false) => JumpOperation::IfGreaterOrEqual,
+            (Comparator::GreaterOrEqual(_), true) => JumpOperation::IfSignedGreaterOrEqual,
+            (Comparator::LessThan(_), false) => JumpOperation::IfLessThan,
+            (Comparator::LessThan(_), true) => JumpOperation::IfSignedLessThan,
+            (Comparator::LessOrEqual(_), false) => JumpOperation::IfLessThanOrEqual,
+            (Comparator::LessOrEqual(_), true) => JumpOperation::IfSignedLessThanOrEqual,
+        };
+
+        match right_imm {
+            Some(imm) => {
+                self.instructions
+                    .push(Instruction::jmp_if(left_reg, operation, imm, offset));
+            }
+            None => {
+                self.instructions
+                    .push(Instruction::jmp_ifx(left_reg, operation, right_reg, offset));
+            }
+        }
+
+        Ok(())
     }
 
-    /// Given a type and deref slice, returns the offset of the deref and its type.
+    /// Emits short-circuiting jumps for a boolean expression. When the expression
+    /// holds, execution falls through to the instruction emitted next. Otherwise
+    /// it takes one of the returned jumps, which the caller must patch.
+    ///
+    /// For `&&`, each false clause jumps out. For `||`, each clause but the last
+    /// jumps straight to the fall-through when it holds.
     ///
     /// # Arguments
     ///
-    /// * `ty` - The type being dereferenced.
-    /// * `derefs` - The list of derefs to apply to the type.
-    fn get_deref_offset(
-        &mut self,
-        ty: &Type,
-        derefs: &[DeReference],
-    ) -> InternalResult<(i16, Type)> {
-        let mut offset = 0;
-        let mut cur_type = ty.clone();
-        for deref in derefs.iter() {
-            if cur_type.is_pointer() {
-                semantics_bail!(
-                    self.expr_num,
-                    "Can't deref an offset through an indirection"
-                );
+    /// * `expr` - The boolean expression.
+    fn emit_bool_expr_jumps(&mut self, expr: &BoolExpr) -> InternalResult<Vec<usize>> {
+        let mut true_jumps = vec![];
+        let mut false_jumps = vec![];
+        for (i, term) in expr.terms.iter().enumerate() {
+            let is_last_term = i + 1 == expr.terms.len();
+            let mut next_jumps = vec![];
+            for (j, cond) in term.conds.iter().enumerate() {
+                let is_last_cond = j + 1 == term.conds.len();
+                let cond = self.get_term_condition(cond)?;
+                if is_last_cond && !is_last_term {
+                    self.emit_condition_jump(&cond, Register::R8, Register::R9, 0)?;
+                    true_jumps.push(self.instructions.current_index() - 1);
+                    continue;
+                }
+
+                self.emit_condition_jump(&cond, Register::R8, Register::R9, 1)?;
+                if is_last_term {
+                    false_jumps.push(self.instructions.current_index());
+                } else {
+                    next_jumps.push(self.instructions.current_index());
+                }
+                self.instructions.push(Instruction::jmp_abs(0));
             }
 
-            let (off, ty) = match deref {
-                DeReference::FieldAccess(ma) => self.get_field_access(&cur_type, &ma.name)?,
-                DeReference::ArrayIndex(ai) => self.get_array_index(&cur_type, &ai.element)?,
-            };
+            // the patched jumps land here, so the optimizer must leave them be.
+            self.optimized_len = self.instructions.current_index();
+            for jump_index in next_jumps {
+                self.patch_jump(jump_index)?;
+            }
+        }
 
-            offset += off;
-            cur_type = ty;
+        for jump_index in true_jumps {
+            self.patch_jump(jump_index)?;
         }
 
-        let offset: i16 = offset
-            .try_into()
-            .context(self.expr_num, "Type is too large to deref")?;
-        Ok((offset, cur_type))
+        Ok(false_jumps)
     }
 
-    /// Emit instructions for an assignment expression.
+    /// Emits instructions that perform an if statement.
     ///
     /// # Arguments
     ///
-    /// * `assign` - Information about the assignment.
-    fn emit_assign(&mut self, assign: &Assignment) -> InternalResult<()> {
-        let mut new_variable = true;
-        let (cast_type, use_offset) =
-            if let Ok(info) = &self.get_variable_by_name(&assign.left.name) {
-                if assign.type_name.is_some() {
-                    semantics_bail!(
-                        self.expr_num,
-                        "Can't re-type \"{}\" after first assignment",
-                        assign.left.name
-                    );
-                } else if let VariableLocation::Stack(off) = info.location {
-                    let (rel_off, offset_type) =
-                        self.get_deref_offset(&info.var_type, &assign.left.derefs)?;
-                    new_variable = false;
-                    (offset_type, Some(off + rel_off))
-                } else {
-                    semantics_bail!(
-                        self.expr_num,
-                        "Variable \"{}\" cannot be re-assigned",
-                        assign.left.name
-                    );
-                }
-            } else if let Some(type_name) = &assign.type_name {
-                let assign_type = self.type_from_decl(type_name)?;
-                (assign_type, None)
+    /// * `if_statement` - The if statement information.
+    fn emit_if_statement(&mut self, if_statement: &IfStatement) -> InternalResult<()> {
+        if let Some(taken) = self.evaluate_constant_bool_expr(&if_statement.cond)? {
+            return self.emit_pruned_if_statement(if_statement, taken);
+        }
+
+        let else_jumps = self.emit_bool_expr_jumps(&if_statement.cond)?;
+
+        self.branch_depth += 1;
+        self.max_branch_depth = self.max_branch_depth.max(self.branch_depth);
+
+        /*
+         * a variable is only assigned after the if statement if it's assigned in
+         * every branch that reaches the end of it.
+         */
+        let initialized = self.get_initialized_variables();
+        self.emit_scoped_body(&if_statement.exprs)?;
+        let body_initialized = if Self::block_diverges(&if_statement.exprs) {
+            None
+        } else {
+            Some(self.get_initialized_variables())
+        };
+        self.set_initialized_variables(&initialized);
+
+        let has_else = if_statement.else_if.is_some() || !if_statement.else_exprs.is_empty();
+        let end_index = self.instructions.current_index();
+        if has_else {
+            self.instructions.push(Instruction::jmp_abs(0));
+        }
+
+        for jump_index in else_jumps {
+            self.patch_jump(jump_index)?;
+        }
+
+        /*
+         * the else branch is a sibling of the body, so an else if chain doesn't
+         * nest any deeper than a single if statement.
+         */
+        self.branch_depth -= 1;
+
+        if has_else {
+            if if_statement.else_if.is_some() {
+                self.emit_else_branch(if_statement)?;
             } else {
-                (Default::default(), None)
+                self.branch_depth += 1;
+                self.emit_else_branch(if_statement)?;
+                self.branch_depth -= 1;
+            }
+
+            // the end of the else branch is also the end of the whole chain.
+            self.patch_jump(end_index)?;
+
+            let else_initialized = if Self::block_diverges(&if_statement.else_exprs) {
+                None
+            } else {
+                Some(self.get_initialized_variables())
             };
+            let merged = match (body_initialized, else_initialized) {
+                (Some(body), Some(other)) => body.intersection(&other).cloned().collect(),
+                (Some(names), None) | (None, Some(names)) => names,
+                (None, None) => initialized,
+            };
+            self.set_initialized_variables(&merged);
+        }
 
-        let (offset, new_type) = self.emit_push_rvalue(&assign.right, &cast_type, use_offset)?;
+        Ok(())
+    }
 
-        if new_variable {
-            self.variables.insert(
-                assign.left.name.clone(),
-                VariableInfo {
-                    var_type: new_type,
-                    location: VariableLocation::Stack(offset),
-                },
-            );
+    /// Emits instructions for a while loop. The condition is tested before each
+    /// iteration and the end of the body jumps back to it. If a loop limit is
+    /// set, a hidden counter also ends the loop after that many iterations.
+    ///
+    /// # Arguments
+    ///
+    /// * `while_statement` - The while statement information.
+    fn emit_while_statement(&mut self, while_statement: &WhileStatement) -> InternalResult<()> {
+        if let Some(false) = self.evaluate_constant_bool_expr(&while_statement.cond)? {
+            self.expr_num += Self::count_expressions(&while_statement.exprs);
+            return Ok(());
+        }
+
+        let counter = match self.loop_limit {
+            Some(_) => {
+                let offset = self.push_stack(8)?;
+                self.instructions
+                    .push(Instruction::store64(Register::R10, offset, 0));
+                Some(offset)
+            }
+            None => None,
+        };
+
+        /*
+         * the start of the loop is a jump target, so nothing before it may be
+         * merged into it by the optimizer.
+         */
+        self.emit_optimize();
+        let start_index = self.instructions.current_index();
+        self.loops.push(LoopInfo {
+            start_index,
+            break_jumps: vec![],
+        });
+
+        let end_jumps = self.emit_bool_expr_jumps(&while_statement.cond)?;
+
+        let limit_index = match (counter, self.loop_limit) {
+            (Some(offset), Some(limit)) => {
+                self.instructions
+                    .push(Instruction::loadx64(Register::R8, Register::R10, offset));
+                let limit_index = self.instructions.current_index();
+                self.instructions.push(Instruction::jmp_if(
+                    Register::R8,
+                    JumpOperation::IfGreaterOrEqual,
+                    limit.into(),
+                    0,
+                ));
+                self.instructions.push(Instruction::add64(Register::R8, 1));
+                self.instructions
+                    .push(Instruction::storex64(Register::R10, offset, Register::R8));
+                Some(limit_index)
+            }
+            _ => None,
+        };
+
+        self.branch_depth += 1;
+        self.max_branch_depth = self.max_branch_depth.max(self.branch_depth);
+
+        // the body might not run at all, so its assignments don't count after it.
+        let initialized = self.get_initialized_variables();
+        self.emit_scoped_body(&while_statement.exprs)?;
+        self.set_initialized_variables(&initialized);
+        self.emit_jump_back(start_index)?;
+
+        let break_jumps = match self.loops.pop() {
+            Some(info) => info.break_jumps,
+            None => vec![],
+        };
+        for jump_index in end_jumps.into_iter().chain(limit_index).chain(break_jumps) {
+            self.patch_jump(jump_index)?;
         }
 
+        self.branch_depth -= 1;
+
         Ok(())
     }
 
-    /// From an address held in a register and a structure type, emits instructions that set
-    /// the register value to the address of the field being accessed.
+    /// Emits a jump back to an earlier instruction, e.g. the start of a loop.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register holding the address of the structure.
-    /// * `structure` - The structure's type.
-    /// * `field_access` - Information about the field being accessed.
-    fn emit_field_access(
-        &mut self,
-        reg: Register,
-        structure: &Type,
-        field_access: &FieldAccess,
-    ) -> InternalResult<Type> {
-        let (offset, field_type) = self.get_field_access(structure, &field_access.name)?;
-        if offset > 0 {
-            self.instructions
-                .push(Instruction::add64(reg, offset as i32));
+    /// * `target_index` - The index of the instruction to jump to.
+    fn emit_jump_back(&mut self, target_index: usize) -> InternalResult<()> {
+        let jump_index = self.instructions.current_index();
+        let slots: usize = self.instructions.instructions()[target_index..jump_index]
+            .iter()
+            .map(|ins| if ins.is_wide() { 2 } else { 1 })
+            .sum();
+        let offset = self.check_jump_distance(jump_index, slots + 1)?;
+        self.instructions.push(Instruction::jmp_abs(-offset));
+        self.optimized_len = self.instructions.current_index();
+
+        Ok(())
+    }
+
+    /// Emits a `break`, which jumps to the end of the innermost loop. The jump is
+    /// patched once the end of the loop is known.
+    fn emit_break(&mut self) -> InternalResult<()> {
+        if self.loops.is_empty() {
+            semantics_bail!(self.expr_num, "\"break\" can only be used in a loop");
         }
-        Ok(field_type)
+
+        // the jump is patched later, so the optimizer must leave it be.
+        self.emit_optimize();
+        let jump_index = self.instructions.current_index();
+        self.instructions.push(Instruction::jmp_abs(0));
+        self.optimized_len = self.instructions.current_index();
+        if let Some(info) = self.loops.last_mut() {
+            info.break_jumps.push(jump_index);
+        }
+
+        Ok(())
     }
 
-    /// From an address held in a register and an array type, emits instructions that set
-    /// the register value to the address of the element being accessed.
+    /// Emits a `continue`, which jumps back to the condition of the innermost
+    /// loop.
+    fn emit_continue(&mut self) -> InternalResult<()> {
+        let start_index = match self.loops.last() {
+            Some(info) => info.start_index,
+            None => {
+                semantics_bail!(self.expr_num, "\"continue\" can only be used in a loop");
+            }
+        };
+
+        self.emit_optimize();
+        self.emit_jump_back(start_index)
+    }
+
+    /// Emits instructions that perform a return. Structures and unions don't fit
+    /// in the return register, so returning one held on the stack, e.g. `return
+    /// vec`, returns its address instead, as if `return &vec` had been written.
+    /// The returned type is then a pointer to the structure, and the address is
+    /// only valid until the program exits; the kernel verifier only accepts a
+    /// pointer in R0 for program types that allow it.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register holding the address of the array.
-    /// * `array` - The array's type.
-    /// * `index` - Information about the index being accessed.
-    fn emit_index_array(
-        &mut self,
-        reg: Register,
-        array: &Type,
-        index: &ArrayIndex,
-    ) -> InternalResult<Type> {
-        let (offset, element_type) = self.get_array_index(array, &index.element)?;
-        if offset > 0 {
-            self.instructions
-                .push(Instruction::add64(reg, offset as i32));
+    /// * `ret` - Information about the return.
+    fn emit_return(&mut self, ret: &Return) -> InternalResult<()> {
+        let start = self.instructions.current_index();
+        match &ret.value {
+            None => {
+                self.emit_set_register_from_constant(RETURN_REGISTER, self.default_return);
+            }
+            Some(value) => {
+                let value_type = match self.get_returned_structure(value)? {
+                    Some(lval) => {
+                        self.check_variable_initialized(&lval.name)?;
+                        let mut var_type =
+                            self.emit_set_register_to_lvalue_addr(RETURN_REGISTER, lval)?;
+                        var_type.num_refs += 1;
+                        var_type
+                    }
+                    None => self.emit_set_register_from_rvalue(RETURN_REGISTER, value, None)?,
+                };
+                if let Some(return_type) = &self.return_type {
+                    if !Self::is_return_compatible(return_type, &value_type) {
+                        semantics_bail!(
+                            self.expr_num,
+                            "Returned value doesn't match the declared return type"
+                        );
+                    }
+                }
+            }
         }
-        Ok(element_type)
+
+        self.check_return_register(start, ret.value.as_ref())?;
+        self.instructions.push(Instruction::exit());
+
+        Ok(())
     }
 
-    /// Given a register holding a `var_type` address, and a list of derefs, emits instructions
-    /// that apply these derefs to the register. After the instructions are executed, `reg` will
-    /// hold the address to the deref.
+    /// Returns the lvalue of a returned value if it's a structure or union held on
+    /// the stack, e.g. `vec` or `outer.inner`, which is returned by address.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register holding the address to be dereferenced.
-    /// * `var_type` - The type of variable being pointed to by `reg`.
-    /// * `derefs` - A list of derefs to apply.
-    fn emit_apply_derefs_to_reg(
+    /// * `value` - The returned value.
+    fn get_returned_structure<'b>(
         &mut self,
-        reg: Register,
-        var_type: &Type,
-        derefs: &[DeReference],
-    ) -> InternalResult<Type> {
-        if derefs.is_empty() {
-            return Ok(var_type.clone());
-        }
-
-        // If the current var_type is a pointer then this deref is through a pointer.
-        // Before emiting instructions to access the structure or field, the address
-        // needs to be loaded into the register.
-        if var_type.is_pointer() {
-            self.instructions.push(Instruction::loadx64(reg, reg, 0));
-        }
+        value: &'b RValue,
+    ) -> InternalResult<Option<&'b LValue>> {
+        let lval = match value {
+            RValue {
+                left: RValueInner::LValue(lval),
+                op: None,
+                ..
+            } if lval.prefix.is_none() => lval,
+            _ => return Ok(None),
+        };
 
-        let next_type = match &derefs[0] {
-            DeReference::FieldAccess(ma) => self.emit_field_access(reg, var_type, ma)?,
-            DeReference::ArrayIndex(ai) => self.emit_index_array(reg, var_type, ai)?,
+        let info = match self.variables.get(&lval.name) {
+            Some(info) if matches!(info.location, VariableLocation::Stack(_)) => info.clone(),
+            _ => return Ok(None),
         };
 
-        self.emit_apply_derefs_to_reg(reg, &next_type, &derefs[1..])
+        let mut cur_type = &info.var_type;
+        for deref in &lval.derefs {
+            if cur_type.is_pointer() {
+                return Ok(None);
+            }
+
+            cur_type = match deref {
+                DeReference::FieldAccess(ma) => self.get_field_access(cur_type, &ma.name)?.1,
+                DeReference::ArrayIndex(_) => self.get_array_element(cur_type)?.1,
+            };
+        }
+
+        let is_structure = matches!(cur_type.base_type, BaseType::Struct(_) | BaseType::Union(_));
+        Ok(Some(lval).filter(|_| is_structure && !cur_type.is_pointer()))
     }
 
-    /// Given a register and lvalue information, emits instructions that set the
-    /// register to the address of the lvalue being accessed. On success, the final
-    /// type of the lval access is returned and `reg` will contain the address pointing
-    /// to this type.
+    /// Checks that the return value is still in `RETURN_REGISTER` at the end of
+    /// the instructions emitted for a return. The register must be written, and
+    /// if the last write is a helper call (which clobbers R0-R5), the returned
+    /// value must be that call's result.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register to be set.
-    /// * `lval` - The lvalue information.
-    fn emit_set_register_to_lvalue_addr(
+    /// * `start` - The index of the first instruction emitted for the return.
+    /// * `value` - The returned value, if any.
+    fn check_return_register(
         &mut self,
-        reg: Register,
-        lval: &LValue,
-    ) -> InternalResult<Type> {
-        let info = self.get_variable_by_name(&lval.name)?;
+        start: usize,
+        value: Option<&RValue>,
+    ) -> InternalResult<()> {
+        let instructions = &self.instructions.instructions()[start..];
+        let last_write = instructions
+            .iter()
+            .rev()
+            .find(|ins| match ins.get_opcode() {
+                Opcode::Arithmetic(_) => ins.get_dst_reg() == RETURN_REGISTER,
+                Opcode::Memory(memory) => {
+                    matches!(memory.get_class(), OpcodeClass::Load | OpcodeClass::LoadReg)
+                        && ins.get_dst_reg() == RETURN_REGISTER
+                }
+                Opcode::Jump(jump) => matches!(jump.get_operation(), JumpOperation::Call),
+            });
 
-        match info.location {
-            VariableLocation::SpecialImmediate(_) => {
-                semantics_bail!(
-                    self.expr_num,
-                    "Variable \"{}\" is a capture; captures can't be assigned to",
-                    lval.name
-                );
-            }
-            VariableLocation::Stack(o) => {
-                self.instructions
-                    .push(Instruction::movx64(reg, Register::R10));
-                self.instructions.push(Instruction::add64(reg, o.into()));
-            }
+        let mut value = value;
+        while let Some(RValue {
+            left:
+                RValueInner::Volatile(Volatile { value: inner })
+                | RValueInner::Parenthesized(Parenthesized { value: inner }),
+            op: None,
+            ..
+        }) = value
+        {
+            value = Some(inner.as_ref());
         }
 
-        self.emit_apply_derefs_to_reg(reg, &info.var_type, &lval.derefs)
+        let clobbered = match last_write.map(|ins| ins.get_opcode()) {
+            None => true,
+            Some(Opcode::Jump(_)) => !matches!(
+                value,
+                Some(RValue {
+                    left: RValueInner::FunctionCall(_),
+                    op: None,
+                    ..
+                })
+            ),
+            Some(_) => false,
+        };
+        if clobbered {
+            semantics_bail!(
+                self.expr_num,
+                "Internal error; the return value was clobbered before exit"
+            );
+        }
+
+        Ok(())
     }
 
-    /// Given a register and lvalue information, emits instructions that set the
-    /// register to the value of this lvalue access. This is different from
-    /// `emit_set_register_to_lvalue_addr` in that the register receives the final
-    /// dereferenced type, _not_ an address pointing to it.
+    /// Returns whether a value of type `value_type` can be returned from a program
+    /// declared to return `return_type`. Pointers can only be returned as pointers,
+    /// and scalars (integers and enums) are interchangeable since the value is
+    /// passed back in a 64-bit register either way.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register that receives the value.
-    /// * `lval` - The lvalue information.
-    /// * `load_type` - The BPF load type to use when setting the register value.
-    fn emit_set_register_from_lvalue(
-        &mut self,
-        reg: Register,
-        lval: &LValue,
-        load_type: Option<MemoryOpLoadType>,
-    ) -> InternalResult<Type> {
-        let info = self.get_variable_by_name(&lval.name)?;
-        if let VariableLocation::SpecialImmediate(v) = info.location {
-            if !lval.derefs.is_empty() {
-                semantics_bail!(
-                    self.expr_num,
-                    "Can't dereference \"{}\"; it's a capture",
-                    lval.name
-                );
-            }
-
-            let load_type = load_type.unwrap_or(MemoryOpLoadType::Void);
-            self.instructions
-                .push(Instruction::loadtype(reg, v.into(), load_type));
-            return Ok(info.var_type);
+    /// * `return_type` - The declared return type.
+    /// * `value_type` - The type of the value being returned.
+    fn is_return_compatible(return_type: &Type, value_type: &Type) -> bool {
+        if return_type.is_pointer() || value_type.is_pointer() {
+            return return_type.is_pointer() && value_type.is_pointer();
         }
 
-        let mut var_type = self.emit_set_register_to_lvalue_addr(reg, lval)?;
+        let is_scalar =
+            |ty: &Type| matches!(ty.base_type, BaseType::Integer(_) | BaseType::Enum(_));
+        is_scalar(return_type) && is_scalar(value_type)
+    }
 
+    /// Emits instructions that setup the function. Pushes arguments to the
+    /// stack, sets their types, etc.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Information about the function's input.
+    fn emit_prologue(&mut self, input: &InputLine) -> InternalResult<()> {
         /*
-         * the register is already holding a pointer to the lvalue so, if a reference
-         * was specified, nothing else needs to be done.
+         * BPF limits the number of function arguments to 5 (R1 to R5).
          */
-        if matches!(lval.prefix, Some(Prefix::ReferencePrefix(_))) {
-            var_type.num_refs += 1;
-            return Ok(var_type);
+        if input.args.len() > 5 {
+            semantics_bail!(self.expr_num, "Function exceeds 5 arguments");
         }
 
-        /*
-         * register is pointing to a value of type `var_type`, load it into the register,
-         * if it fits.
-         */
-        match var_type.get_size() {
-            1 => self.instructions.push(Instruction::loadx8(reg, reg, 0)),
-            2 => self.instructions.push(Instruction::loadx16(reg, reg, 0)),
-            4 => self.instructions.push(Instruction::loadx32(reg, reg, 0)),
-            8 => self.instructions.push(Instruction::loadx64(reg, reg, 0)),
-            size => {
-                semantics_bail!(
-                    self.expr_num,
-                    "The variable \"{}\" is {} bytes and is too large to be passed in a register",
-                    lval.name,
-                    size
-                );
-            }
+        if let Some(return_type) = &input.return_type {
+            self.return_type = Some(self.type_from_decl(return_type)?);
         }
 
         /*
-         * the register is now holding `var_type`. if another dereference was requested
-         * then make sure the type being held by the register is a pointer.
+         * Push all input arguments to the stack and create variables entries for them.
          */
-        if matches!(lval.prefix, Some(Prefix::DeReferencePrefix(_))) {
-            if !var_type.is_pointer() {
-                semantics_bail!(self.expr_num, "Cannot dereference a non-pointer type");
+        for (i, arg) in input.args.iter().enumerate() {
+            if input.args[..i].iter().any(|prev| prev.name == arg.name) {
+                semantics_bail!(self.expr_num, "Duplicate argument name \"{}\"", arg.name);
             }
 
-            var_type.num_refs -= 1;
-            self.instructions.push(Instruction::loadx64(reg, reg, 0));
+            let register = Register::from_num((i + 1) as u8).expect("too many args");
+            let arg_type = self.type_from_decl(&arg.type_name)?;
+            let offset = self.emit_push_register(register, None)?;
+            self.variables.insert(
+                arg.name.clone(),
+                VariableInfo {
+                    var_type: arg_type,
+                    location: VariableLocation::Stack(offset),
+                    initialized: true,
+                },
+            );
         }
 
-        Ok(var_type)
+        Ok(())
     }
 
-    /// Given a register and rvalue information, emits instructions that set the
-    /// register to the value of this lvalue access. This can either be an lvalue,
-    /// in which case `emit_set_register_from_lvalue` is called, an immediate, or
-    /// a function call.
+    /// Returns the offset for the jump at index `jump_index` that lands on the next
+    /// instruction to be emitted. The kernel counts jump offsets in 8-byte slots,
+    /// so wide instructions (64-bit immediate loads) count twice.
     ///
     /// # Arguments
     ///
-    /// * `reg` - The register that receives the value.
-    /// * `lval` - The lvalue information.
-    /// * `load_type` - The BPF load type to use when setting the register value.
-    fn emit_set_register_from_rvalue(
-        &mut self,
-        reg: Register,
-        rval: &RValue,
-        load_type: Option<MemoryOpLoadType>,
-    ) -> InternalResult<Type> {
-        if let (Some(op), Some(right)) = (&rval.op, &rval.right) {
-            let var_type = self.emit_rvalue_arithmetic(&rval.left, op, right)?;
-            self.instructions
-                .push(Instruction::movx64(reg, Register::R6));
-            return Ok(var_type);
+    /// * `jump_index` - The index of the jump instruction.
+    fn get_jump_offset(&self, jump_index: usize) -> InternalResult<i16> {
+        let slots: usize = self.instructions.instructions()[jump_index + 1..]
+            .iter()
+            .map(|ins| if ins.is_wide() { 2 } else { 1 })
+            .sum();
+        self.check_jump_distance(jump_index, slots)
+    }
+
+    /// Fails, with the line of the jump, if a jump is farther than the signed
+    /// 16-bit offset of a BPF jump can reach, e.g. over a very large if body.
+    ///
+    /// # Arguments
+    ///
+    /// * `jump_index` - The index of the jump instruction.
+    /// * `slots` - The number of slots to jump over.
+    fn check_jump_distance(&self, jump_index: usize, slots: usize) -> InternalResult<i16> {
+        match i16::try_from(slots) {
+            Ok(offset) => Ok(offset),
+            Err(_) => {
+                semantics_bail!(
+                    self.get_instruction_line(jump_index),
+                    "Jump over {} instructions exceeds the {} a BPF jump can reach",
+                    slots,
+                    i16::MAX
+                );
+            }
         }
+    }
 
-        match &rval.left {
-            RValueInner::Immediate(imm_str) => {
-                if let Some(load_type) = load_type {
-                    let imm = self.parse_immediate(imm_str)?;
-                    self.instructions
-                        .push(Instruction::loadtype(reg, imm, load_type));
-                } else {
-                    let imm = self.parse_immediate(imm_str)?;
-                    self.instructions.push(Instruction::mov64(reg, imm));
-                }
+    /// Returns the line an instruction was emitted for, or the current line if
+    /// it hasn't been attributed yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instruction.
+    fn get_instruction_line(&self, index: usize) -> u32 {
+        self.instruction_lines
+            .get(index)
+            .copied()
+            .unwrap_or(self.expr_num)
+    }
 
-                let var_type: Type = BaseType::Integer(Integer {
-                    used_bits: 64,
-                    bits: 64,
-                    is_signed: false,
-                })
-                .into();
+    /// Checks that every jump in the finished program lands on an instruction.
+    /// Jumps are patched as they're emitted and re-patched when dead code is
+    /// removed, so this catches any pass that left a jump pointing outside the
+    /// program or into the middle of a wide instruction.
+    fn verify_jumps(&self) -> InternalResult<()> {
+        let instructions = self.instructions.instructions();
+
+        // jump offsets count wide instructions as two slots.
+        let mut slots = Vec::with_capacity(instructions.len());
+        let mut slot: i64 = 0;
+        for ins in instructions {
+            slots.push(slot);
+            slot += if ins.is_wide() { 2 } else { 1 };
+        }
 
-                Ok(var_type)
+        for (i, ins) in instructions.iter().enumerate() {
+            if retarget_jump(ins, 0).is_none() {
+                continue;
             }
-            RValueInner::LValue(lval) => self.emit_set_register_from_lvalue(reg, lval, load_type),
-            RValueInner::FunctionCall(call) => {
-                let ret_type = self.emit_call(call)?;
-                if !matches!(reg, Register::R0) {
-                    self.instructions
-                        .push(Instruction::movx64(reg, Register::R0));
-                }
-                Ok(ret_type)
+
+            let target = slots[i] + 1 + i64::from(ins.get_offset());
+            if slots.binary_search(&target).is_err() {
+                semantics_bail!(
+                    self.get_instruction_line(i),
+                    "Jump at instruction {} lands outside the program",
+                    i
+                );
             }
         }
+
+        Ok(())
     }
 
-    /// Emits instructions that perform a call.
+    /// Patches the jump at index `jump_index` to land on the next instruction to be
+    /// emitted, keeping its condition and operands.
     ///
     /// # Arguments
     ///
-    /// * `call` - Information about the call.
-    fn emit_call(&mut self, call: &FunctionCall) -> InternalResult<Type> {
-        let helper = match Helpers::from_string(&call.name) {
-            Some(helper) => helper,
+    /// * `jump_index` - The index of the jump instruction.
+    fn patch_jump(&mut self, jump_index: usize) -> InternalResult<()> {
+        let offset = self.get_jump_offset(jump_index)?;
+        let jump = self.instructions.instructions()[jump_index];
+        let patched = match retarget_jump(&jump, offset) {
+            Some(patched) => patched,
             None => {
-                semantics_bail!(self.expr_num, "Unknown function \"{}\"", call.name);
+                semantics_bail!(self.expr_num, "Expected a jump to patch");
             }
         };
+        self.instructions.patch(jump_index, patched);
 
-        let types = helper.get_arg_types();
+        Ok(())
+    }
 
-        for (i, arg) in call.args.iter().enumerate() {
-            match i {
-                0 => self.emit_set_register_from_rvalue(Register::R1, arg, Some(types[i]))?,
-                1 => self.emit_set_register_from_rvalue(Register::R2, arg, Some(types[i]))?,
-                2 => self.emit_set_register_from_rvalue(Register::R3, arg, Some(types[i]))?,
-                3 => self.emit_set_register_from_rvalue(Register::R4, arg, Some(types[i]))?,
-                4 => self.emit_set_register_from_rvalue(Register::R5, arg, Some(types[i]))?,
-                _ => {
-                    semantics_bail!(self.expr_num, "Function call exceeds 5 arguments");
-                }
-            };
+    /// Runs the optimizer over everything emitted since the last time it ran.
+    /// Instructions that were already optimized are left untouched: jumps
+    /// emitted after them hold offsets that must stay valid, and re-running the
+    /// peephole passes could shrink the code underneath those offsets.
+    fn emit_optimize(&mut self) {
+        self.record_instruction_lines();
+
+        let instructions = self.instructions.instructions();
+        let end = instructions.len();
+        let mut optimized = instructions[..self.optimized_len].to_vec();
+        let mut lines = self.instruction_lines[..self.optimized_len].to_vec();
+
+        // volatile ranges are copied as they are, the rest is optimized.
+        let optimized_len = self.optimized_len;
+        let ranges = self
+            .volatile_ranges
+            .drain(..)
+            .filter(|range| range.start >= optimized_len);
+        let mut start = optimized_len;
+        for range in ranges.chain(std::iter::once(end..end)) {
+            let (tail, sources) = optimize(&instructions[start..range.start]);
+            optimized.extend(tail);
+
+            // each optimized instruction keeps the line of the first instruction
+            // it was made from.
+            lines.extend(sources.iter().map(|i| self.instruction_lines[start + i]));
+
+            optimized.extend_from_slice(&instructions[range.clone()]);
+            lines.extend_from_slice(&self.instruction_lines[range.clone()]);
+            start = range.end;
         }
-        self.instructions.push(Instruction::call(helper as u32));
+        self.instruction_lines = lines;
 
-        let var_type: Type = BaseType::Integer(Integer {
-            used_bits: 64,
-            bits: 64,
-            is_signed: false,
-        })
-        .into();
+        self.optimized_len = optimized.len();
+        self.instructions.replace(optimized);
+    }
 
-        Ok(var_type)
+    /// Removes the instructions that can't be reached once the whole program has
+    /// been emitted, e.g. the implicit return after an if statement whose
+    /// branches both return. The kernel rejects programs with unreachable code.
+    fn emit_remove_dead_code(&mut self) {
+        self.record_instruction_lines();
+
+        let (instructions, sources) = remove_dead_code(self.instructions.instructions());
+        self.instruction_lines = sources.iter().map(|i| self.instruction_lines[*i]).collect();
+
+        self.optimized_len = instructions.len();
+        self.instructions.replace(instructions);
     }
 
-    /// Emits instructions that perform an if statement.
+    /// Keeps the optimizer from changing the instructions emitted since `start`,
+    /// so that a `volatile(...)` value isn't folded into a constant.
     ///
     /// # Arguments
     ///
-    /// * `if_statement` - The if statement information.
-    fn emit_if_statement(&mut self, if_statement: &IfStatement) -> InternalResult<()> {
-        self.emit_set_register_from_rvalue(Register::R8, &if_statement.cond.left, None)?;
-        self.emit_set_register_from_rvalue(Register::R9, &if_statement.cond.right, None)?;
-
-        self.instructions = optimize(&self.instructions);
+    /// * `start` - The index of the first instruction to keep.
+    fn keep_unoptimized(&mut self, start: usize) {
+        // ranges of nested volatile values are covered by this one.
+        self.volatile_ranges.retain(|range| range.start < start);
+        self.volatile_ranges
+            .push(start..self.instructions.current_index());
+    }
 
-        let operation = match if_statement.cond.op {
-            Comparator::Equals(_) => JumpOperation::IfEqual,
-            Comparator::NotEquals(_) => JumpOperation::IfNotEqual,
-            Comparator::GreaterThan(_) => JumpOperation::IfGreater,
-            Comparator::GreaterOrEqual(_) => JumpOperation::IfGreaterOrEqual,
-            Comparator::LessThan(_) => JumpOperation::IfLessThan,
-            Comparator::LessOrEqual(_) => JumpOperation::IfLessThanOrEqual,
-        };
+    /// Attributes the instructions emitted since the last call to the current
+    /// line, for the line info.
+    fn record_instruction_lines(&mut self) {
+        let count = self.instructions.current_index();
+        if self.instruction_lines.len() < count {
+            self.instruction_lines.resize(count, self.expr_num);
+        }
+    }
 
-        self.instructions.push(Instruction::jmp_ifx(
-            Register::R8,
-            operation,
-            Register::R9,
-            1,
-        ));
+    /// Returns the names of the variables that are assigned on every path to the
+    /// current expression.
+    fn get_initialized_variables(&self) -> HashSet<String> {
+        self.variables
+            .iter()
+            .filter(|(_, info)| info.initialized)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
 
-        let else_index = self.instructions.len();
-        self.instructions.push(Instruction::jmp_abs(0));
+    /// Marks the given variables as assigned and every other variable as not.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the assigned variables.
+    fn set_initialized_variables(&mut self, names: &HashSet<String>) {
+        for (name, info) in self.variables.iter_mut() {
+            info.initialized = names.contains(name);
+        }
+    }
 
-        self.emit_body(&if_statement.exprs)?;
+    /// Returns whether a block never finishes normally because every path through
+    /// it returns, breaks or continues; its assignments don't reach the code after
+    /// it.
+    ///
+    /// # Arguments
+    ///
+    /// * `exprs` - The expressions in the block.
+    fn block_diverges(exprs: &[Expression]) -> bool {
+        exprs.iter().any(|expr| match expr {
+            Expression::Return(_) | Expression::Break(_) | Expression::Continue(_) => true,
+            Expression::IfStatement(if_statement) => Self::if_diverges(if_statement),
+            _ => false,
+        })
+    }
 
-        let end_index = self.instructions.len();
-        if !if_statement.else_exprs.is_empty() {
-            self.instructions.push(Instruction::jmp_abs(0));
-        }
+    /// Returns whether every branch of an if statement, including its else
+    /// branch, diverges.
+    ///
+    /// # Arguments
+    ///
+    /// * `if_statement` - The if statement.
+    fn if_diverges(if_statement: &IfStatement) -> bool {
+        Self::block_diverges(&if_statement.exprs)
+            && match &if_statement.else_if {
+                Some(else_if) => Self::if_diverges(else_if),
+                None => Self::block_diverges(&if_statement.else_exprs),
+            }
+    }
 
-        let offset: i16 = (self.instructions.len() - else_index - 1).try_into()?;
-        self.instructions[else_index] = Instruction::jmp_abs(offset);
+    /// Returns whether every path through a block ends in a return statement,
+    /// e.g. an if statement whose branches all return.
+    ///
+    /// # Arguments
+    ///
+    /// * `exprs` - The expressions in the block.
+    fn always_returns(exprs: &[Expression]) -> bool {
+        exprs.iter().any(|expr| match expr {
+            Expression::Return(_) => true,
+            Expression::IfStatement(if_statement) => Self::if_always_returns(if_statement),
+            _ => false,
+        })
+    }
 
-        if !if_statement.else_exprs.is_empty() {
-            self.emit_body(&if_statement.else_exprs)?;
+    /// Returns whether every branch of an if statement, including its else
+    /// branch, ends in a return statement.
+    ///
+    /// # Arguments
+    ///
+    /// * `if_statement` - The if statement.
+    fn if_always_returns(if_statement: &IfStatement) -> bool {
+        Self::always_returns(&if_statement.exprs)
+            && match &if_statement.else_if {
+                Some(else_if) => Self::if_always_returns(else_if),
+                None => Self::always_returns(&if_statement.else_exprs),
+            }
+    }
 
-            let offset: i16 = (self.instructions.len() - end_index - 1).try_into()?;
-            self.instructions[end_index] = Instruction::jmp_abs(offset);
+    /// Fails if a variable is read before it's assigned on every path, it would
+    /// read whatever was left on the stack.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the variable.
+    fn check_variable_initialized(&mut self, name: &str) -> InternalResult<()> {
+        if let Some(VariableInfo {
+            initialized: false, ..
+        }) = self.variables.get(name)
+        {
+            semantics_bail!(
+                self.expr_num,
+                "Variable \"{}\" may be used before it's assigned",
+                name
+            );
         }
 
         Ok(())
     }
 
-    /// Emits instructions that perform a return.
+    /// Emits instructions for a block, e.g. the body of an if statement. Variables
+    /// declared in the block go out of scope when it ends and the stack space
+    /// they occupied is reclaimed for later variables.
     ///
     /// # Arguments
     ///
-    /// * `ret` - Information about the return.
-    fn emit_return(&mut self, ret: &Return) -> InternalResult<()> {
-        match &ret.value {
-            None => {
-                self.instructions.push(Instruction::mov64(Register::R0, 0));
-                self.instructions.push(Instruction::exit());
-            }
-            Some(value) => {
-                self.emit_set_register_from_rvalue(Register::R0, value, None)?;
-                self.instructions.push(Instruction::exit());
-            }
-        }
+    /// * `exprs` - The expressions in the block.
+    fn emit_scoped_body(&mut self, exprs: &[Expression]) -> InternalResult<()> {
+        let stack = self.stack;
+        let outer_variables: HashSet<String> = self.variables.keys().cloned().collect();
+
+        self.emit_body(exprs)?;
+
+        self.variables
+            .retain(|name, _| outer_variables.contains(name));
+        self.stack = stack;
 
         Ok(())
     }
 
-    /// Emits instructions that setup the function. Pushes arguments to the
-    /// stack, sets their types, etc.
+    /// Adds the name of the variable being assigned or declared to a stack
+    /// overflow error, which is otherwise raised deep inside the emission.
     ///
     /// # Arguments
     ///
-    /// * `input` - Information about the function's input.
-    fn emit_prologue(&mut self, input: &InputLine) -> InternalResult<()> {
-        /*
-         * BPF limits the number of function arguments to 5 (R1 to R5).
-         */
-        if input.args.len() > 5 {
-            semantics_bail!(self.expr_num, "Function exceeds 5 arguments");
-        }
-
-        /*
-         * Push all input arguments to the stack and create variables entries for them.
-         */
-        for (i, arg) in input.args.iter().enumerate() {
-            let register = Register::from_num((i + 1) as u8).expect("too many args");
-            let arg_type = self.type_from_decl(&arg.type_name)?;
-            let offset = self.emit_push_register(register, None)?;
-            self.variables.insert(
-                arg.name.clone(),
-                VariableInfo {
-                    var_type: arg_type,
-                    location: VariableLocation::Stack(offset),
-                },
-            );
+    /// * `err` - The error raised by the expression.
+    /// * `name` - The name of the variable.
+    fn name_stack_overflow(err: Error, name: &str) -> Error {
+        match err {
+            Error::Semantics { line, message } if message.starts_with("Stack size exceeded") => {
+                Error::Semantics {
+                    line,
+                    message: format!("{} by \"{}\"", message, name),
+                }
+            }
+            err => err,
         }
-
-        Ok(())
     }
 
     /// Emits instructions for the list of expressions given.
@@ -1140,12 +4681,18 @@ impl<'a> Compiler<'a> {
     ///
     /// * `exprs` - The expressions in the body.
     fn emit_body(&mut self, exprs: &[Expression]) -> InternalResult<()> {
-        for expr in exprs {
+        for (i, expr) in exprs.iter().enumerate() {
+            self.record_instruction_lines();
             self.expr_num += 1;
 
             match expr {
                 Expression::Assignment(assign) => {
-                    self.emit_assign(assign)?;
+                    self.emit_assign(assign)
+                        .map_err(|err| Self::name_stack_overflow(err, &assign.left.name))?;
+                }
+                Expression::Declaration(declaration) => {
+                    self.emit_declaration(declaration)
+                        .map_err(|err| Self::name_stack_overflow(err, &declaration.name))?;
                 }
                 Expression::FunctionCall(call) => {
                     self.emit_call(call)?;
@@ -1153,13 +4700,43 @@ impl<'a> Compiler<'a> {
                 Expression::IfStatement(if_statement) => {
                     self.emit_if_statement(if_statement)?;
                 }
+                Expression::WhileStatement(while_statement) => {
+                    self.emit_while_statement(while_statement)?;
+                }
                 Expression::Return(ret) => {
                     self.emit_return(ret)?;
                 }
+                Expression::Break(_) => {
+                    self.emit_break()?;
+                }
+                Expression::Continue(_) => {
+                    self.emit_continue()?;
+                }
+            }
+
+            /*
+             * nothing after an unconditional return, break or continue can run, so
+             * the rest of the body isn't emitted.
+             */
+            let keyword = match expr {
+                Expression::Return(_) => "return",
+                Expression::Break(_) => "break",
+                Expression::Continue(_) => "continue",
+                _ => continue,
+            };
+            let unreachable = &exprs[i + 1..];
+            if !unreachable.is_empty() {
+                self.expr_num += 1;
+                self.warn(format!(
+                    "Unreachable statements after {} are ignored",
+                    keyword
+                ));
+                self.expr_num += Self::count_expressions(unreachable) - 1;
+                break;
             }
         }
 
-        self.instructions = optimize(&self.instructions);
+        self.emit_optimize();
 
         Ok(())
     }
@@ -1184,21 +4761,69 @@ impl<'a> Compiler<'a> {
     /// "#).expect("Failed to compile.");
     /// ```
     pub fn compile(&mut self, script_text: &str) -> InternalResult<()> {
-        let ast = ScriptDef::parse(script_text)?;
+        let ast = parse(script_text)?;
         self.emit_prologue(&ast.input)?;
         self.emit_body(&ast.exprs)?;
 
         /*
-         * Programs implicitly return 0 when no return statement is specified.
+         * Programs implicitly return the default return value (0 unless changed
+         * with `set_default_return`) when no return statement is specified.
          */
-        let last = ast.exprs.last();
-        if matches!(last, None) || !matches!(last, Some(Expression::Return(_))) {
+        let returns = ast
+            .exprs
+            .iter()
+            .any(|expr| matches!(expr, Expression::Return(_)));
+        if !returns {
             self.emit_return(&Return { value: None })?;
         }
+        if !Self::always_returns(&ast.exprs) {
+            self.warn(
+                "Program has no explicit return; it implicitly returns the default value"
+                    .to_string(),
+            );
+        }
+        self.emit_remove_dead_code();
+        self.verify_jumps()?;
 
         Ok(())
     }
 
+    /// Compiles a given script, like `compile`, and returns a summary of the
+    /// compiled program.
+    ///
+    /// # Arguments
+    ///
+    /// * `script_text` - The script to compile, as a string.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// let report = compiler.compile_with_report(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// assert_eq!(report.stack_size, 8);
+    /// assert!(report.helpers.is_empty());
+    /// ```
+    pub fn compile_with_report(&mut self, script_text: &str) -> InternalResult<CompileReport> {
+        self.compile(script_text)?;
+
+        Ok(CompileReport {
+            instructions: self.get_instructions().to_vec(),
+            instruction_count: self.get_kernel_instruction_count(),
+            stack_size: self.max_stack,
+            helpers: self.helpers_used.iter().copied().collect(),
+            return_type: self.return_type.clone(),
+            max_branch_depth: self.max_branch_depth,
+            warnings: self.warnings.clone(),
+        })
+    }
+
     /// Returns the internally held instructions after `compile` has been called.
     ///
     /// # Example
@@ -1218,6 +4843,321 @@ impl<'a> Compiler<'a> {
     /// }
     /// ```
     pub fn get_instructions(&self) -> &[Instruction] {
+        self.instructions.instructions()
+    }
+
+    /// Returns the deepest nesting of conditional blocks in the compiled program,
+    /// 0 if it has no branches. If statements whose condition is known at compile
+    /// time don't branch and aren't counted.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         if a == 1 {
+    ///             return 1
+    ///         }
+    /// "#).expect("Failed to compile.");
+    /// assert_eq!(compiler.max_branch_depth(), 1);
+    /// ```
+    pub fn max_branch_depth(&self) -> usize {
+        self.max_branch_depth
+    }
+
+    /// Returns the peak stack usage, in bytes, of the compiled program. The
+    /// kernel rejects programs using more than 512 bytes of stack.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         b: u32 = a
+    ///         return b
+    /// "#).expect("Failed to compile.");
+    /// assert_eq!(compiler.get_stack_usage(), 12);
+    /// ```
+    pub fn get_stack_usage(&self) -> u32 {
+        self.max_stack
+    }
+
+    /// Returns the stack layout of the compiled program: the offset (relative to
+    /// the frame pointer, R10), type and name of each variable on the stack, in
+    /// the order they were placed, starting from the top of the stack. Variables
+    /// declared in blocks are out of scope by the end of compilation and aren't
+    /// listed.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// let stack_map = compiler.stack_map();
+    /// assert_eq!(stack_map[0].0, -8);
+    /// assert_eq!(stack_map[0].2, "a");
+    /// ```
+    pub fn stack_map(&self) -> Vec<(i16, Type, String)> {
+        let mut stack_map: Vec<(i16, Type, String)> = self
+            .variables
+            .iter()
+            .filter_map(|(name, info)| match info.location {
+                VariableLocation::Stack(offset) => {
+                    Some((offset, info.var_type.clone(), name.clone()))
+                }
+                VariableLocation::SpecialImmediate(_) => None,
+            })
+            .collect();
+        stack_map.sort_by_key(|(offset, _, _)| std::cmp::Reverse(*offset));
+        stack_map
+    }
+
+    /// Returns the number of instruction slots the kernel sees for the compiled
+    /// program. Wide instructions (64-bit immediate loads) take two slots, so this
+    /// can be larger than `get_instructions().len()`. This is the count to check
+    /// against the kernel's instruction limit.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.capture("outer", 0xdeadbeef);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return outer
+    /// "#).expect("Failed to compile.");
+    /// assert_eq!(compiler.get_kernel_instruction_count(), 3);
+    /// ```
+    pub fn get_kernel_instruction_count(&self) -> usize {
+        self.instructions
+            .instructions()
+            .iter()
+            .map(|ins| if ins.is_wide() { 2 } else { 1 })
+            .sum()
+    }
+
+    /// Pads the compiled program with no-op instructions (`r0 = r0`) until its
+    /// kernel instruction count is a multiple of `count`. The padding goes after
+    /// the final `exit` and is unreachable, so it should be called after
+    /// `compile`. Note that the kernel's verifier rejects unreachable
+    /// instructions; this is meant for loaders and tools that expect aligned
+    /// programs.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The instruction count to pad to a multiple of.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return 1
+    /// "#).expect("Failed to compile.");
+    /// compiler.pad_to(8);
+    /// assert_eq!(compiler.get_kernel_instruction_count(), 8);
+    /// ```
+    pub fn pad_to(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        let padding = (count - self.get_kernel_instruction_count() % count) % count;
+        for _ in 0..padding {
+            self.instructions
+                .push(Instruction::movx64(Register::R0, Register::R0));
+        }
+        self.record_instruction_lines();
+        self.optimized_len = self.instructions.current_index();
+    }
+
+    /// Returns the line info of the compiled program: an entry for each run of
+    /// instructions compiled from the same line, in the layout the kernel expects
+    /// when loading a program. Lines are numbered the same way as in errors and
+    /// warnings, and instruction offsets count wide instructions as two slots.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// for entry in compiler.get_line_info() {
+    ///     println!("{}: line {}", entry.insn_off, entry.line());
+    /// }
+    /// ```
+    pub fn get_line_info(&self) -> Vec<LineInfo> {
+        let mut line_info: Vec<LineInfo> = vec![];
+        let mut insn_off = 0;
+        for (i, ins) in self.instructions.instructions().iter().enumerate() {
+            let line = self.get_instruction_line(i);
+            if line_info.last().map(LineInfo::line) != Some(line) {
+                line_info.push(LineInfo {
+                    insn_off,
+                    line_col: line << 10,
+                    ..Default::default()
+                });
+            }
+            insn_off += if ins.is_wide() { 2 } else { 1 };
+        }
+
+        line_info
+    }
+
+    /// Returns the line of each instruction returned by `get_instructions`,
+    /// numbered the same way as in errors and warnings. Unlike `get_line_info`,
+    /// there's one entry per instruction, wide instructions included.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// let lines = compiler.get_line_map();
+    /// assert_eq!(lines.len(), compiler.get_instructions().len());
+    /// ```
+    pub fn get_line_map(&self) -> &[u32] {
+        &self.instruction_lines
+    }
+
+    /// Returns the compiled program as text, for debugging code generation: one
+    /// line per instruction with its index, the line it was compiled from and,
+    /// for loads and stores on the stack, the variable being accessed.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database.add_integer(Some("u32"), 4, false);
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn(a: u32)
+    ///         return a
+    /// "#).expect("Failed to compile.");
+    /// println!("{}", compiler.disassemble());
+    /// ```
+    pub fn disassemble(&self) -> String {
+        let mut text = String::new();
+        for (i, ins) in self.instructions.instructions().iter().enumerate() {
+            let mut comment = format!("line {}", self.get_instruction_line(i));
+            if let Some(access) = self.describe_stack_access(ins) {
+                comment = format!("{}, {}", comment, access);
+            }
+            text.push_str(&format!(
+                "{:>4}: {:<32} ; {}\n",
+                i,
+                ins.to_string(),
+                comment
+            ));
+        }
+
+        text
+    }
+
+    /// Describes a load from or a store to the stack for the disassembly, e.g.
+    /// "store to vec+8", or returns `None` for any other instruction.
+    ///
+    /// # Arguments
+    ///
+    /// * `ins` - The instruction to describe.
+    fn describe_stack_access(&self, ins: &Instruction) -> Option<String> {
+        let (action, reg) = match ins.get_opcode() {
+            Opcode::Memory(memory) => match memory.get_class() {
+                OpcodeClass::Store | OpcodeClass::StoreReg => ("store to", ins.get_dst_reg()),
+                OpcodeClass::LoadReg => ("load from", ins.get_src_reg()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if reg != Register::R10 {
+            return None;
+        }
+
+        let offset = ins.get_offset();
+        let variable = self
+            .variables
+            .iter()
+            .find_map(|(name, info)| match info.location {
+                VariableLocation::Stack(start)
+                    if offset >= start
+                        && i64::from(offset)
+                            < i64::from(start) + i64::from(info.var_type.get_size()) =>
+                {
+                    Some((name, offset - start))
+                }
+                _ => None,
+            });
+
+        let target = match variable {
+            Some((name, 0)) => name.clone(),
+            Some((name, field_offset)) => format!("{}+{}", name, field_offset),
+            None => format!("stack[{}]", offset),
+        };
+        Some(format!("{} {}", action, target))
+    }
+
+    /// Returns the warnings produced by `compile` as (line, message) pairs.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::compiler::Compiler;
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let mut compiler = Compiler::create(&database);
+    /// compiler.compile(r#"
+    ///     fn()
+    ///         return 0
+    /// "#).expect("Failed to compile.");
+    /// for (line, message) in compiler.get_warnings() {
+    ///     println!("warning: {} (Line {})", message, line);
+    /// }
+    /// ```
+    pub fn get_warnings(&self) -> &[(u32, String)] {
+        &self.warnings
+    }
+
+    /// Returns the sink holding the emitted instructions.
+    pub fn get_sink(&self) -> &S {
         &self.instructions
     }
 
@@ -1243,7 +5183,7 @@ impl<'a> Compiler<'a> {
     /// ```
     pub fn get_bytecode(&self) -> Vec<u64> {
         let mut bytecode = vec![];
-        for instruction in &self.instructions {
+        for instruction in self.instructions.instructions() {
             let (n, x) = instruction.encode();
             bytecode.push(n);
             if let Some(x) = x {
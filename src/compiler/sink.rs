@@ -0,0 +1,59 @@
+use bpf_ins::Instruction;
+
+/// A destination for the instructions emitted by the [`Compiler`](crate::compiler::Compiler).
+/// The compiler back-patches jumps and runs optimization passes over what it has
+/// already emitted, so a sink must be able to hand back the instructions it holds.
+/// Implementing this trait allows emission to be observed or retargeted without
+/// changing the compiler itself.
+pub trait InstructionSink {
+    /// Appends an instruction to the end of the program.
+    ///
+    /// # Arguments
+    ///
+    /// * `instruction` - The instruction to append.
+    fn push(&mut self, instruction: Instruction);
+
+    /// Returns the index at which the next pushed instruction will be placed.
+    fn current_index(&self) -> usize;
+
+    /// Replaces a previously emitted instruction, used when back-patching jumps.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instruction to replace.
+    /// * `instruction` - The new instruction.
+    fn patch(&mut self, index: usize, instruction: Instruction);
+
+    /// Returns the instructions emitted so far.
+    fn instructions(&self) -> &[Instruction];
+
+    /// Replaces every emitted instruction, used after an optimization pass has
+    /// rewritten the program.
+    ///
+    /// # Arguments
+    ///
+    /// * `instructions` - The new program.
+    fn replace(&mut self, instructions: Vec<Instruction>);
+}
+
+impl InstructionSink for Vec<Instruction> {
+    fn push(&mut self, instruction: Instruction) {
+        Vec::push(self, instruction);
+    }
+
+    fn current_index(&self) -> usize {
+        self.len()
+    }
+
+    fn patch(&mut self, index: usize, instruction: Instruction) {
+        self[index] = instruction;
+    }
+
+    fn instructions(&self) -> &[Instruction] {
+        self
+    }
+
+    fn replace(&mut self, instructions: Vec<Instruction>) {
+        *self = instructions;
+    }
+}
@@ -23,6 +23,9 @@ pub enum Error {
     #[error("no type with that name")]
     InvalidTypeName,
 
+    #[error("array {name:?} has a zero-sized element type")]
+    ZeroSizedArrayElement { name: String },
+
     #[error("internal error occurred that shouldn't be possible")]
     InternalError,
 }
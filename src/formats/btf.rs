@@ -1,119 +1,173 @@
 use crate::error::{Error, Result};
-use crate::types::{Array, BaseType, Field, Float, Integer, Struct, Type, TypeDatabase};
+use crate::types::{
+    Array, BaseType, Enum, Field, Float, Function, Integer, Struct, Type, TypeDatabase, Union,
+};
 
 use btf::{
-    Array as BtfArray, Btf, Float as BtfFloat, Integer as BtfInteger, Struct as BtfStruct,
-    Type as BtfType,
+    Array as BtfArray, Btf, Enum as BtfEnum, Float as BtfFloat, FunctionProto as BtfFunctionProto,
+    Integer as BtfInteger, Struct as BtfStruct, Type as BtfType,
 };
 
 use std::collections::HashMap;
 
 impl TypeDatabase {
-    /// Adds a void type.
+    /// Converts a BTF integer type.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
-    /// * `num_refs` - The reference count on the type.
-    fn add_btf_void(&mut self, name: Option<&str>, num_refs: u32) -> Result<usize> {
-        let new_type = Type {
-            base_type: BaseType::Void,
-            num_refs,
-        };
-
-        self.add_type(name, &new_type)
+    /// * `integer` - The BTF integer to convert.
+    fn convert_btf_integer(integer: &BtfInteger) -> BaseType {
+        BaseType::Integer(Integer {
+            used_bits: integer.used_bits,
+            bits: integer.bits,
+            is_signed: integer.is_signed,
+        })
     }
 
-    /// Adds a BTF integer type.
+    /// Converts a BTF float type.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
-    /// * `integer` - The BTF integer to add.
-    /// * `num_refs` - The reference count on the type.
-    fn add_btf_integer(
-        &mut self,
-        name: Option<&str>,
-        integer: &BtfInteger,
-        num_refs: u32,
-    ) -> Result<usize> {
-        let base_type = BaseType::Integer(Integer {
-            used_bits: integer.used_bits,
-            bits: integer.bits,
-            is_signed: integer.is_signed,
-        });
-
-        let new_type = Type {
-            base_type,
-            num_refs,
-        };
-
-        self.add_type(name, &new_type)
+    /// * `float` - The BTF float to convert.
+    fn convert_btf_float(float: &BtfFloat) -> BaseType {
+        BaseType::Float(Float { bits: float.bits })
     }
 
-    /// Adds a BTF float type.
+    /// Converts a BTF enum type, keeping the names and values of its entries.
+    /// Unnamed entries can't be referenced and are dropped.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
-    /// * `float` - The BTF float to add.
-    /// * `num_refs` - The reference count on the type.
-    fn add_btf_float(
-        &mut self,
-        name: Option<&str>,
-        float: &BtfFloat,
-        num_refs: u32,
-    ) -> Result<usize> {
-        let base_type = BaseType::Float(Float { bits: float.bits });
-
-        let new_type = Type {
-            base_type,
-            num_refs,
-        };
+    /// * `enumeration` - The BTF enum to convert.
+    /// * `bits` - The number of bits representing each value.
+    fn convert_btf_enum(enumeration: &BtfEnum, bits: u32) -> BaseType {
+        let values = enumeration
+            .entries
+            .iter()
+            .filter_map(|entry| Some((entry.name.clone()?, entry.value)))
+            .collect();
 
-        self.add_type(name, &new_type)
+        BaseType::Enum(Enum { bits, values })
     }
 
-    /// Adds a BTF array type.
+    /// Converts a BTF array type.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
-    /// * `array` - The BTF array to add.
-    /// * `num_refs` - The reference count on the type.
-    fn add_btf_array(
-        &mut self,
-        name: Option<&str>,
-        array: &BtfArray,
-        num_refs: u32,
-    ) -> Result<usize> {
+    /// * `array` - The BTF array to convert.
+    fn convert_btf_array(&self, array: &BtfArray) -> Result<BaseType> {
         let btf_id_name = format!(".btf.{}", array.elem_type_id);
         let element_type_id = self
             .get_type_id_by_name(&btf_id_name)
             .ok_or(Error::NoConversion)?;
-        let base_type = BaseType::Array(Array::create(self, element_type_id, array.num_elements)?);
-        let new_type = Type {
-            base_type,
-            num_refs,
+        Ok(BaseType::Array(Array::create(
+            self,
+            element_type_id,
+            array.num_elements,
+        )?))
+    }
+
+    /// Converts a BTF function prototype. BTF functions are flattened to their
+    /// prototypes, so this also converts named functions. A trailing void
+    /// parameter marks a variadic function and isn't kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `proto` - The BTF function prototype to convert.
+    fn convert_btf_function_proto(&self, proto: &BtfFunctionProto) -> Result<BaseType> {
+        let params = match proto.params.split_last() {
+            Some((last, params)) if last.type_id == 0 => params,
+            _ => &proto.params,
         };
-        self.add_type(name, &new_type)
+
+        let param_type_ids = params
+            .iter()
+            .map(|param| {
+                let btf_id_name = format!(".btf.{}", param.type_id);
+                self.get_type_id_by_name(&btf_id_name)
+                    .ok_or(Error::NoConversion)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(BaseType::Function(Function::create(&param_type_ids)))
     }
 
-    /// Adds a BTF struct type.
+    /// Returns the BTF struct (or union) an unnamed member embeds, if any. The
+    /// members of these anonymous containers are accessed as if they were
+    /// members of the enclosing struct.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
-    /// * `structure` - The BTF struct to add.
-    /// * `num_refs` - The reference count on the type.
-    fn add_btf_struct(
-        &mut self,
-        name: Option<&str>,
+    /// * `btf` - The BTF types.
+    /// * `type_id` - The BTF type id of the unnamed member.
+    fn get_anonymous_btf_struct(btf: &Btf, type_id: u32) -> Option<&BtfStruct> {
+        let btf_type = btf.get_types().get(type_id as usize)?;
+        if btf_type.num_refs != 0 {
+            return None;
+        }
+
+        match &btf_type.base_type {
+            BtfType::Struct(structure) | BtfType::Union(structure) => Some(structure),
+            _ => None,
+        }
+    }
+
+    /// Adds the members of an anonymous BTF struct to the field map of the
+    /// enclosing struct, at their absolute offsets. Nested anonymous structs
+    /// are flattened as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `btf` - The BTF types.
+    /// * `structure` - The anonymous BTF struct.
+    /// * `base_offset` - The offset, in bits, of the anonymous struct.
+    /// * `fields` - The field map of the enclosing struct.
+    fn promote_btf_struct_fields(
+        &self,
+        btf: &Btf,
+        structure: &BtfStruct,
+        base_offset: u32,
+        fields: &mut HashMap<String, Field>,
+    ) -> Result<()> {
+        for member in &structure.members {
+            let offset = base_offset + member.offset;
+            match member.name.as_deref().filter(|name| !name.is_empty()) {
+                Some(member_name) => {
+                    let btf_id_name = format!(".btf.{}", member.type_id);
+                    let type_id = self
+                        .get_type_id_by_name(&btf_id_name)
+                        .ok_or(Error::NoConversion)?;
+                    fields
+                        .entry(member_name.to_string())
+                        .or_insert(Field { offset, type_id });
+                }
+                None => {
+                    if let Some(inner) = Self::get_anonymous_btf_struct(btf, member.type_id) {
+                        self.promote_btf_struct_fields(btf, inner, offset, fields)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts the members of a BTF struct or union, returning the fields, size
+    /// and alignment. Members of anonymous nested structs and unions are promoted
+    /// into the fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `btf` - The BTF types.
+    /// * `structure` - The BTF struct or union to convert.
+    fn convert_btf_members(
+        &self,
+        btf: &Btf,
         structure: &BtfStruct,
-        num_refs: u32,
-    ) -> Result<usize> {
+    ) -> Result<(HashMap<String, Field>, u32, u32)> {
         let mut size = 0;
+        let mut alignment = 1;
         let mut fields = HashMap::with_capacity(structure.members.len());
+        let mut anonymous = vec![];
         for (i, member) in structure.members.iter().enumerate() {
             let btf_id_name = format!(".btf.{}", member.type_id);
             let type_id = self
@@ -124,53 +178,97 @@ impl TypeDatabase {
                 type_id,
             };
 
-            let field_type = self
-                .get_type_by_name(&btf_id_name)
-                .ok_or(Error::NoConversion)?;
+            let field_type = self.get_type_by_id(type_id).ok_or(Error::NoConversion)?;
             let field_size = (member.offset + field_type.get_size() * 8) / 8;
             if field_size > size {
                 size = field_size;
             }
+            alignment = alignment.max(field_type.alignment());
 
-            if let Some(member_name) = &member.name {
+            if let Some(member_name) = member.name.as_deref().filter(|name| !name.is_empty()) {
                 fields.insert(member_name.to_string(), field);
             } else {
                 let member_name = format!("{}", i);
                 fields.insert(member_name, field);
+                if let Some(inner) = Self::get_anonymous_btf_struct(btf, member.type_id) {
+                    anonymous.push((inner, member.offset));
+                }
             }
         }
 
-        let base_type = BaseType::Struct(Struct { size, fields });
-        let new_type = Type {
-            base_type,
-            num_refs,
-        };
-        self.add_type(name, &new_type)
+        // named members take precedence over promoted ones.
+        for (inner, offset) in anonymous {
+            self.promote_btf_struct_fields(btf, inner, offset, &mut fields)?;
+        }
+
+        Ok((fields, size, alignment))
     }
 
-    /// Adds a BTF type to the database.
+    /// Converts a BTF struct type.
     ///
     /// # Arguments
     ///
-    /// * `name` - The optional name of the type.
+    /// * `btf` - The BTF types.
+    /// * `structure` - The BTF struct to convert.
+    fn convert_btf_struct(&self, btf: &Btf, structure: &BtfStruct) -> Result<BaseType> {
+        let (fields, size, alignment) = self.convert_btf_members(btf, structure)?;
+        Ok(BaseType::Struct(Struct {
+            size,
+            fields,
+            alignment,
+        }))
+    }
+
+    /// Converts a BTF union type. The members all start at offset 0, so the
+    /// union is as large as its largest member.
+    ///
+    /// # Arguments
+    ///
+    /// * `btf` - The BTF types.
+    /// * `union` - The BTF union to convert.
+    fn convert_btf_union(&self, btf: &Btf, union: &BtfStruct) -> Result<BaseType> {
+        let (fields, size, alignment) = self.convert_btf_members(btf, union)?;
+        Ok(BaseType::Union(Union {
+            fields,
+            size,
+            alignment,
+        }))
+    }
+
+    /// Converts a BTF type into a type for this database. The conversion only
+    /// reads from the database, so the result can be registered under as many
+    /// names as needed. BTF types arrive flattened: `const`, `volatile` and
+    /// `restrict` qualifiers are already stripped and the pointers underneath
+    /// them are counted in `num_refs`.
+    ///
+    /// # Arguments
+    ///
+    /// * `btf` - The BTF types.
     /// * `btf_type` - The BTF type.
     /// * `num_refs` - The reference count on the type.
-    fn add_btf_type(
-        &mut self,
-        name: Option<&str>,
-        btf_type: &BtfType,
-        num_refs: u32,
-    ) -> Result<usize> {
-        match btf_type {
-            BtfType::Integer(integer) => self.add_btf_integer(name, integer, num_refs),
-            BtfType::Float(float) => self.add_btf_float(name, float, num_refs),
-            BtfType::Array(array) => self.add_btf_array(name, array, num_refs),
-            BtfType::Struct(structure) => self.add_btf_struct(name, structure, num_refs),
-            _ => self.add_btf_void(name, num_refs),
-        }
+    fn convert_btf_type(&self, btf: &Btf, btf_type: &BtfType, num_refs: u32) -> Result<Type> {
+        let base_type = match btf_type {
+            BtfType::Integer(integer) => Self::convert_btf_integer(integer),
+            BtfType::Float(float) => Self::convert_btf_float(float),
+            BtfType::Array(array) => self.convert_btf_array(array)?,
+            BtfType::Struct(structure) => self.convert_btf_struct(btf, structure)?,
+            BtfType::Union(union) => self.convert_btf_union(btf, union)?,
+            BtfType::Enum32(enumeration) => Self::convert_btf_enum(enumeration, 32),
+            BtfType::Enum64(enumeration) => Self::convert_btf_enum(enumeration, 64),
+            BtfType::FunctionProto(proto) => self.convert_btf_function_proto(proto)?,
+            _ => BaseType::Void,
+        };
+
+        Ok(Type {
+            base_type,
+            num_refs,
+        })
     }
 
-    /// Adds a parsed list of BTF types to this type database.
+    /// Adds a parsed list of BTF types to this type database. The BTF parser
+    /// resolves typedefs (and cv-qualifiers) to the type they name, so a typedef
+    /// like `size_t` is registered as an alias of its resolved type. Typedef
+    /// cycles are rejected when the BTF is parsed.
     ///
     /// # Arguments
     ///
@@ -194,19 +292,70 @@ impl TypeDatabase {
     /// ```
     pub fn add_btf_types(&mut self, btf: &Btf) -> Result<()> {
         // Types can forward reference, add placeholder for each.
+        let placeholder = Type::default();
         for i in 0..btf.get_types().len() {
             let btf_id_name = format!(".btf.{}", i);
-            self.add_btf_void(Some(&btf_id_name), 0)?;
+            self.add_type(Some(&btf_id_name), &placeholder)?;
         }
 
+        // Each type is converted once and then registered under both its
+        // id-name and, if it has one, its human readable name. Arrays can come
+        // before their element types, so they're deferred until those are.
+        let mut deferred = vec![];
         for (i, btf_type) in btf.get_types().iter().enumerate() {
-            let btf_id_name = format!(".btf.{}", i);
-            self.add_btf_type(Some(&btf_id_name), &btf_type.base_type, btf_type.num_refs)?;
-            self.add_btf_type(
-                btf_type.name.as_deref(),
-                &btf_type.base_type,
-                btf_type.num_refs,
-            )?;
+            match self.convert_btf_type(btf, &btf_type.base_type, btf_type.num_refs) {
+                Ok(new_type) => self.add_btf_type(i, btf_type.name.as_deref(), &new_type)?,
+                Err(Error::ZeroSizedArrayElement { .. }) => deferred.push(i),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Arrays of arrays resolve one level per pass.
+        loop {
+            let num_deferred = deferred.len();
+            let mut remaining = vec![];
+            for i in deferred {
+                let btf_type = &btf.get_types()[i];
+                match self.convert_btf_type(btf, &btf_type.base_type, btf_type.num_refs) {
+                    Ok(new_type) => self.add_btf_type(i, btf_type.name.as_deref(), &new_type)?,
+                    Err(Error::ZeroSizedArrayElement { .. }) => remaining.push(i),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            deferred = remaining;
+            if deferred.is_empty() || deferred.len() == num_deferred {
+                break;
+            }
+        }
+
+        // Arrays of unsupported (void) types are kept as void, like the
+        // unsupported types themselves.
+        for i in deferred {
+            let btf_type = &btf.get_types()[i];
+            let new_type = Type {
+                base_type: BaseType::Void,
+                num_refs: btf_type.num_refs,
+            };
+            self.add_btf_type(i, btf_type.name.as_deref(), &new_type)?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a converted BTF type under its id-name and, if it has one, its
+    /// human readable name.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the type in the BTF types.
+    /// * `name` - The name of the BTF type, if it has one.
+    /// * `new_type` - The converted type.
+    fn add_btf_type(&mut self, index: usize, name: Option<&str>, new_type: &Type) -> Result<()> {
+        let btf_id_name = format!(".btf.{}", index);
+        self.add_type(Some(&btf_id_name), new_type)?;
+        if let Some(name) = name.filter(|name| !name.is_empty()) {
+            self.add_type(Some(name), new_type)?;
         }
 
         Ok(())
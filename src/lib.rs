@@ -40,6 +40,30 @@
 //! }
 //! ```
 //!
+//! The commonly used types are also re-exported at the crate root:
+//!
+//! ```rust
+//! use bpf_script::{Compiler, Field, TypeDatabase};
+//!
+//! let mut types = TypeDatabase::default();
+//! let u32_id = types
+//!     .add_integer(Some("u32"), 4, false)
+//!     .expect("Failed to add type");
+//! let field = Field {
+//!     offset: 0,
+//!     type_id: u32_id,
+//! };
+//! types
+//!     .add_struct(Some("wrapper"), &[("value", field)])
+//!     .expect("Failed to add type");
+//!
+//! let mut compiler = Compiler::create(&types);
+//! compiler.compile(r#"
+//!     fn(w: &wrapper)
+//!         return w.value
+//! "#).expect("Compilation failed");
+//! ```
+//!
 //! ## TODO
 //!
 //! * Add control flow.
@@ -55,12 +79,19 @@ pub mod compiler;
 pub mod error;
 pub mod types;
 
+pub use compiler::Compiler;
+pub use types::{AddToTypeDatabase, BaseType, Field, Type, TypeDatabase};
+
 #[cfg(test)]
 mod tests {
-    use crate::compiler::Compiler;
-    use crate::error::Result;
-    use crate::types::{AddToTypeDatabase, Field, TypeDatabase};
-    use bpf_ins::{ArithmeticOperation, Instruction, JumpOperation, Register};
+    use crate::compiler::{
+        parse, Compiler, Expression, HelperTable, Helpers, InstructionSink, MapType,
+        RETURN_REGISTER,
+    };
+    use crate::error::{Error, Result};
+    use crate::types::{AddToTypeDatabase, BaseType, Field, StructLayout, Type, TypeDatabase};
+    use bpf_ins::{ArithmeticOperation, Instruction, JumpOperation, MemoryOpLoadType, Register};
+    use btf::Btf;
 
     #[repr(C, align(1))]
     struct LargeType {
@@ -83,7 +114,31 @@ mod tests {
         }
     }
 
-    fn compile_and_compare(prog: &str, expected: &[Instruction]) {
+    /// Builds a little-endian BTF blob from raw type and string sections and
+    /// parses it. The `btf` crate only parses from files, so the blob is written
+    /// to a temporary file first.
+    fn btf_from_raw(name: &str, types: &[u32], strings: &[u8]) -> Btf {
+        let type_len = (types.len() * 4) as u32;
+        let mut blob = vec![];
+        blob.extend_from_slice(&0xeb9fu16.to_le_bytes());
+        blob.extend_from_slice(&[1, 0]);
+        for word in [24, 0, type_len, type_len, strings.len() as u32] {
+            blob.extend_from_slice(&word.to_le_bytes());
+        }
+        for word in types {
+            blob.extend_from_slice(&word.to_le_bytes());
+        }
+        blob.extend_from_slice(strings);
+
+        let path =
+            std::env::temp_dir().join(format!("bpf-script-{}-{}.btf", name, std::process::id()));
+        std::fs::write(&path, blob).expect("Failed to write btf.");
+        let btf = Btf::from_file(&path).expect("Failed to parse btf.");
+        std::fs::remove_file(&path).expect("Failed to remove btf.");
+        btf
+    }
+
+    fn test_database() -> TypeDatabase {
         let mut database = TypeDatabase::default();
 
         LargeType::add_to_database(&mut database).expect("Failed to add type.");
@@ -96,6 +151,14 @@ mod tests {
             .add_integer(Some("__u64"), 8, false)
             .expect("Failed to add type.");
 
+        database
+            .add_float(Some("f32"), 32)
+            .expect("Failed to add type.");
+
+        database
+            .add_float(Some("f64"), 64)
+            .expect("Failed to add type.");
+
         let iov_base = Field {
             offset: 0,
             type_id: u64id,
@@ -113,6 +176,11 @@ mod tests {
             )
             .expect("Failed to add type.");
 
+        database
+    }
+
+    fn compile_and_compare(prog: &str, expected: &[Instruction]) {
+        let database = test_database();
         let mut compiler = Compiler::create(&database);
         compiler.compile(prog).unwrap();
 
@@ -178,12 +246,10 @@ mod tests {
         "#;
 
         let expected = [
-            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
-            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
             Instruction::store64(Register::R10, -16, 100), // *(r10 - 16) = 100
-            Instruction::store64(Register::R10, -8, 200), // *(r10 - 8) = 200
-            Instruction::mov64(Register::R0, 0),         // r0 = 0
-            Instruction::exit(),                         // exit
+            Instruction::store64(Register::R10, -8, 200),  // *(r10 - 8) = 200
+            Instruction::mov64(Register::R0, 0),           // r0 = 0
+            Instruction::exit(),                           // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -311,14 +377,11 @@ mod tests {
             Instruction::movx64(Register::R9, Register::R10),       // r9 = r10
             Instruction::loadx64(Register::R9, Register::R9, -16),  // r9 = *(r9 -16)
             Instruction::jmp_ifx(Register::R8, JumpOperation::IfGreater, Register::R9, 1), // if r8 > r9; PC += 1
-            Instruction::jmp_abs(3),                                // PC += 3
+            Instruction::jmp_abs(2),                                // PC += 2
             Instruction::loadx64(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8),
             Instruction::exit(),                                    // exit
-            Instruction::jmp_abs(2),                                // PC += 2
             Instruction::loadx64(Register::R0, Register::R10, -16), // r0 = *(r10 - 16),
             Instruction::exit(),                                    // exit
-            Instruction::mov64(Register::R0, 0),                    // r0 = 0
-            Instruction::exit(),                                    // exit
         ];
 
         compile_and_compare(prog, &expected);
@@ -338,9 +401,7 @@ mod tests {
         let expected = [
             Instruction::storex64(Register::R10, -8, Register::R1),
             Instruction::storex64(Register::R10, -16, Register::R2),
-            Instruction::mov64(Register::R6, 100),
-            Instruction::mov64(Register::R7, 1),
-            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Add),
+            Instruction::mov64(Register::R6, 101),
             Instruction::storex64(Register::R10, -24, Register::R6),
             Instruction::loadx64(Register::R6, Register::R10, -8),
             Instruction::movx64(Register::R7, Register::R10),
@@ -361,4 +422,4159 @@ mod tests {
 
         compile_and_compare(prog, &expected);
     }
+
+    #[test]
+    fn btf_types_converted_once() {
+        let strings = b"\0int\0foo\0a\0b\0";
+        let types = [
+            // [1] int: 4 bytes, signed, 32 bits
+            1,
+            1 << 24,
+            4,
+            (1 << 24) | 32,
+            // [2] struct foo { int a; int b; }
+            5,
+            (4 << 24) | 2,
+            8,
+            9,
+            1,
+            0,
+            11,
+            1,
+            32,
+            // [3] foo *
+            0,
+            2 << 24,
+            2,
+        ];
+        let btf = btf_from_raw("converted-once", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+
+        let by_name = database.get_type_by_name("foo").expect("No foo type.");
+        let by_id_name = database
+            .get_type_by_name(".btf.2")
+            .expect("No .btf.2 type.");
+        assert_eq!(by_name, by_id_name);
+        assert_eq!(by_name.get_size(), 8);
+        assert_eq!(
+            database.get_type_by_name("int"),
+            database.get_type_by_name(".btf.1")
+        );
+
+        // One placeholder per BTF type (including void) and one entry per named
+        // type; the anonymous pointer isn't registered a second time.
+        let next_id = database
+            .add_integer(None, 1, false)
+            .expect("Failed to add type.");
+        assert_eq!(next_id, btf.get_types().len() + 2);
+    }
+
+    #[test]
+    fn btf_array_before_element() {
+        let strings = b"\0int\0foo\0a\0b\0";
+        let types = [
+            // [1] int: 4 bytes, signed, 32 bits
+            1,
+            1 << 24,
+            4,
+            (1 << 24) | 32,
+            // [2] struct foo[4], before struct foo itself
+            0,
+            3 << 24,
+            0,
+            3,
+            1,
+            4,
+            // [3] struct foo { int a; int b; }
+            5,
+            (4 << 24) | 2,
+            8,
+            9,
+            1,
+            0,
+            11,
+            1,
+            32,
+        ];
+        let btf = btf_from_raw("array-before-element", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+
+        let array = database
+            .get_type_by_name(".btf.2")
+            .expect("No .btf.2 type.");
+        let foo_id = database
+            .get_type_id_by_name(".btf.3")
+            .expect("No .btf.3 type.");
+        match &array.base_type {
+            BaseType::Array(array) => assert_eq!(array.element_type_id, foo_id),
+            _ => panic!("The array was converted to {:?}.", array.base_type),
+        }
+        assert_eq!(array.get_size(), 32);
+    }
+
+    #[test]
+    fn offset_of_field() {
+        let prog = r#"
+            fn()
+                return offsetof(iovec, iov_len)
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R0, 8), // r0 = 8
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn size_of_and_offset_of() {
+        let prog = r#"
+            fn()
+                return sizeof(iovec) + offsetof(iovec, iov_len)
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R6, 24),
+            Instruction::movx64(Register::R0, Register::R6),
+            Instruction::exit(),
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn offset_of_unknown_field() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(
+                r#"
+            fn()
+                return offsetof(iovec, iov_size)
+        "#,
+            )
+            .expect_err("Unknown field compiled.");
+        assert!(err.to_string().contains("iov_size"));
+
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(
+                r#"
+            fn()
+                return offsetof(int, iov_len)
+        "#,
+            )
+            .expect_err("Non-structure compiled.");
+        assert!(err.to_string().contains("structure"));
+    }
+
+    #[derive(Default)]
+    struct CountingSink {
+        instructions: Vec<Instruction>,
+        pushed: usize,
+    }
+
+    impl InstructionSink for CountingSink {
+        fn push(&mut self, instruction: Instruction) {
+            self.pushed += 1;
+            self.instructions.push(instruction);
+        }
+
+        fn current_index(&self) -> usize {
+            self.instructions.len()
+        }
+
+        fn patch(&mut self, index: usize, instruction: Instruction) {
+            self.instructions[index] = instruction;
+        }
+
+        fn instructions(&self) -> &[Instruction] {
+            &self.instructions
+        }
+
+        fn replace(&mut self, instructions: Vec<Instruction>) {
+            self.instructions = instructions;
+        }
+    }
+
+    #[test]
+    fn custom_sink() {
+        let database = test_database();
+        let mut compiler = Compiler::with_sink(&database, CountingSink::default());
+        compiler
+            .compile(
+                r#"
+            fn(a: int)
+              return a
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // The store, the three address/load instructions and the exit are pushed
+        // before the optimizer folds the load down to a single instruction.
+        let sink = compiler.get_sink();
+        assert_eq!(sink.pushed, 5);
+        assert_eq!(sink.instructions.len(), 3);
+        assert_eq!(compiler.get_instructions(), sink.instructions.as_slice());
+    }
+
+    #[test]
+    fn prune_captured_condition() {
+        let prog = r#"
+            fn()
+                if flag == 1 {
+                    return 5
+                } else {
+                    return 6
+                }
+        "#;
+
+        let database = test_database();
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("flag", 0);
+        compiler.compile(prog).expect("Failed to compile.");
+        let expected = [
+            Instruction::mov64(Register::R0, 6), // r0 = 6
+            Instruction::exit(),                 // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("flag", 1);
+        compiler.compile(prog).expect("Failed to compile.");
+        let expected = [
+            Instruction::mov64(Register::R0, 5), // r0 = 5
+            Instruction::exit(),                 // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn prune_signed_captured_condition() {
+        let prog = r#"
+            fn()
+                if neg < 0 {
+                    return 1
+                }
+                return 2
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_typed("neg", -1, "int")
+            .expect("Failed to capture value.");
+        compiler.compile(prog).expect("Failed to compile.");
+        let expected = [
+            Instruction::mov64(Register::R0, 1), // r0 = 1
+            Instruction::exit(),                 // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn list_helpers() {
+        let helpers: Vec<_> = Helpers::all().collect();
+        assert!(!helpers.is_empty());
+
+        let (_, id, arg_types) = helpers
+            .iter()
+            .find(|(name, _, _)| *name == "map_update_elem")
+            .expect("map_update_elem not listed");
+        assert_eq!(*id, 2);
+        assert_eq!(arg_types[0], MemoryOpLoadType::Map);
+
+        for (name, id, _) in helpers {
+            let helper = Helpers::from_string(name).expect("Listed helper not found");
+            assert_eq!(helper.id(), id);
+        }
+    }
+
+    #[test]
+    fn declared_return_type() {
+        let prog = r#"
+            fn(a: int) -> int
+              return a
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx32(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn declared_return_type_mismatch() {
+        let mut database = test_database();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        database
+            .add_struct_by_ids(Some("pair"), &[("a", u32id), ("b", u32id)])
+            .expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(
+                r#"
+            fn() -> u32
+                p: pair = 0
+                return p
+        "#,
+            )
+            .expect_err("Returning a struct compiled.");
+        assert!(err.to_string().contains("return type"));
+
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(
+                r#"
+            fn(p: pair) -> u32
+                return &p
+        "#,
+            )
+            .expect_err("Returning a pointer compiled.");
+        assert!(err.to_string().contains("return type"));
+    }
+
+    #[test]
+    fn uninitialized_helper_argument() {
+        let mut database = TypeDatabase::default();
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("map", 0x1000, MapType::Hash);
+        compiler
+            .compile(
+                r#"
+            fn()
+                key: u32 = 0
+                buf: [u8; 64]
+                map_update_elem(map, &key, &buf, 0)
+                return 0
+        "#,
+            )
+            .expect("Failed to compile.");
+        let warnings = compiler.get_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, 4);
+        assert!(warnings[0].1.contains("\"buf\""));
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("map", 0x1000, MapType::Hash);
+        compiler
+            .compile(
+                r#"
+            fn()
+                key: u32 = 0
+                buf: [u8; 64]
+                get_current_comm(&buf, 64)
+                map_update_elem(map, &key, &buf, 0)
+                return 0
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+    }
+
+    #[test]
+    fn call_arguments_not_clobbered() {
+        let prog = r#"
+            fn()
+                x: __u64 = 5
+                return map_lookup_elem(get_current_uid_gid(), x)
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 5), // *(r10 - 8) = 5
+            Instruction::call(15),                      // call #15 (get_current_uid_gid)
+            Instruction::movx64(Register::R1, Register::R0), // r1 = r0
+            Instruction::loadx64(Register::R2, Register::R10, -8), // r2 = *(r10 - 8)
+            Instruction::call(1),                       // call #1 (map_lookup_elem)
+            Instruction::exit(),                        // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                x: __u64 = 5
+                return map_lookup_elem(x, get_current_uid_gid())
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 5), // *(r10 - 8) = 5
+            Instruction::call(15),                      // call #15 (get_current_uid_gid)
+            Instruction::movx64(Register::R2, Register::R0), // r2 = r0
+            Instruction::loadx64(Register::R1, Register::R10, -8), // r1 = *(r10 - 8)
+            Instruction::call(1),                       // call #1 (map_lookup_elem)
+            Instruction::exit(),                        // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                return map_lookup_elem(get_current_pid_tgid(), get_current_uid_gid())
+        "#;
+
+        let expected = [
+            Instruction::call(14), // call #14 (get_current_pid_tgid)
+            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
+            Instruction::call(15), // call #15 (get_current_uid_gid)
+            Instruction::movx64(Register::R2, Register::R0), // r2 = r0
+            Instruction::loadx64(Register::R1, Register::R10, -8), // r1 = *(r10 - 8)
+            Instruction::call(1),  // call #1 (map_lookup_elem)
+            Instruction::exit(),   // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn array_map_key_validation() {
+        let mut database = test_database();
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("counts", 3, MapType::Array);
+        let err = compiler
+            .compile(
+                r#"
+            fn()
+                key: iovec = 0
+                return map_lookup_elem(counts, &key)
+        "#,
+            )
+            .expect_err("Struct key on an array map compiled.");
+        assert!(err.to_string().contains("integer index key"));
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("counts", 3, MapType::PerCpuArray);
+        compiler
+            .compile(
+                r#"
+            fn()
+                key: u32 = 0
+                return map_lookup_elem(counts, &key)
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("events", 3, MapType::RingBuf);
+        compiler
+            .compile(
+                r#"
+            fn()
+                key: u32 = 0
+                return map_lookup_elem(events, &key)
+        "#,
+            )
+            .expect_err("Keyed access to a ring buffer compiled.");
+    }
+
+    #[test]
+    fn float_field_bits() {
+        let mut database = TypeDatabase::default();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        let floatid = database
+            .add_float(Some("float"), 32)
+            .expect("Failed to add type.");
+        database
+            .add_struct_by_ids(Some("sample"), &[("flags", u32id), ("ratio", floatid)])
+            .expect("Failed to add type.");
+
+        let prog = r#"
+            fn()
+                s: sample = 0
+                if s.ratio == one {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 0), // *(r10 - 8) = 0
+            Instruction::movx64(Register::R8, Register::R10), // r8 = r10
+            Instruction::add64(Register::R8, -8),       // r8 += -8
+            Instruction::loadx32(Register::R8, Register::R8, 4), // r8 = *(r8 + 4)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 0x3f800000, 1), // if r8 == 0x3f800000; PC += 1
+            Instruction::jmp_abs(2),                                                  // PC += 2
+            Instruction::mov64(Register::R0, 1),                                      // r0 = 1
+            Instruction::exit(),                                                      // exit
+            Instruction::mov64(Register::R0, 0),                                      // r0 = 0
+            Instruction::exit(),                                                      // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("one", 0x3f800000);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("one", 0x3f800000);
+        compiler
+            .compile(
+                r#"
+            fn()
+                s: sample = 0
+                if s.ratio > one {
+                    return 1
+                }
+        "#,
+            )
+            .expect_err("Ordered float comparison compiled.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+                s: sample = 0
+                r = s.ratio + s.ratio
+        "#,
+            )
+            .expect_err("Float arithmetic compiled.");
+    }
+
+    #[test]
+    fn kernel_instruction_count() {
+        let database = TypeDatabase::default();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("outer", 0xdeadbeef);
+        compiler
+            .compile(
+                r#"
+            fn()
+                return outer
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        assert_eq!(compiler.get_instructions().len(), 2);
+        assert_eq!(compiler.get_kernel_instruction_count(), 3);
+        assert_eq!(
+            compiler.get_kernel_instruction_count(),
+            compiler.get_bytecode().len()
+        );
+    }
+
+    #[test]
+    fn signed_helper_result() {
+        let prog = r#"
+            fn()
+                result = csum_diff(0, 0, 0, 0, 0)
+                if result < -1 {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::loadtype(Register::R1, 0, MemoryOpLoadType::Void), // r1 = 0
+            Instruction::loadtype(Register::R2, 0, MemoryOpLoadType::Void), // r2 = 0
+            Instruction::loadtype(Register::R3, 0, MemoryOpLoadType::Void), // r3 = 0
+            Instruction::loadtype(Register::R4, 0, MemoryOpLoadType::Void), // r4 = 0
+            Instruction::loadtype(Register::R5, 0, MemoryOpLoadType::Void), // r5 = 0
+            Instruction::call(28),                                          // call #28 (csum_diff)
+            Instruction::storex64(Register::R10, -8, Register::R0),         // *(r10 - 8) = r0
+            Instruction::loadx64(Register::R8, Register::R10, -8),          // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfSignedLessThan, -1, 1), // if (s64)r8 < -1; PC += 1
+            Instruction::jmp_abs(2),                                                   // PC += 2
+            Instruction::mov64(Register::R0, 1),                                       // r0 = 1
+            Instruction::exit(),                                                       // exit
+            Instruction::mov64(Register::R0, 0),                                       // r0 = 0
+            Instruction::exit(),                                                       // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn signed_narrow_comparison() {
+        let prog = r#"
+            fn(a: int)
+                if a < 0 {
+                    return 1
+                }
+                return 2
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx32(Register::R8, Register::R10, -8),  // w8 = *(r10 - 8)
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Lhs), // r8 <<= 32
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Ash), // r8 s>>= 32
+            Instruction::jmp_if(Register::R8, JumpOperation::IfSignedLessThan, 0, 1), // if (s64)r8 < 0; PC += 1
+            Instruction::jmp_abs(2),                                                  // PC += 2
+            Instruction::mov64(Register::R0, 1),                                      // r0 = 1
+            Instruction::exit(),                                                      // exit
+            Instruction::mov64(Register::R0, 2),                                      // r0 = 2
+            Instruction::exit(),                                                      // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn signed_narrow_negative_immediates() {
+        let prog = r#"
+            fn(a: int)
+                if a == -1 {
+                    return 1
+                }
+                if a < -2 {
+                    return 2
+                }
+                return 3
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx32(Register::R8, Register::R10, -8),  // w8 = *(r10 - 8)
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Lhs), // r8 <<= 32
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Ash), // r8 s>>= 32
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, -1, 1), // if r8 == -1; PC += 1
+            Instruction::jmp_abs(2),                                          // PC += 2
+            Instruction::mov64(Register::R0, 1),                              // r0 = 1
+            Instruction::exit(),                                              // exit
+            Instruction::loadx32(Register::R8, Register::R10, -8),            // w8 = *(r10 - 8)
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Lhs),   // r8 <<= 32
+            Instruction::alu64(Register::R8, 32, ArithmeticOperation::Ash),   // r8 s>>= 32
+            Instruction::jmp_if(Register::R8, JumpOperation::IfSignedLessThan, -2, 1), // if (s64)r8 < -2; PC += 1
+            Instruction::jmp_abs(2),                                                   // PC += 2
+            Instruction::mov64(Register::R0, 2),                                       // r0 = 2
+            Instruction::exit(),                                                       // exit
+            Instruction::mov64(Register::R0, 3),                                       // r0 = 3
+            Instruction::exit(),                                                       // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn array_of_void_rejected() {
+        let mut database = TypeDatabase::default();
+        let void_id = database
+            .add_type(Some("void"), &Type::default())
+            .expect("Failed to add type.");
+
+        let err = database
+            .add_array(Some("voids"), void_id, 4)
+            .expect_err("Array of void was added.");
+        assert!(matches!(err, Error::ZeroSizedArrayElement { name } if name == "voids"));
+        assert!(database.get_type_by_name("voids").is_none());
+    }
+
+    #[test]
+    fn condition_immediate_jump() {
+        let prog = r#"
+            fn(a: u64)
+                if a > 5 {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 5, 1), // if r8 > 5; PC += 1
+            Instruction::jmp_abs(2),                                // PC += 2
+            Instruction::mov64(Register::R0, 1),                    // r0 = 1
+            Instruction::exit(),                                    // exit
+            Instruction::mov64(Register::R0, 0),                    // r0 = 0
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn reserved_buffer() {
+        let mut database = TypeDatabase::default();
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .reserve_buffer("comm", 16)
+            .expect("Failed to reserve buffer.");
+        compiler
+            .compile(
+                r#"
+            fn()
+                get_current_comm(&comm, 16)
+                return trace_printk(&comm, 16)
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -16),            // r1 += -16
+            Instruction::loadtype(Register::R2, 16, MemoryOpLoadType::Void), // r2 = 16
+            Instruction::call(Helpers::GetCurrentComm.id()),  // call #16 (get_current_comm)
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -16),            // r1 += -16
+            Instruction::loadtype(Register::R2, 16, MemoryOpLoadType::Void), // r2 = 16
+            Instruction::call(Helpers::TracePrintk.id()),     // call #6 (trace_printk)
+            Instruction::exit(),                              // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+        assert!(compiler.get_warnings().is_empty());
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .reserve_buffer("comm", 16)
+            .expect("Failed to reserve buffer.");
+        assert!(compiler.reserve_buffer("comm", 16).is_err());
+    }
+
+    #[test]
+    fn compare_fields_of_two_structs() {
+        let prog = r#"
+            fn()
+                a: iovec = 0
+                b: iovec = 0
+                if a.iov_len > b.iov_len {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
+            Instruction::store64(Register::R10, -32, 0), // *(r10 - 32) = 0
+            Instruction::store64(Register::R10, -24, 0), // *(r10 - 24) = 0
+            Instruction::movx64(Register::R8, Register::R10), // r8 = r10
+            Instruction::add64(Register::R8, -16),       // r8 += -16
+            Instruction::loadx64(Register::R8, Register::R8, 8), // r8 = *(r8 + 8)
+            Instruction::movx64(Register::R9, Register::R10), // r9 = r10
+            Instruction::add64(Register::R9, -32),       // r9 += -32
+            Instruction::loadx64(Register::R9, Register::R9, 8), // r9 = *(r9 + 8)
+            Instruction::jmp_ifx(Register::R8, JumpOperation::IfGreater, Register::R9, 1), // if r8 > r9; PC += 1
+            Instruction::jmp_abs(2),             // PC += 2
+            Instruction::mov64(Register::R0, 1), // r0 = 1
+            Instruction::exit(),                 // exit
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        /*
+         * the optimizer used to re-run over the already emitted condition, shrinking
+         * it after the jump target had been recorded.
+         */
+        let prog = r#"
+            fn(a: &iovec, b: &iovec)
+                if a.iov_len > b.iov_len {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::loadx64(Register::R8, Register::R8, 8),    // r8 = *(r8 + 8)
+            Instruction::movx64(Register::R9, Register::R10),       // r9 = r10
+            Instruction::loadx64(Register::R9, Register::R9, -16),  // r9 = *(r9 - 16)
+            Instruction::loadx64(Register::R9, Register::R9, 8),    // r9 = *(r9 + 8)
+            Instruction::jmp_ifx(Register::R8, JumpOperation::IfGreater, Register::R9, 1), // if r8 > r9; PC += 1
+            Instruction::jmp_abs(2),             // PC += 2
+            Instruction::mov64(Register::R0, 1), // r0 = 1
+            Instruction::exit(),                 // exit
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn block_stack_reuse() {
+        let prog = r#"
+            fn(a: u64)
+                if a > 1 {
+                    x: __u64 = 5
+                }
+                if a > 2 {
+                    y: __u64 = 6
+                }
+                return x
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(prog)
+            .expect_err("Out-of-scope variable was accessible.");
+        assert!(err.to_string().contains("x"));
+
+        let prog = r#"
+            fn(a: u64)
+                if a > 1 {
+                    x: __u64 = 5
+                }
+                if a > 2 {
+                    y: __u64 = 6
+                }
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 1, 1), // if r8 > 1; PC += 1
+            Instruction::jmp_abs(1),                                // PC += 1
+            Instruction::store64(Register::R10, -16, 5),            // *(r10 - 16) = 5
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 2, 1), // if r8 > 2; PC += 1
+            Instruction::jmp_abs(1),                                // PC += 1
+            Instruction::store64(Register::R10, -16, 6),            // *(r10 - 16) = 6
+            Instruction::mov64(Register::R0, 0),                    // r0 = 0
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn trace_printk_format_string() {
+        let prog = r#"
+            fn()
+                trace_printk("hi\n")
+        "#;
+
+        let expected = [
+            Instruction::store32(Register::R10, -4, i32::from_le_bytes(*b"hi\n\0")), // *(r10 - 4) = "hi\n\0"
+            Instruction::movx64(Register::R1, Register::R10),                        // r1 = r10
+            Instruction::add64(Register::R1, -4),                                    // r1 += -4
+            Instruction::loadtype(Register::R2, 4, MemoryOpLoadType::Void),          // r2 = 4
+            Instruction::call(Helpers::TracePrintk.id()), // call #6 (trace_printk)
+            Instruction::mov64(Register::R0, 0),          // r0 = 0
+            Instruction::exit(),                          // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // an explicit size is passed as is.
+        let prog = r#"
+            fn()
+                trace_printk("hi\n", 4)
+        "#;
+
+        let expected = [
+            Instruction::store32(Register::R10, -4, i32::from_le_bytes(*b"hi\n\0")), // *(r10 - 4) = "hi\n\0"
+            Instruction::movx64(Register::R1, Register::R10),                        // r1 = r10
+            Instruction::add64(Register::R1, -4),                                    // r1 += -4
+            Instruction::loadtype(Register::R2, 4, MemoryOpLoadType::Void),          // r2 = 4
+            Instruction::call(Helpers::TracePrintk.id()), // call #6 (trace_printk)
+            Instruction::mov64(Register::R0, 0),          // r0 = 0
+            Instruction::exit(),                          // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn(a: u64)
+                trace_printk("%d%%\n", a)
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store32(Register::R10, -16, i32::from_le_bytes(*b"%d%%")), // *(r10 - 16) = "%d%%"
+            Instruction::store32(Register::R10, -12, i32::from_le_bytes(*b"\n\0\0\0")), // *(r10 - 12) = "\n\0"
+            Instruction::movx64(Register::R1, Register::R10),                           // r1 = r10
+            Instruction::add64(Register::R1, -16),                                      // r1 += -16
+            Instruction::loadtype(Register::R2, 6, MemoryOpLoadType::Void),             // r2 = 6
+            Instruction::loadx64(Register::R3, Register::R10, -8), // r3 = *(r10 - 8)
+            Instruction::call(Helpers::TracePrintk.id()),          // call #6 (trace_printk)
+            Instruction::mov64(Register::R0, 0),                   // r0 = 0
+            Instruction::exit(),                                   // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let err = compiler
+            .compile(
+                r#"
+            fn(a: u64)
+                trace_printk("%d %d\n", a)
+        "#,
+            )
+            .expect_err("Mismatched format arguments compiled.");
+        assert!(err.to_string().contains("expects 2 arguments"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+                trace_printk("hi\n", 5)
+        "#,
+            )
+            .expect_err("Oversized format compiled.");
+        assert!(err.to_string().contains("Format size 5 exceeds"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+                trace_printk("hi\n", 3)
+        "#,
+            )
+            .expect_err("Format size without the NUL terminator compiled.");
+        assert!(err
+            .to_string()
+            .contains("Format size 3 cuts off the NUL terminator"));
+    }
+
+    #[test]
+    fn natural_struct_layout() {
+        let mut database = TypeDatabase::default();
+        let u8id = u8::add_to_database(&mut database).expect("Failed to add type.");
+        let u64id = u64::add_to_database(&mut database).expect("Failed to add type.");
+
+        let padded_id = database
+            .add_struct_by_ids_with_layout(
+                Some("padded"),
+                &[("a", u8id), ("b", u64id)],
+                StructLayout::Natural,
+            )
+            .expect("Failed to add type.");
+        let packed_id = database
+            .add_struct_by_ids(Some("packed"), &[("a", u8id), ("b", u64id)])
+            .expect("Failed to add type.");
+
+        let padded = database.get_type_by_id(padded_id).unwrap();
+        assert_eq!(padded.alignment(), 8);
+        assert_eq!(padded.get_size(), 16);
+        let BaseType::Struct(structure) = &padded.base_type else {
+            panic!("Not a struct.");
+        };
+        assert_eq!(structure.fields["a"].offset, 0);
+        assert_eq!(structure.fields["b"].offset, 64);
+
+        let packed = database.get_type_by_id(packed_id).unwrap();
+        assert_eq!(packed.alignment(), 8);
+        assert_eq!(packed.get_size(), 9);
+    }
+
+    #[test]
+    fn statements_after_return() {
+        let prog = r#"
+            fn(a: u64)
+                return a
+                b = 5
+                return b
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
+            Instruction::exit(),                                    // exit
+        ];
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let warnings = compiler.get_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, 3);
+    }
+
+    #[test]
+    fn byte_swap() {
+        let prog = r#"
+            fn(a: u64)
+                return ntohs(a)
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        let instructions = compiler.get_instructions();
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(
+            instructions[1],
+            Instruction::loadx64(Register::R0, Register::R10, -8)
+        );
+
+        // BPF_ALU | BPF_END | BPF_TO_BE on r0, 16 bits.
+        assert_eq!(instructions[2].encode(), (0x0000_0010_0000_00dc, None));
+        assert!(matches!(
+            instructions[2].get_opcode(),
+            bpf_ins::Opcode::Arithmetic(opcode)
+                if matches!(opcode.get_operation(), ArithmeticOperation::End)
+                    && matches!(opcode.get_order(), bpf_ins::SwapOrder::Big)
+        ));
+        assert_eq!(instructions[3], Instruction::exit());
+    }
+
+    #[test]
+    fn map_get_value() {
+        let mut database = test_database();
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+
+        let prog = r#"
+            fn()
+                key: u32 = 7
+                vec = map_get(vectors, &key)
+                return vec.iov_len
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_map_with_value_type("vectors", 3, MapType::Hash, "iovec")
+            .expect("Failed to capture map.");
+        compiler.compile(prog).expect("Failed to compile.");
+
+        let expected = [
+            Instruction::store32(Register::R10, -4, 7), // *(r10 - 4) = 7
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map(3)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 += -4
+            Instruction::call(Helpers::MapLookupElem.id()), // call #1 (map_lookup_elem)
+            Instruction::jmp_if(Register::R0, JumpOperation::IfNotEqual, 0, 3), // if r0 != 0; PC += 3
+            Instruction::store64(Register::R10, -20, 0),                        // *(r10 - 20) = 0
+            Instruction::store64(Register::R10, -12, 0),                        // *(r10 - 12) = 0
+            Instruction::jmp_abs(4),                                            // PC += 4
+            Instruction::loadx64(Register::R1, Register::R0, 0),                // r1 = *(r0 + 0)
+            Instruction::storex64(Register::R10, -20, Register::R1),            // *(r10 - 20) = r1
+            Instruction::loadx64(Register::R1, Register::R0, 8),                // r1 = *(r0 + 8)
+            Instruction::storex64(Register::R10, -12, Register::R1),            // *(r10 - 12) = r1
+            Instruction::movx64(Register::R0, Register::R10),                   // r0 = r10
+            Instruction::add64(Register::R0, -20),                              // r0 += -20
+            Instruction::loadx64(Register::R0, Register::R0, 8),                // r0 = *(r0 + 8)
+            Instruction::exit(),                                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("vectors", 3, MapType::Hash);
+        let err = compiler
+            .compile(prog)
+            .expect_err("map_get on a map without a value type compiled.");
+        assert!(err.to_string().contains("value type"));
+    }
+
+    #[test]
+    fn indentation_is_cosmetic() {
+        let spaces = "fn(a: u64, b: u64)\n    if a > b {\n        return a\n    } else {\n        c = b + 1\n        return c\n    }\n";
+        let tabs = "fn(a: u64, b: u64)\n\tif a\t>\tb {\n\t\treturn a\n\t} else {\n\t\tc = b + 1\n\t\treturn c\n\t}\n";
+        let mixed = "\t fn(a: u64,\tb: u64) \r\n \tif a>b {\r\n\t    return a \t\r\n  \t}\telse\t{\r\n\t\t  c = b+1\n    \treturn c\n}";
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(spaces).expect("Failed to compile.");
+        let expected = compiler.get_instructions().to_vec();
+
+        for prog in [tabs, mixed] {
+            let mut compiler = Compiler::create(&database);
+            compiler.compile(prog).expect("Failed to compile.");
+            assert_eq!(compiler.get_instructions(), expected);
+        }
+
+        // two-character comparators parse regardless of the surrounding spacing.
+        for prog in [
+            "fn(a: u64, b: u64)\n\tif a <= b {\n\t\treturn 1\n\t}",
+            "fn(a: u64, b: u64)\n  if a<=b {\n    return 1\n  }",
+        ] {
+            let mut compiler = Compiler::create(&database);
+            compiler.compile(prog).expect("Failed to compile.");
+            assert!(compiler.get_instructions().contains(&Instruction::jmp_ifx(
+                Register::R8,
+                JumpOperation::IfLessThanOrEqual,
+                Register::R9,
+                1
+            )));
+        }
+    }
+
+    #[test]
+    fn custom_helper_table() {
+        let mut helpers = HelperTable::new();
+        helpers.insert(
+            "vm_emit".to_string(),
+            (
+                7,
+                [
+                    MemoryOpLoadType::Map,
+                    MemoryOpLoadType::Void,
+                    MemoryOpLoadType::Void,
+                    MemoryOpLoadType::Void,
+                    MemoryOpLoadType::Void,
+                ],
+            ),
+        );
+
+        let database = test_database();
+        let mut compiler = Compiler::with_helpers(&database, helpers.clone());
+        compiler.capture("channel", 2);
+        compiler
+            .compile(
+                r#"
+            fn(a: u64)
+                return vm_emit(channel, a)
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadtype(Register::R1, 2, MemoryOpLoadType::Map), // r1 = map(2)
+            Instruction::loadx64(Register::R2, Register::R10, -8),  // r2 = *(r10 - 8)
+            Instruction::call(7),                                   // call #7 (vm_emit)
+            Instruction::exit(),                                    // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::with_helpers(&database, helpers);
+        let err = compiler
+            .compile(
+                r#"
+            fn()
+                return get_current_pid_tgid()
+        "#,
+            )
+            .expect_err("Built-in helper was available.");
+        assert!(err.to_string().contains("Unknown function"));
+    }
+
+    #[test]
+    fn condition_on_call_result() {
+        let prog = r#"
+            fn()
+                if get_current_pid_tgid() == 1234 {
+                    return 1
+                }
+        "#;
+
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::movx64(Register::R8, Register::R0),    // r8 = r0
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 1234, 1), // if r8 == 1234; PC += 1
+            Instruction::jmp_abs(2),                                            // PC += 2
+            Instruction::mov64(Register::R0, 1),                                // r0 = 1
+            Instruction::exit(),                                                // exit
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // a target too large for the jump immediate is loaded into r9 after the call.
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("target", 0xdeadbeef);
+        compiler
+            .compile(
+                r#"
+            fn()
+                if get_current_pid_tgid() == target {
+                    return 1
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::movx64(Register::R8, Register::R0),    // r8 = r0
+            Instruction::loadtype(Register::R9, 0xdeadbeef, MemoryOpLoadType::Void), // r9 = 0xdeadbeef
+            Instruction::jmp_ifx(Register::R8, JumpOperation::IfEqual, Register::R9, 1), // if r8 == r9; PC += 1
+            Instruction::jmp_abs(2),                                                     // PC += 2
+            Instruction::mov64(Register::R0, 1),                                         // r0 = 1
+            Instruction::exit(),                                                         // exit
+            Instruction::mov64(Register::R0, 0),                                         // r0 = 0
+            Instruction::exit(),                                                         // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn default_return_value() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.set_default_return(1);
+        compiler
+            .compile(
+                r#"
+            fn()
+                pid_tgid = get_current_pid_tgid()
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
+            Instruction::mov64(Register::R0, 1),                // r0 = 1
+            Instruction::exit(),                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn array_of_struct_field() {
+        let prog = r#"
+            fn()
+                arr: [iovec; 4]
+                arr[1].iov_len = 5
+                return arr[1].iov_len
+        "#;
+
+        // arr is at r10 - 64; arr[1].iov_len is 16 + 8 = 24 bytes into it.
+        let expected = [
+            Instruction::store64(Register::R10, -40, 5), // *(r10 - 40) = 5
+            Instruction::movx64(Register::R0, Register::R10), // r0 = r10
+            Instruction::add64(Register::R0, -64),       // r0 += -64
+            Instruction::add64(Register::R0, 16),        // r0 += 16
+            Instruction::loadx64(Register::R0, Register::R0, 8), // r0 = *(r0 + 8)
+            Instruction::exit(),                         // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn plain_capture_as_map_argument() {
+        let mut database = TypeDatabase::default();
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+        let prog = r#"
+            fn()
+                key: u32 = 0
+                return map_lookup_elem(counts, &key)
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("counts", 3);
+        compiler.compile(prog).expect("Failed to compile.");
+        let warnings = compiler.get_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].0, 3);
+        assert!(warnings[0].1.contains("\"counts\""));
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("counts", 3, MapType::Hash);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+    }
+
+    #[test]
+    fn anonymous_nested_struct() {
+        let mut database = TypeDatabase::default();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        let u64id = u64::add_to_database(&mut database).expect("Failed to add type.");
+        let inner = database
+            .add_struct_by_ids(None, &[("low", u32id), ("high", u32id)])
+            .expect("Failed to add type.");
+        database
+            .add_struct_by_ids(Some("outer"), &[("id", u64id), ("", inner)])
+            .expect("Failed to add type.");
+
+        let outer = database
+            .get_type_by_name("outer")
+            .expect("Couldn't find outer.");
+        assert_eq!(outer.get_size(), 16);
+        if let BaseType::Struct(structure) = &outer.base_type {
+            assert_eq!(structure.fields.get("high").map(|f| f.offset), Some(96));
+            assert!(!structure.fields.contains_key(""));
+        } else {
+            panic!("outer isn't a struct");
+        }
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(o: &outer)
+                return o.high
+        "#,
+            )
+            .expect("Failed to compile.");
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
+            Instruction::loadx32(Register::R0, Register::R0, 12),   // w0 = *(w0 + 12)
+            Instruction::exit(),                                    // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn max_branch_depth() {
+        let database = test_database();
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: int)
+                b = a
+                return b
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert_eq!(compiler.max_branch_depth(), 0);
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: int)
+                if a == 1 {
+                    if a != 2 {
+                        if a != 3 {
+                            return 3
+                        }
+                    }
+                } else {
+                    return 4
+                }
+                if a == 5 {
+                    return 5
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert_eq!(compiler.max_branch_depth(), 3);
+    }
+
+    #[test]
+    fn assign_function_call_to_field() {
+        let prog = r#"
+            fn()
+                vec: iovec
+                vec.iov_len = get_current_uid_gid()
+        "#;
+
+        let expected = [
+            Instruction::call(15), // call #15 (get_current_uid_gid)
+            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
+            Instruction::mov64(Register::R0, 0), // r0 = 0
+            Instruction::exit(),   // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let mut database = TypeDatabase::default();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        database
+            .add_struct_by_ids(Some("pair"), &[("a", u32id), ("b", u32id)])
+            .expect("Failed to add type.");
+        let mut compiler = Compiler::create(&database);
+        let result = compiler.compile(
+            r#"
+            fn()
+                p: pair
+                p.b = get_current_uid_gid()
+        "#,
+        );
+        match result {
+            Err(Error::Semantics { line, message }) => {
+                assert_eq!(line, 3);
+                assert!(message.contains("64-bit"));
+            }
+            _ => panic!("storing a call result in a u32 field should fail"),
+        }
+    }
+
+    #[test]
+    fn compare_structs_rejected() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let result = compiler.compile(
+            r#"
+            fn()
+                x: iovec
+                y: iovec
+                if x > y {
+                    return 1
+                }
+        "#,
+        );
+        match result {
+            Err(Error::Semantics { line, message }) => {
+                assert_eq!(line, 4);
+                assert_eq!(message, "Cannot compare values of struct/array type");
+            }
+            _ => panic!("comparing structs should fail"),
+        }
+
+        let mut compiler = Compiler::create(&database);
+        let result = compiler.compile(
+            r#"
+            fn(a: &iovec)
+                if *a == 0 {
+                    return 1
+                }
+        "#,
+        );
+        match result {
+            Err(Error::Semantics { line, message }) => {
+                assert_eq!(line, 2);
+                assert_eq!(message, "Cannot compare values of struct/array type");
+            }
+            _ => panic!("comparing structs should fail"),
+        }
+    }
+
+    #[test]
+    fn volatile_expression() {
+        let prog = r#"
+            fn()
+                a: __u64 = volatile(1 + 1)
+                return a
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R6, 1), // r6 = 1
+            Instruction::mov64(Register::R7, 1), // r7 = 1
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Add), // r6 += r7
+            Instruction::storex64(Register::R10, -8, Register::R6), // *(r10 - 8) = r6
+            Instruction::loadx64(Register::R0, Register::R10, -8), // r0 = *(r10 - 8)
+            Instruction::exit(),                 // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // a volatile capture is compared at runtime rather than pruned.
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("flag", 0);
+        compiler
+            .compile(
+                r#"
+            fn()
+                if volatile(flag) == volatile(1) {
+                    return 1
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::loadtype(Register::R8, 0, MemoryOpLoadType::Void), // r8 = 0
+            Instruction::mov64(Register::R9, 1),                            // r9 = 1
+            Instruction::jmp_ifx(Register::R8, JumpOperation::IfEqual, Register::R9, 1), // if r8 == r9; PC += 1
+            Instruction::jmp_abs(2),                                                     // PC += 2
+            Instruction::mov64(Register::R0, 1),                                         // r0 = 1
+            Instruction::exit(),                                                         // exit
+            Instruction::mov64(Register::R0, 0),                                         // r0 = 0
+            Instruction::exit(),                                                         // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn unsigned_condition_immediate() {
+        let mut database = TypeDatabase::default();
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+
+        let compile = |prog: &str| {
+            let mut compiler = Compiler::create(&database);
+            compiler.compile(prog).expect("Failed to compile.");
+            compiler.get_instructions().to_vec()
+        };
+
+        let instructions = compile(
+            r#"
+            fn(a: u32)
+                if a > 5 {
+                    return 1
+                }
+        "#,
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 5, 1)
+        );
+
+        // -1 compared to a zero-extended u32 means 0xffffffff, not a 64-bit -1.
+        let instructions = compile(
+            r#"
+            fn(a: u32)
+                if a == -1 {
+                    return 1
+                }
+        "#,
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::loadtype(Register::R9, 0xffffffff, MemoryOpLoadType::Void)
+        );
+        assert_eq!(
+            instructions[3],
+            Instruction::jmp_ifx(Register::R8, JumpOperation::IfEqual, Register::R9, 1)
+        );
+
+        let instructions = compile(
+            r#"
+            fn(a: u8)
+                if -1 != a {
+                    return 1
+                }
+        "#,
+        );
+        assert_eq!(
+            instructions[2..4],
+            [
+                Instruction::mov64(Register::R8, 255),
+                Instruction::jmp_ifx(Register::R8, JumpOperation::IfNotEqual, Register::R9, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn return_call_result() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("map", 0x1000, MapType::Hash);
+        compiler
+            .compile(
+                r#"
+            fn()
+                key: __u64 = 5
+                return volatile(map_lookup_elem(map, &key))
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // the call leaves its result in the return register, nothing may follow it.
+        let instructions = compiler.get_instructions();
+        assert_eq!(RETURN_REGISTER, Register::R0);
+        assert_eq!(
+            instructions[instructions.len() - 2..],
+            [
+                Instruction::call(Helpers::MapLookupElem.id()),
+                Instruction::exit()
+            ]
+        );
+    }
+
+    #[test]
+    fn many_field_reads() {
+        const NUM_FIELDS: usize = 256;
+
+        let mut database = TypeDatabase::default();
+        let u64id = u64::add_to_database(&mut database).expect("Failed to add type.");
+        let names: Vec<String> = (0..NUM_FIELDS).map(|i| format!("f{}", i)).collect();
+        let fields: Vec<(&str, usize)> = names.iter().map(|name| (name.as_str(), u64id)).collect();
+        database
+            .add_struct_by_ids(Some("big"), &fields)
+            .expect("Failed to add type.");
+
+        let mut prog = String::from("fn(b: &big)\n");
+        for i in 0..NUM_FIELDS {
+            prog += &format!("    last = b.f{}\n", i);
+        }
+        prog += "    return last\n";
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(&prog).expect("Failed to compile.");
+
+        // every field is read through the pointer at its own offset, into `last`.
+        let instructions = compiler.get_instructions();
+        for i in 1..NUM_FIELDS {
+            let add = Instruction::add64(Register::R6, (i * 8) as i32);
+            assert!(instructions.contains(&add), "missing read of f{}", i);
+        }
+        let reads = instructions
+            .iter()
+            .filter(|ins| **ins == Instruction::call(Helpers::ProbeRead.id()))
+            .count();
+        assert_eq!(reads, NUM_FIELDS);
+    }
+
+    #[test]
+    fn capture_struct_flat() {
+        let mut database = TypeDatabase::default();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        let u64id = u64::add_to_database(&mut database).expect("Failed to add type.");
+        database
+            .add_struct_by_ids(Some("config"), &[("limit", u32id), ("mask", u64id)])
+            .expect("Failed to add type.");
+
+        let config = [7u32.to_le_bytes().as_slice(), 9u64.to_le_bytes().as_slice()].concat();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_struct_flat("config", &config)
+            .expect("Failed to capture struct.");
+        compiler
+            .compile(
+                r#"
+            fn()
+                if limit == 7 {
+                    return mask
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::store32(Register::R10, -12, 7), // *(r10 - 12) = 7
+            Instruction::store32(Register::R10, -8, 9),  // *(r10 - 8) = 9
+            Instruction::store32(Register::R10, -4, 0),  // *(r10 - 4) = 0
+            Instruction::loadx32(Register::R8, Register::R10, -12), // w8 = *(r10 - 12)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 7, 1), // if r8 == 7; PC += 1
+            Instruction::jmp_abs(2),                     // PC += 2
+            Instruction::loadx64(Register::R0, Register::R10, -8), // r0 = *(r10 - 8)
+            Instruction::exit(),                         // exit
+            Instruction::mov64(Register::R0, 0),         // r0 = 0
+            Instruction::exit(),                         // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        assert!(compiler
+            .capture_struct_flat("config", &config[..8])
+            .is_err());
+    }
+
+    #[test]
+    fn comparison_as_helper_argument() {
+        let prog = r#"
+            fn(a: __u64, b: __u64)
+                return redirect(a, a > b)
+        "#;
+
+        // the comparison's 0/1 result is materialized in r2, the flags argument.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R1, Register::R10, -8),  // r1 = *(r10 - 8)
+            Instruction::movx64(Register::R2, Register::R10),       // r2 = r10
+            Instruction::loadx64(Register::R2, Register::R2, -8),   // r2 = *(r2 - 8)
+            Instruction::movx64(Register::R0, Register::R10),       // r0 = r10
+            Instruction::loadx64(Register::R0, Register::R0, -16),  // r0 = *(r0 - 16)
+            Instruction::jmp_ifx(Register::R2, JumpOperation::IfGreater, Register::R0, 2), // if r2 > r0; PC += 2
+            Instruction::mov64(Register::R2, 0),                                           // r2 = 0
+            Instruction::jmp_abs(1),                   // PC += 1
+            Instruction::mov64(Register::R2, 1),       // r2 = 1
+            Instruction::call(Helpers::Redirect.id()), // call #23 (redirect)
+            Instruction::exit(),                       // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+        // parenthesized comparisons are values anywhere an rvalue is.
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+                above = (a > 1)
+                return (above == 1)
+        "#,
+            )
+            .expect("Failed to compile.");
+    }
+
+    #[test]
+    fn jump_over_wide_load() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("outer", 0xdeadbeef);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+                if a == 1 {
+                    return outer
+                } else {
+                    return 2
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // the wide load takes two slots, so the jump over the body skips three.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 1, 1), // if r8 == 1; PC += 1
+            Instruction::jmp_abs(3),                                // PC += 3
+            Instruction::loadtype(Register::R0, 0xdeadbeef, MemoryOpLoadType::Void), // r0 = 0xdeadbeef
+            Instruction::exit(),                                                     // exit
+            Instruction::mov64(Register::R0, 2),                                     // r0 = 2
+            Instruction::exit(),                                                     // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn pad_to_multiple() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("outer", 0xdeadbeef);
+        compiler
+            .compile(
+                r#"
+            fn()
+                return outer
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // the wide load counts as two instructions.
+        compiler.pad_to(4);
+        assert_eq!(compiler.get_kernel_instruction_count(), 4);
+        let instructions = compiler.get_instructions();
+        assert_eq!(instructions[1], Instruction::exit());
+        assert_eq!(
+            instructions[2],
+            Instruction::movx64(Register::R0, Register::R0)
+        );
+
+        // already a multiple, nothing is added.
+        compiler.pad_to(2);
+        assert_eq!(compiler.get_kernel_instruction_count(), 4);
+    }
+
+    #[test]
+    fn type_queries_by_name() {
+        let database = test_database();
+        assert!(database.contains("iovec"));
+        assert_eq!(database.size_of("iovec"), Some(16));
+        assert_eq!(database.size_of("LargeType"), Some(15));
+        assert!(!database.contains("sk_buff"));
+        assert_eq!(database.size_of("sk_buff"), None);
+    }
+
+    #[test]
+    fn map_counter_in_place_update() {
+        let mut database = test_database();
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+        u64::add_to_database(&mut database).expect("Failed to add type.");
+
+        let prog = r#"
+            fn()
+                key: u32 = 0
+                one: u64 = 1
+                v = map_lookup_elem(counts, &key)
+                if v == 0 {
+                    map_update_elem(counts, &key, &one, 0)
+                } else {
+                    *v = *v + 1
+                }
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_map_with_value_type("counts", 3, MapType::Hash, "u64")
+            .expect("Failed to capture map.");
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // the lookup result is a pointer to the map's u64 value, which is
+        // incremented in place without copying it to the stack.
+        let expected = [
+            Instruction::store32(Register::R10, -4, 0), // *(r10 - 4) = 0
+            Instruction::store64(Register::R10, -12, 1), // *(r10 - 12) = 1
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map(3)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 += -4
+            Instruction::call(Helpers::MapLookupElem.id()), // call #1 (map_lookup_elem)
+            Instruction::storex64(Register::R10, -20, Register::R0), // *(r10 - 20) = r0
+            Instruction::loadx64(Register::R8, Register::R10, -20), // r8 = *(r10 - 20)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 0, 1), // if r8 == 0; PC += 1
+            Instruction::jmp_abs(10),                   // PC += 10
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map(3)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 += -4
+            Instruction::movx64(Register::R3, Register::R10), // r3 = r10
+            Instruction::add64(Register::R3, -12),      // r3 += -12
+            Instruction::loadtype(Register::R4, 0, MemoryOpLoadType::Void), // r4 = 0
+            Instruction::call(Helpers::MapUpdateElem.id()), // call #2 (map_update_elem)
+            Instruction::jmp_abs(6),                    // PC += 6
+            Instruction::loadx64(Register::R6, Register::R10, -20), // r6 = *(r10 - 20)
+            Instruction::loadx64(Register::R6, Register::R6, 0), // r6 = *(r6 + 0)
+            Instruction::mov64(Register::R7, 1),        // r7 = 1
+            Instruction::addx64(Register::R6, Register::R7), // r6 += r7
+            Instruction::loadx64(Register::R7, Register::R10, -20), // r7 = *(r10 - 20)
+            Instruction::storex64(Register::R7, 0, Register::R6), // *(r7 + 0) = r6
+            Instruction::mov64(Register::R0, 0),        // r0 = 0
+            Instruction::exit(),                        // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(a: u64)
+                *a = 1
+        "#,
+            )
+            .expect_err("Assigning through a non-pointer compiled.");
+        assert!(err.to_string().contains("non-pointer"));
+    }
+
+    #[test]
+    fn duplicate_argument_name() {
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(a: u64, a: u32)
+                return a
+        "#,
+            )
+            .expect_err("Duplicate argument names compiled.");
+        assert!(err.to_string().contains("Duplicate argument name"));
+    }
+
+    #[test]
+    fn nested_struct_field() {
+        let mut database = test_database();
+        let u64id = database
+            .get_type_id_by_name("__u64")
+            .expect("Couldn't find __u64.");
+        let iovec_id = database
+            .get_type_id_by_name("iovec")
+            .expect("Couldn't find iovec.");
+        let flags = Field {
+            offset: 0,
+            type_id: u64id,
+        };
+        let inner = Field {
+            offset: 64,
+            type_id: iovec_id,
+        };
+        database
+            .add_struct(Some("outer"), &[("flags", flags), ("inner", inner)])
+            .expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+              o: outer = 0
+              o.inner.iov_len = 5
+              return o.inner.iov_len
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // `inner` is embedded by value, so `inner.iov_len` is 8 + 8 bytes into
+        // the struct and no pointer is followed on the way.
+        let expected = [
+            Instruction::store64(Register::R10, -24, 0), // *(r10 - 24) = 0
+            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -8, 5),  // *(r10 - 8) = 5
+            Instruction::movx64(Register::R0, Register::R10), // r0 = r10
+            Instruction::add64(Register::R0, -24),       // r0 += -24
+            Instruction::add64(Register::R0, 8),         // r0 += 8
+            Instruction::loadx64(Register::R0, Register::R0, 8), // r0 = *(r0 + 8)
+            Instruction::exit(),                         // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn initial_stack_offset() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .set_initial_stack(16)
+            .expect("Failed to reserve stack.");
+        compiler
+            .compile(
+                r#"
+            fn(a: u64)
+              return a
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // the argument is spilled below the 16 reserved bytes.
+        let expected = [
+            Instruction::storex64(Register::R10, -24, Register::R1), // *(r10 - 24) = r1
+            Instruction::loadx64(Register::R0, Register::R10, -24),  // r0 = *(r10 - 24)
+            Instruction::exit(),                                     // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_struct_flat("iovec", &[0; 16])
+            .expect("Failed to capture struct.");
+        compiler
+            .set_initial_stack(16)
+            .expect_err("Set the initial stack after it was used.");
+    }
+
+    #[test]
+    fn shifted_condition() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("target_pid", 1234);
+        compiler
+            .compile(
+                r#"
+            fn()
+              pid_tgid: __u64 = get_current_pid_tgid()
+              if (pid_tgid >> 32) == target_pid {
+                return 1
+              }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
+            Instruction::loadx64(Register::R6, Register::R10, -8), // r6 = *(r10 - 8)
+            Instruction::mov64(Register::R7, 32),               // r7 = 32
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Rhs), // r6 >>= r7
+            Instruction::movx64(Register::R8, Register::R6),    // r8 = r6
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 1234, 1), // if r8 == 1234; PC += 1
+            Instruction::jmp_abs(2),                                            // PC += 2
+            Instruction::mov64(Register::R0, 1),                                // r0 = 1
+            Instruction::exit(),                                                // exit
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        // both operands use the arithmetic registers, so one is spilled.
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64, b: __u64)
+              c = (a + 1) * (b - 2)
+              return c
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R6, Register::R10, -16), // r6 = *(r10 - 16)
+            Instruction::mov64(Register::R7, 2),                    // r7 = 2
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Sub), // r6 -= r7
+            Instruction::movx64(Register::R7, Register::R6),        // r7 = r6
+            Instruction::storex64(Register::R10, -24, Register::R7), // *(r10 - 24) = r7
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::mov64(Register::R7, 1),                    // r7 = 1
+            Instruction::addx64(Register::R6, Register::R7),        // r6 += r7
+            Instruction::loadx64(Register::R7, Register::R10, -24), // r7 = *(r10 - 24)
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Mul), // r6 *= r7
+            Instruction::storex64(Register::R10, -32, Register::R6), // *(r10 - 32) = r6
+            Instruction::loadx64(Register::R0, Register::R10, -32), // r0 = *(r10 - 32)
+            Instruction::exit(),                                    // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn stack_overflow_names_variable() {
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+              small: __u64 = 1
+              big: [__u64; 600]
+        "#,
+            )
+            .expect_err("Stack overflow compiled.");
+        assert!(err.to_string().contains("Stack size exceeded"));
+        assert!(err.to_string().contains("big"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+              first: [__u64; 63] = 0
+              second: iovec = 0
+        "#,
+            )
+            .expect_err("Stack overflow compiled.");
+        assert!(err.to_string().contains("second"));
+    }
+
+    #[test]
+    fn return_stored_call_result() {
+        let prog = r#"
+            fn()
+              x = get_current_pid_tgid()
+              return x
+        "#;
+
+        // r0 still holds `x` after it's stored, so it isn't reloaded.
+        let expected = [
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::storex64(Register::R10, -8, Register::R0), // *(r10 - 8) = r0
+            Instruction::exit(),                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn compile_report() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let report = compiler
+            .compile_with_report(
+                r#"
+            fn(a: __u64) -> __u64
+              if a > 1 {
+                b: iovec = 0
+                pid_tgid = get_current_pid_tgid()
+                return pid_tgid
+              }
+              return a
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        assert_eq!(report.instructions, compiler.get_instructions());
+        assert_eq!(report.instruction_count, report.instructions.len());
+        assert_eq!(report.stack_size, 32);
+        assert_eq!(report.helpers, vec![Helpers::GetCurrentPidTgid.id()]);
+        assert_eq!(
+            report.return_type,
+            database.get_type_by_name("__u64").cloned()
+        );
+        assert_eq!(report.max_branch_depth, 1);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn mut_reference_initializes() {
+        let mut helpers = HelperTable::new();
+        for (id, name) in [(7, "fill"), (8, "consume")] {
+            helpers.insert(name.to_string(), (id, [MemoryOpLoadType::Void; 5]));
+        }
+
+        let mut database = TypeDatabase::default();
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+
+        // without a known direction, `&buf` is assumed to be read.
+        let mut compiler = Compiler::with_helpers(&database, helpers.clone());
+        compiler
+            .compile(
+                r#"
+            fn()
+                buf: [u8; 16]
+                fill(&buf)
+                consume(&buf)
+                return 0
+        "#,
+            )
+            .expect("Failed to compile.");
+        let warnings = compiler.get_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].1.contains("\"fill\""));
+
+        let mut compiler = Compiler::with_helpers(&database, helpers);
+        compiler
+            .compile(
+                r#"
+            fn()
+                buf: [u8; 16]
+                fill(&mut buf)
+                consume(&buf)
+                return 0
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(a: &mut u8)
+        "#,
+            )
+            .expect_err("A &mut type compiled.");
+        assert!(err.to_string().contains("&mut"));
+    }
+
+    #[test]
+    fn stack_map_layout() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("outer", 1);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+              vec: iovec = 0
+              b: int = 5
+              if a > 1 {
+                c: __u64 = 2
+              }
+              return outer
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let u64_type = database.get_type_by_name("__u64").unwrap().clone();
+        let iovec_type = database.get_type_by_name("iovec").unwrap().clone();
+        let int_type = database.get_type_by_name("int").unwrap().clone();
+        assert_eq!(
+            compiler.stack_map(),
+            vec![
+                (-8, u64_type, "a".to_string()),
+                (-24, iovec_type, "vec".to_string()),
+                (-28, int_type, "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn func_arg_helpers() {
+        let prog = r#"
+            fn(ctx: __u64)
+              out: __u64
+              get_func_arg(ctx, 0, &out)
+              get_func_ret(ctx, &out)
+              return out
+        "#;
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // r1 still holds `ctx` after it's stored, so it isn't reloaded for the
+        // first call.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadtype(Register::R2, 0, MemoryOpLoadType::Void), // r2 = 0
+            Instruction::movx64(Register::R3, Register::R10),       // r3 = r10
+            Instruction::add64(Register::R3, -16),                  // r3 += -16
+            Instruction::call(183),                                 // call #183 (get_func_arg)
+            Instruction::loadx64(Register::R1, Register::R10, -8),  // r1 = *(r10 - 8)
+            Instruction::movx64(Register::R2, Register::R10),       // r2 = r10
+            Instruction::add64(Register::R2, -16),                  // r2 += -16
+            Instruction::call(184),                                 // call #184 (get_func_ret)
+            Instruction::loadx64(Register::R0, Register::R10, -16), // r0 = *(r10 - 16)
+            Instruction::exit(),                                    // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+        assert_eq!(Helpers::GetFuncArg.id(), 183);
+        assert_eq!(Helpers::GetFuncRet.id(), 184);
+        assert!(compiler.get_warnings().is_empty());
+    }
+
+    #[test]
+    fn line_info_after_optimization() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(vec: &iovec)
+              a = vec.iov_len
+              x = get_current_pid_tgid()
+              return x
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        // the reload of `x` for the return is optimized away, so line 4 starts
+        // at the exit.
+        let instructions = compiler.get_instructions();
+        assert_eq!(instructions.len(), 11);
+        assert_eq!(
+            instructions[8],
+            Instruction::call(Helpers::GetCurrentPidTgid.id())
+        );
+        assert_eq!(instructions[10], Instruction::exit());
+
+        let line_info: Vec<(u32, u32)> = compiler
+            .get_line_info()
+            .iter()
+            .map(|entry| (entry.insn_off, entry.line()))
+            .collect();
+        assert_eq!(line_info, vec![(0, 1), (1, 2), (8, 3), (10, 4)]);
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let prog = r#"
+            fn(a: __u64)
+                b: __u64 = 0
+                while a > 0 {
+                    a = a - 1
+                    b = b + 2
+                }
+                return b
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store64(Register::R10, -16, 0),            // *(r10 - 16) = 0
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 0, 1), // if r8 > 0; PC += 1
+            Instruction::jmp_abs(9),                                // PC += 9
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::mov64(Register::R7, 1),                    // r7 = 1
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Sub), // r6 -= r7
+            Instruction::storex64(Register::R10, -8, Register::R6), // *(r10 - 8) = r6
+            Instruction::loadx64(Register::R6, Register::R10, -16), // r6 = *(r10 - 16)
+            Instruction::mov64(Register::R7, 2),                    // r7 = 2
+            Instruction::addx64(Register::R6, Register::R7),        // r6 += r7
+            Instruction::storex64(Register::R10, -16, Register::R6), // *(r10 - 16) = r6
+            Instruction::jmp_abs(-12),                              // PC -= 12
+            Instruction::loadx64(Register::R0, Register::R10, -16), // r0 = *(r10 - 16)
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // with a loop limit, a hidden counter ends the loop after 16 iterations.
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.set_loop_limit(Some(16));
+        compiler.compile(prog).expect("Failed to compile.");
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store64(Register::R10, -16, 0),            // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -24, 0),            // *(r10 - 24) = 0
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 0, 1), // if r8 > 0; PC += 1
+            Instruction::jmp_abs(13),                               // PC += 13
+            Instruction::loadx64(Register::R8, Register::R10, -24), // r8 = *(r10 - 24)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreaterOrEqual, 16, 11), // if r8 >= 16; PC += 11
+            Instruction::add64(Register::R8, 1),                                        // r8 += 1
+            Instruction::storex64(Register::R10, -24, Register::R8), // *(r10 - 24) = r8
+            Instruction::loadx64(Register::R6, Register::R10, -8),   // r6 = *(r10 - 8)
+            Instruction::mov64(Register::R7, 1),                     // r7 = 1
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Sub), // r6 -= r7
+            Instruction::storex64(Register::R10, -8, Register::R6),  // *(r10 - 8) = r6
+            Instruction::loadx64(Register::R6, Register::R10, -16),  // r6 = *(r10 - 16)
+            Instruction::mov64(Register::R7, 2),                     // r7 = 2
+            Instruction::addx64(Register::R6, Register::R7),         // r6 += r7
+            Instruction::storex64(Register::R10, -16, Register::R6), // *(r10 - 16) = r6
+            Instruction::jmp_abs(-16),                               // PC -= 16
+            Instruction::loadx64(Register::R0, Register::R10, -16),  // r0 = *(r10 - 16)
+            Instruction::exit(),                                     // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn hex_and_binary_immediates() {
+        let prog = r#"
+            fn()
+                return 0xff
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R0, 255), // r0 = 255
+            Instruction::exit(),                   // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                a: int = 0b1010
+                b: int = -0x10
+                return a
+        "#;
+
+        let expected = [
+            Instruction::store32(Register::R10, -4, 10), // *(r10 - 4) = 10
+            Instruction::store32(Register::R10, -8, -16), // *(r10 - 8) = -16
+            Instruction::loadx32(Register::R0, Register::R10, -4), // w0 = *(r10 - 4)
+            Instruction::exit(),                         // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // constants outside the 32-bit immediate range are set with a wide load.
+        let prog = r#"
+            fn()
+                flags: __u64 = 0xdeadbeef
+                return flags
+        "#;
+
+        let expected = [
+            Instruction::loadtype(Register::R6, 0xdeadbeef, MemoryOpLoadType::Void), // r6 = 0xdeadbeef
+            Instruction::storex64(Register::R10, -8, Register::R6), // *(r10 - 8) = r6
+            Instruction::loadx64(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                return 0xdeadbeef
+        "#;
+
+        let expected = [
+            Instruction::loadtype(Register::R0, 0xdeadbeef, MemoryOpLoadType::Void), // r0 = 0xdeadbeef
+            Instruction::exit(),                                                     // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                a: int = 0x80000000
+                return a
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let err = compiler.compile(prog).unwrap_err();
+        assert!(err.to_string().contains("0x80000000"));
+    }
+
+    #[test]
+    fn negative_immediates() {
+        let prog = r#"
+            fn()
+                return -1
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R0, -1), // r0 = -1
+            Instruction::exit(),                  // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn()
+                x: u8 = -1
+                return x
+        "#;
+
+        let mut database = test_database();
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+        let mut compiler = Compiler::create(&database);
+        let err = compiler.compile(prog).unwrap_err();
+        assert!(err.to_string().contains("unsigned type"));
+    }
+
+    #[test]
+    fn logical_and_or_conditions() {
+        let prog = r#"
+            fn(a: __u64, b: __u64)
+                if a > 1 && b < 2 {
+                    return 1
+                }
+                return 0
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 1, 1), // if r8 > 1; PC += 1
+            Instruction::jmp_abs(5),                                // PC += 5
+            Instruction::loadx64(Register::R8, Register::R10, -16), // r8 = *(r10 - 16)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfLessThan, 2, 1), // if r8 < 2; PC += 1
+            Instruction::jmp_abs(2),                                            // PC += 2
+            Instruction::mov64(Register::R0, 1),                                // r0 = 1
+            Instruction::exit(),                                                // exit
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn(a: __u64, b: __u64)
+                if a > 1 || b < 2 {
+                    return 1
+                }
+                return 0
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 1, 3), // if r8 > 1; PC += 3
+            Instruction::loadx64(Register::R8, Register::R10, -16), // r8 = *(r10 - 16)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfLessThan, 2, 1), // if r8 < 2; PC += 1
+            Instruction::jmp_abs(2),                                            // PC += 2
+            Instruction::mov64(Register::R0, 1),                                // r0 = 1
+            Instruction::exit(),                                                // exit
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // && binds tighter than ||.
+        let prog = r#"
+            fn(a: __u64, b: __u64)
+                if a > 1 && a < 5 || b == 2 {
+                    return 1
+                }
+                return 0
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::storex64(Register::R10, -16, Register::R2), // *(r10 - 16) = r2
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 1, 1), // if r8 > 1; PC += 1
+            Instruction::jmp_abs(2),                                // PC += 2
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfLessThan, 5, 3), // if r8 < 5; PC += 3
+            Instruction::loadx64(Register::R8, Register::R10, -16),             // r8 = *(r10 - 16)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 2, 1), // if r8 == 2; PC += 1
+            Instruction::jmp_abs(2),                                         // PC += 2
+            Instruction::mov64(Register::R0, 1),                             // r0 = 1
+            Instruction::exit(),                                             // exit
+            Instruction::mov64(Register::R0, 0),                             // r0 = 0
+            Instruction::exit(),                                             // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn else_if_chain() {
+        let prog = r#"
+            fn(a: __u64)
+                if a == 1 {
+                    return 10
+                } else if a == 2 {
+                    return 20
+                } else {
+                    return 30
+                }
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 1, 1), // if r8 == 1; PC += 1
+            Instruction::jmp_abs(2),                                // PC += 2
+            Instruction::mov64(Register::R0, 10),                   // r0 = 10
+            Instruction::exit(),                                    // exit
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 2, 1), // if r8 == 2; PC += 1
+            Instruction::jmp_abs(2),                                // PC += 2
+            Instruction::mov64(Register::R0, 20),                   // r0 = 20
+            Instruction::exit(),                                    // exit
+            Instruction::mov64(Register::R0, 30),                   // r0 = 30
+            Instruction::exit(),                                    // exit
+        ];
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).unwrap();
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let exits = compiler
+            .get_instructions()
+            .iter()
+            .filter(|ins| **ins == Instruction::exit())
+            .count();
+        assert_eq!(exits, 3);
+        assert_eq!(compiler.max_branch_depth(), 1);
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let plain = r#"
+            fn(a: __u64)
+                b: __u64 = a
+                if b > 1 {
+                    return b
+                }
+                return 0
+        "#;
+
+        let commented = r#"
+            // returns its argument when it's greater than one.
+            fn(a: __u64)
+                b: __u64 = a // copy the argument
+                /*
+                 * only large values are returned, the rest
+                 * map to zero.
+                 */
+                if b > 1 { /* inline */
+                    return b
+                }
+                return 0 // default
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(plain).expect("Failed to compile.");
+        let expected = compiler.get_instructions().to_vec();
+
+        compile_and_compare(commented, &expected);
+
+        // comment markers in string literals are kept.
+        let plain = r#"
+            fn()
+                s = "a // b /* c */"
+                return 0
+        "#;
+
+        let commented = r#"
+            fn()
+                s = "a // b /* c */" // a string
+                return 0
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(plain).expect("Failed to compile.");
+        let expected = compiler.get_instructions().to_vec();
+        assert!(expected.len() > 2);
+
+        compile_and_compare(commented, &expected);
+    }
+
+    #[test]
+    fn parse_without_compiling() {
+        let ast = parse("fn(a: u32)\n return a").expect("Failed to parse.");
+        assert_eq!(ast.input.args.len(), 1);
+        assert_eq!(ast.input.args[0].name, "a");
+        assert_eq!(ast.input.args[0].type_name.name.as_deref(), Some("u32"));
+
+        match &ast.exprs[..] {
+            [Expression::Return(ret)] => {
+                let value = ret.value.as_ref().expect("Missing return value.");
+                assert!(value.op.is_none());
+            }
+            exprs => panic!("unexpected expressions {:?}", exprs),
+        }
+
+        assert!(matches!(parse("fn(a: u32"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn core_helpers() {
+        let prog = r#"
+            fn()
+                return ktime_get_ns()
+        "#;
+
+        let expected = [
+            Instruction::call(5), // call #5 (ktime_get_ns)
+            Instruction::exit(),  // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        for (name, id) in [
+            ("get_prandom_u32", 7),
+            ("get_smp_processor_id", 8),
+            ("ktime_get_boot_ns", 125),
+        ] {
+            let helper = Helpers::from_string(name).expect("Helper not found");
+            assert_eq!(helper.id(), id);
+            assert!(!helper.is_return_signed());
+        }
+    }
+
+    #[test]
+    fn capture_wide_value() {
+        let prog = r#"
+            fn()
+                return big
+        "#;
+
+        let mut database = test_database();
+        u64::add_to_database(&mut database).expect("Failed to add type.");
+        u8::add_to_database(&mut database).expect("Failed to add type.");
+
+        let big = i64::from(u32::MAX) + 2;
+        let expected = [
+            Instruction::loadtype(Register::R0, big, MemoryOpLoadType::Void), // r0 = 0x100000001
+            Instruction::exit(),                                              // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("big", big);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .capture_typed("big", big, "u64")
+            .expect("Failed to capture value.");
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let mut compiler = Compiler::create(&database);
+        assert!(compiler.capture_typed("big", big, "u8").is_err());
+        assert!(compiler.capture_typed("small", -1, "u8").is_err());
+        assert!(compiler.capture_typed("small", 255, "u8").is_ok());
+        assert!(compiler.capture_typed("vec", 0, "iovec").is_err());
+        assert!(matches!(
+            compiler.capture_typed("big", big, "u128"),
+            Err(Error::InvalidTypeName)
+        ));
+    }
+
+    #[test]
+    fn enum_constants() {
+        let mut database = test_database();
+        database
+            .add_enum(
+                Some("task_state"),
+                4,
+                &[("TASK_RUNNING", 0), ("TASK_DEAD", 64)],
+            )
+            .expect("Failed to add type.");
+        database
+            .add_enum(Some("color"), 4, &[("RED", 1), ("SHARED", 2)])
+            .expect("Failed to add type.");
+        database
+            .add_enum(Some("shape"), 4, &[("SQUARE", 1), ("SHARED", 3)])
+            .expect("Failed to add type.");
+
+        let prog = r#"
+            fn(a: __u64)
+                state = TASK_DEAD
+                if a == TASK_DEAD {
+                    return RED
+                }
+                return state
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store32(Register::R10, -12, 64),           // *(r10 - 12) = 64
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 64, 1), // if r8 == 64; PC += 1
+            Instruction::jmp_abs(2),                                          // PC += 2
+            Instruction::mov64(Register::R0, 1),                              // r0 = 1
+            Instruction::exit(),                                              // exit
+            Instruction::loadx32(Register::R0, Register::R10, -12),           // w0 = *(r10 - 12)
+            Instruction::exit(),                                              // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+
+        // variables shadow enum constants.
+        let prog = r#"
+            fn(RED: __u64)
+                return RED
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert!(!compiler
+            .get_instructions()
+            .contains(&Instruction::mov64(Register::R0, 1)));
+
+        // two enums define SHARED.
+        let prog = r#"
+            fn()
+                return SHARED
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        let err = compiler.compile(prog).unwrap_err();
+        assert!(err.to_string().contains("ambiguous"));
+    }
+
+    #[test]
+    fn btf_union_fields() {
+        let strings = b"\0int\0long\0val\0small\0large\0";
+        let types = [
+            // [1] int: 4 bytes, signed, 32 bits
+            1,
+            1 << 24,
+            4,
+            (1 << 24) | 32,
+            // [2] long: 8 bytes, signed, 64 bits
+            5,
+            1 << 24,
+            8,
+            (1 << 24) | 64,
+            // [3] union val { int small; long large; }
+            10,
+            (5 << 24) | 2,
+            8,
+            14,
+            1,
+            0,
+            20,
+            2,
+            0,
+        ];
+        let btf = btf_from_raw("union", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+        assert_eq!(database.size_of("val"), Some(8));
+
+        let prog = r#"
+            fn(v: &val)
+                a = v.small
+                b = v.large
+                return b
+        "#;
+
+        // both fields are read from the start of the union.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::movx64(Register::R1, Register::R10),       // r1 = r10
+            Instruction::add64(Register::R1, -12),                  // r1 += -12
+            Instruction::mov64(Register::R2, 4),                    // r2 = 4
+            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
+            Instruction::call(4),                                   // call #4 (probe_read)
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::movx64(Register::R1, Register::R10),       // r1 = r10
+            Instruction::add64(Register::R1, -20),                  // r1 += -20
+            Instruction::mov64(Register::R2, 8),                    // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
+            Instruction::call(4),                                   // call #4 (probe_read)
+            Instruction::loadx64(Register::R0, Register::R10, -20), // r0 = *(r10 - 20)
+            Instruction::exit(),                                    // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn btf_enum_values() {
+        let strings = b"\0state\0RUNNING\0STOPPED\0";
+        let types = [
+            // [1] enum state { RUNNING = 0, STOPPED = 4 }
+            1,
+            (6 << 24) | 2,
+            4,
+            7,
+            0,
+            15,
+            4,
+        ];
+        let btf = btf_from_raw("enum", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+
+        let state = database.get_type_by_name("state").expect("No state type.");
+        match &state.base_type {
+            BaseType::Enum(state) => {
+                assert_eq!(state.bits, 32);
+                assert_eq!(state.values.len(), 2);
+                assert_eq!(state.get_value("RUNNING"), Some(0));
+                assert_eq!(state.get_value("STOPPED"), Some(4));
+            }
+            other => panic!("state isn't an enum: {:?}", other),
+        }
+
+        let prog = r#"
+            fn()
+                return STOPPED
+        "#;
+
+        let expected = [
+            Instruction::mov64(Register::R0, 4), // r0 = 4
+            Instruction::exit(),                 // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn btf_typedef_alias() {
+        let strings = b"\0u64\0size_t\0";
+        let types = [
+            // [1] u64: 8 bytes, unsigned, 64 bits
+            1,
+            1 << 24,
+            8,
+            64,
+            // [2] typedef u64 size_t
+            5,
+            8 << 24,
+            1,
+        ];
+        let btf = btf_from_raw("typedef", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+        assert_eq!(
+            database.get_type_by_name("size_t"),
+            database.get_type_by_name("u64")
+        );
+
+        let prog = r#"
+            fn()
+                n: size_t = 5
+                return n
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 5), // *(r10 - 8) = 5
+            Instruction::loadx64(Register::R0, Register::R10, -8), // r0 = *(r10 - 8)
+            Instruction::exit(),                        // exit
+        ];
+
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn btf_function_prototype() {
+        let strings = b"\0u64\0foo\0a\0value\0f\0my_kfunc\0";
+        let types = [
+            // [1] u64: 8 bytes, unsigned, 64 bits
+            1,
+            1 << 24,
+            8,
+            64,
+            // [2] struct foo { u64 a; }
+            5,
+            (4 << 24) | 1,
+            8,
+            9,
+            1,
+            0,
+            // [3] struct foo *
+            0,
+            2 << 24,
+            2,
+            // [4] u64 (*)(u64 value, struct foo *f)
+            0,
+            (13 << 24) | 2,
+            1,
+            11,
+            1,
+            17,
+            3,
+            // [5] global u64 my_kfunc(u64 value, struct foo *f)
+            19,
+            (12 << 24) | 1,
+            4,
+        ];
+        let btf = btf_from_raw("function", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+
+        let foo_id = database.get_type_id_by_name("foo").unwrap();
+        let u64_id = database.get_type_id_by_name("u64").unwrap();
+        let my_kfunc = database.get_type_by_name("my_kfunc").unwrap();
+        match &my_kfunc.base_type {
+            BaseType::Function(function) => {
+                assert_eq!(function.param_type_ids.len(), 2);
+                assert_eq!(
+                    database.get_type_by_id(function.param_type_ids[0]),
+                    database.get_type_by_id(u64_id)
+                );
+                let ptr = database.get_type_by_id(function.param_type_ids[1]).unwrap();
+                assert_eq!(ptr.num_refs, 1);
+                assert_eq!(
+                    Some(&ptr.base_type),
+                    database.get_type_by_id(foo_id).map(|ty| &ty.base_type)
+                );
+            }
+            _ => panic!("my_kfunc isn't a function."),
+        }
+
+        let compile = |prog: &str| {
+            let mut compiler = Compiler::create(&database);
+            compiler.register_helper("my_kfunc", 200, [MemoryOpLoadType::Void; 5]);
+            compiler.compile(prog).map(|_| ())
+        };
+
+        compile(
+            r#"
+            fn(f: &foo)
+                my_kfunc(5, f)
+                return my_kfunc(f.a, 0)
+        "#,
+        )
+        .expect("Failed to compile.");
+
+        let err = compile(
+            r#"
+            fn(f: &foo)
+                return my_kfunc(f, f)
+        "#,
+        )
+        .expect_err("Pointer passed as an integer compiled.");
+        assert!(err.to_string().contains("Argument 1 of"));
+
+        let err = compile(
+            r#"
+            fn(x: &u64)
+                return my_kfunc(1, x)
+        "#,
+        )
+        .expect_err("Pointer to the wrong type compiled.");
+        assert!(err.to_string().contains("Argument 2 of"));
+
+        let err = compile(
+            r#"
+            fn()
+                return my_kfunc(1)
+        "#,
+        )
+        .expect_err("Missing argument compiled.");
+        assert!(err
+            .to_string()
+            .contains("takes 2 arguments but 1 were given"));
+    }
+
+    #[test]
+    fn btf_qualified_pointer_member() {
+        let strings = b"\0char\0file\0name\0flags\0";
+        let types = [
+            // [1] char: 1 byte, signed, 8 bits
+            1,
+            1 << 24,
+            1,
+            (1 << 24) | 8,
+            // [2] const char
+            0,
+            10 << 24,
+            1,
+            // [3] const char *
+            0,
+            2 << 24,
+            2,
+            // [4] volatile char
+            0,
+            9 << 24,
+            1,
+            // [5] struct file { const char *name; volatile char flags; }
+            6,
+            (4 << 24) | 2,
+            9,
+            11,
+            3,
+            0,
+            16,
+            4,
+            64,
+        ];
+        let btf = btf_from_raw("qualifiers", &types, strings);
+
+        let mut database = TypeDatabase::default();
+        database
+            .add_btf_types(&btf)
+            .expect("Failed to add btf types.");
+
+        let file = database.get_type_by_name("file").expect("No file type.");
+        let fields = file.base_type.fields().expect("file isn't a struct.");
+
+        let name = fields["name"]
+            .get_type(&database)
+            .expect("No type for name.");
+        assert!(name.is_pointer());
+        assert_eq!(name.num_refs, 1);
+        assert_eq!(name.get_size(), 8);
+
+        let flags = fields["flags"]
+            .get_type(&database)
+            .expect("No type for flags.");
+        assert!(!flags.is_pointer());
+        assert_eq!(flags.get_size(), 1);
+        assert_eq!(file.get_size(), 9);
+    }
+
+    #[test]
+    fn reset_between_programs() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture("outer", 3);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+              vec: iovec = 0
+              b: int = 5
+              return outer
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        compiler.reset();
+        assert!(compiler.get_instructions().is_empty());
+        compiler
+            .compile(
+                r#"
+            fn()
+              b: __u64 = 9
+              return outer
+        "#,
+            )
+            .expect("Failed to compile after reset.");
+
+        let u64_type = database.get_type_by_name("__u64").unwrap().clone();
+        assert_eq!(compiler.stack_map(), vec![(-8, u64_type, "b".to_string())]);
+
+        let mut fresh = Compiler::create(&database);
+        fresh.capture("outer", 3);
+        fresh
+            .compile(
+                r#"
+            fn()
+              b: __u64 = 9
+              return outer
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert_eq!(compiler.get_instructions(), fresh.get_instructions());
+
+        compiler.reset();
+        compiler.clear_captures();
+        compiler
+            .compile(
+                r#"
+            fn()
+              return outer
+        "#,
+            )
+            .expect_err("A cleared capture compiled.");
+    }
+
+    #[test]
+    fn stack_usage() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+              big: LargeType = 0
+              b: int = 5
+              if a > 1 {
+                c: __u64 = 2
+              }
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let size = |name| database.get_type_by_name(name).unwrap().get_size();
+        assert_eq!(
+            compiler.get_stack_usage(),
+            size("__u64") + size("LargeType") + size("int") + size("__u64")
+        );
+    }
+
+    #[test]
+    fn configurable_stack_limit() {
+        let database = test_database();
+        let script = r#"
+            fn()
+              buffer: [__u64; 75] = 0
+        "#;
+
+        let err = Compiler::create(&database)
+            .compile(script)
+            .expect_err("A 600 byte allocation compiled.");
+        assert!(err.to_string().contains("Stack size exceeded 512 bytes"));
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .set_max_stack(1024)
+            .expect("Failed to set stack size.");
+        compiler.compile(script).expect("Failed to compile.");
+        assert_eq!(compiler.get_stack_usage(), 600);
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .set_max_stack(8192)
+            .expect_err("An oversized stack was allowed.");
+        compiler
+            .set_initial_stack(64)
+            .expect("Failed to reserve stack.");
+        compiler
+            .set_max_stack(32)
+            .expect_err("A stack smaller than the reserved space was allowed.");
+    }
+
+    #[test]
+    fn line_map() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64, vec: &iovec)
+              b = vec.iov_len
+              return a
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let instructions = compiler.get_instructions();
+        let lines = compiler.get_line_map();
+        assert_eq!(lines.len(), instructions.len());
+        assert_eq!(instructions.last(), Some(&Instruction::exit()));
+        assert_eq!(lines.last(), Some(&3));
+        assert!(lines.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let prog = r#"
+            fn(a: u64)
+                c = a & 0xff
+                d = a << 2
+                e = a ^ c
+                f = a | 1
+                return a >> 1
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::mov64(Register::R7, 0xff),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::And),
+            Instruction::storex64(Register::R10, -16, Register::R6),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::mov64(Register::R7, 2),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Lhs),
+            Instruction::storex64(Register::R10, -24, Register::R6),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::movx64(Register::R7, Register::R10),
+            Instruction::loadx64(Register::R7, Register::R7, -16),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Xor),
+            Instruction::storex64(Register::R10, -32, Register::R6),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::mov64(Register::R7, 1),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Or),
+            Instruction::storex64(Register::R10, -40, Register::R6),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::mov64(Register::R7, 1),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Rhs),
+            Instruction::movx64(Register::R0, Register::R6),
+            Instruction::exit(),
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        // right shifts of signed values are arithmetic.
+        let prog = r#"
+            fn(a: int, b: int)
+                return a >> b
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1),
+            Instruction::storex64(Register::R10, -16, Register::R2),
+            Instruction::loadx32(Register::R6, Register::R10, -8),
+            Instruction::movx64(Register::R7, Register::R10),
+            Instruction::loadx32(Register::R7, Register::R7, -16),
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Lhs),
+            Instruction::alu64(Register::R6, 32, ArithmeticOperation::Ash),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Ash),
+            Instruction::movx64(Register::R0, Register::R6),
+            Instruction::exit(),
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn unary_operators() {
+        let prog = r#"
+            fn(mask: u64, flag: int)
+                x = ~mask
+                if !flag {
+                    return x
+                }
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1),
+            Instruction::storex64(Register::R10, -16, Register::R2),
+            Instruction::loadx64(Register::R6, Register::R10, -8),
+            Instruction::alu64(Register::R6, -1, ArithmeticOperation::Xor),
+            Instruction::storex64(Register::R10, -24, Register::R6),
+            Instruction::loadx32(Register::R8, Register::R10, -16),
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 0, 1),
+            Instruction::jmp_abs(2),
+            Instruction::loadx64(Register::R0, Register::R10, -24),
+            Instruction::exit(),
+            Instruction::mov64(Register::R0, 0),
+            Instruction::exit(),
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(flag: int)
+                x = !flag
+        "#,
+            )
+            .expect_err("A logical not compiled as a value.");
+        assert!(err.to_string().contains("conditions"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(mask: __u64)
+                if ~mask {
+                    return 1
+                }
+        "#,
+            )
+            .expect_err("A complement compiled as a condition.");
+        assert!(err.to_string().contains("not a condition"));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let prog = r#"
+            fn(a: __u64)
+                while a > 0 {
+                    a = a - 1
+                    if a == 5 {
+                        break
+                    }
+                    if a == 7 {
+                        continue
+                    }
+                }
+                return a
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 0, 1), // if r8 > 0; PC += 1
+            Instruction::jmp_abs(13),                               // PC += 13
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::mov64(Register::R7, 1),                    // r7 = 1
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Sub), // r6 -= r7
+            Instruction::storex64(Register::R10, -8, Register::R6), // *(r10 - 8) = r6
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 5, 1), // if r8 == 5; PC += 1
+            Instruction::jmp_abs(1),                                // PC += 1
+            Instruction::jmp_abs(5),                                // break: PC += 5
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfEqual, 7, 1), // if r8 == 7; PC += 1
+            Instruction::jmp_abs(1),                                // PC += 1
+            Instruction::jmp_abs(-15),                              // continue: PC -= 15
+            Instruction::jmp_abs(-16),                              // PC -= 16
+            Instruction::loadx64(Register::R0, Register::R10, -8),  // r0 = *(r10 - 8)
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        for keyword in ["break", "continue"] {
+            let prog = format!(
+                r#"
+            fn(a: __u64)
+                if a > 0 {{
+                    {}
+                }}
+        "#,
+                keyword
+            );
+            let err = Compiler::create(&database)
+                .compile(&prog)
+                .expect_err("A statement outside of a loop compiled.");
+            assert!(err.to_string().contains("can only be used in a loop"));
+        }
+    }
+
+    #[test]
+    fn typed_lookup_result() {
+        let database = test_database();
+        let prog = r#"
+            fn()
+                index: int = 0
+                vec: &iovec = map_lookup_elem(vectors, &index)
+                if vec != 0 {
+                    return vec.iov_len
+                }
+        "#;
+
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("vectors", 3, MapType::Hash);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // the field is read straight through the pointer held on the stack.
+        let expected = [
+            Instruction::store32(Register::R10, -4, 0), // *(r10 - 4) = 0
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map(3)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -4),       // r2 += -4
+            Instruction::call(Helpers::MapLookupElem.id()), // call #1 (map_lookup_elem)
+            Instruction::storex64(Register::R10, -12, Register::R0), // *(r10 - 12) = r0
+            Instruction::loadx64(Register::R8, Register::R10, -12), // r8 = *(r10 - 12)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfNotEqual, 0, 1), // if r8 != 0; PC += 1
+            Instruction::jmp_abs(3),                                            // PC += 3
+            Instruction::loadx64(Register::R0, Register::R10, -12),             // r0 = *(r10 - 12)
+            Instruction::loadx64(Register::R0, Register::R0, 8),                // r0 = *(r0 + 8)
+            Instruction::exit(),                                                // exit
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+                vec: &iovec = get_current_pid_tgid()
+        "#,
+            )
+            .expect_err("A scalar return value was stored in a pointer.");
+        assert!(err.to_string().contains("doesn't return a pointer"));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let prog = r#"
+            fn()
+                a: __u64 = 6 * 7
+                b: __u64 = 1 << 40
+                c: __u64 = 0xf0 ^ 0xff
+                return a
+        "#;
+
+        // 1 << 40 doesn't fit an immediate, so it's left alone.
+        let expected = [
+            Instruction::mov64(Register::R6, 42),
+            Instruction::storex64(Register::R10, -8, Register::R6),
+            Instruction::mov64(Register::R6, 1),
+            Instruction::mov64(Register::R7, 40),
+            Instruction::alux64(Register::R6, Register::R7, ArithmeticOperation::Lhs),
+            Instruction::storex64(Register::R10, -16, Register::R6),
+            Instruction::mov64(Register::R6, 0x0f),
+            Instruction::storex64(Register::R10, -24, Register::R6),
+            Instruction::loadx64(Register::R0, Register::R10, -8),
+            Instruction::exit(),
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn dead_code_removed() {
+        let prog = r#"
+            fn(a: __u64)
+                while a > 0 {
+                    return 1
+                }
+                return 2
+        "#;
+
+        // the jump back to the start of the loop can't be reached after the
+        // return, so it's removed and the jump out of the loop shrinks.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R8, Register::R10, -8),  // r8 = *(r10 - 8)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfGreater, 0, 1), // if r8 > 0; PC += 1
+            Instruction::jmp_abs(2),                                // PC += 2
+            Instruction::mov64(Register::R0, 1),                    // r0 = 1
+            Instruction::exit(),                                    // exit
+            Instruction::mov64(Register::R0, 2),                    // r0 = 2
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn dead_store_removed() {
+        let prog = r#"
+            fn()
+              vec: iovec = 0
+              vec.iov_base = 100
+              a: __u64 = vec.iov_len
+              vec.iov_len = a
+        "#;
+
+        // the zero store to iov_base is overwritten before it's read, the zero
+        // store to iov_len is read first and has to stay.
+        let expected = [
+            Instruction::store64(Register::R10, -8, 0), // *(r10 - 8) = 0
+            Instruction::store64(Register::R10, -16, 100), // *(r10 - 16) = 100
+            Instruction::movx64(Register::R6, Register::R10), // r6 = r10
+            Instruction::add64(Register::R6, -16),      // r6 += -16
+            Instruction::add64(Register::R6, 8),        // r6 += 8
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -24),      // r1 += -24
+            Instruction::mov64(Register::R2, 8),        // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6), // r3 = r6
+            Instruction::call(4),                       // call #4 (probe_read)
+            Instruction::movx64(Register::R6, Register::R10), // r6 = r10
+            Instruction::add64(Register::R6, -24),      // r6 += -24
+            Instruction::movx64(Register::R1, Register::R10), // r1 = r10
+            Instruction::add64(Register::R1, -8),       // r1 += -8
+            Instruction::mov64(Register::R2, 8),        // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6), // r3 = r6
+            Instruction::call(4),                       // call #4 (probe_read)
+            Instruction::mov64(Register::R0, 0),        // r0 = 0
+            Instruction::exit(),                        // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn type_alias() {
+        let mut database = TypeDatabase::default();
+        let id = u64::add_to_database(&mut database).expect("Failed to add type.");
+        let alias_id = database
+            .add_alias("__u64", id)
+            .expect("Failed to add alias.");
+
+        assert_eq!(alias_id, id);
+        assert_eq!(database.get_type_id_by_name("u64"), Some(id));
+        assert_eq!(database.get_type_id_by_name("__u64"), Some(id));
+        assert_eq!(
+            database.get_type_by_id(id),
+            database.get_type_by_name("__u64")
+        );
+
+        assert!(matches!(
+            database.add_alias("bogus", id + 1),
+            Err(Error::InvalidTypeId)
+        ));
+        assert!(!database.contains("bogus"));
+    }
+
+    #[test]
+    fn pointer_type() {
+        let mut database = TypeDatabase::default();
+        let id = u64::add_to_database(&mut database).expect("Failed to add type.");
+        let ptr_id = database
+            .add_pointer(Some("u64_ptr"), id, 1)
+            .expect("Failed to add type.");
+        let ptr_ptr_id = database
+            .add_pointer(None, ptr_id, 1)
+            .expect("Failed to add type.");
+
+        let ptr = database.get_type_by_id(ptr_id).unwrap();
+        assert!(ptr.is_pointer());
+        assert_eq!(ptr.get_size(), 8);
+        assert_eq!(database.get_type_by_id(ptr_ptr_id).unwrap().num_refs, 2);
+        assert!(!database.get_type_by_id(id).unwrap().is_pointer());
+
+        assert!(matches!(
+            database.add_pointer(None, ptr_ptr_id + 1, 1),
+            Err(Error::InvalidTypeId)
+        ));
+
+        let prog = r#"
+            fn(ptr: u64_ptr)
+                return *ptr
+        "#;
+        Compiler::create(&database)
+            .compile(prog)
+            .expect("Failed to compile.");
+    }
+
+    #[test]
+    fn float_immediates() {
+        let prog = r#"
+            fn()
+              x: f64 = 1.5
+              y: f32 = -0.25
+        "#;
+
+        let expected = [
+            Instruction::loadtype(
+                Register::R6,
+                1.5f64.to_bits() as i64,
+                MemoryOpLoadType::Void,
+            ), // r6 = 1.5
+            Instruction::storex64(Register::R10, -8, Register::R6), // *(r10 - 8) = r6
+            Instruction::store32(Register::R10, -12, (-0.25f32).to_bits() as i32), // *(r10 - 12) = -0.25
+            Instruction::mov64(Register::R0, 0),                                   // r0 = 0
+            Instruction::exit(),                                                   // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        let prog = r#"
+            fn()
+              x: f64 = 0x10
+        "#;
+        assert!(matches!(
+            compiler.compile(prog),
+            Err(Error::Semantics { .. })
+        ));
+    }
+
+    #[test]
+    fn type_names() {
+        let mut database = TypeDatabase::default();
+        let u32id = u32::add_to_database(&mut database).expect("Failed to add type.");
+        let field = Field {
+            offset: 0,
+            type_id: u32id,
+        };
+        database
+            .add_struct(Some("pair"), &[("first", field)])
+            .expect("Failed to add type.");
+
+        let names: Vec<&str> = database.type_names().collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"u32"));
+        assert!(names.contains(&"pair"));
+        assert_eq!(database.len(), 2);
+    }
+
+    #[test]
+    fn did_you_mean_suggestions() {
+        let mut database = test_database();
+        u32::add_to_database(&mut database).expect("Failed to add type.");
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+              x: u322 = 0
+        "#,
+            )
+            .expect_err("Unknown type compiled.");
+        assert!(err.to_string().contains("did you mean \\\"u32\\\"?"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+              x: completely_unknown = 0
+        "#,
+            )
+            .expect_err("Unknown type compiled.");
+        assert!(!err.to_string().contains("did you mean"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(counter: __u64)
+              return countr
+        "#,
+            )
+            .expect_err("Unknown variable compiled.");
+        assert!(err.to_string().contains("did you mean \\\"counter\\\"?"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(counter: __u64)
+              return total
+        "#,
+            )
+            .expect_err("Unknown variable compiled.");
+        assert!(!err.to_string().contains("did you mean"));
+    }
+
+    #[test]
+    fn array_index_by_variable() {
+        let prog = r#"
+            fn(i: __u64)
+                arr: [__u64; 4] = 0
+                return arr[i]
+        "#;
+
+        // the index is bounds-checked, out-of-bounds accesses return 0.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store64(Register::R10, -40, 0),            // *(r10 - 40) = 0
+            Instruction::store64(Register::R10, -32, 0),            // *(r10 - 32) = 0
+            Instruction::store64(Register::R10, -24, 0),            // *(r10 - 24) = 0
+            Instruction::store64(Register::R10, -16, 0),            // *(r10 - 16) = 0
+            Instruction::movx64(Register::R0, Register::R10),       // r0 = r10
+            Instruction::add64(Register::R0, -40),                  // r0 += -40
+            Instruction::storex64(Register::R10, -48, Register::R0), // *(r10 - 48) = r0
+            Instruction::movx64(Register::R0, Register::R10),       // r0 = r10
+            Instruction::add64(Register::R0, -8),                   // r0 += -8
+            Instruction::loadx64(Register::R0, Register::R0, 0),    // r0 = *(r0 + 0)
+            Instruction::jmp_if(Register::R0, JumpOperation::IfLessThan, 4, 2), // if r0 < 4; PC += 2
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+            Instruction::alu64(Register::R0, 8, ArithmeticOperation::Mul),      // r0 *= 8
+            Instruction::loadx64(Register::R7, Register::R10, -48),             // r7 = *(r10 - 48)
+            Instruction::addx64(Register::R0, Register::R7),                    // r0 += r7
+            Instruction::loadx64(Register::R0, Register::R0, 0),                // r0 = *(r0 + 0)
+            Instruction::exit(),                                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn array_store_by_variable() {
+        let prog = r#"
+            fn(i: __u64)
+                arr: [__u64; 4] = 0
+                arr[i] = 7
+                return arr[0]
+        "#;
+
+        // the value is spilled while the bounds-checked address is computed.
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::store64(Register::R10, -40, 0),            // *(r10 - 40) = 0
+            Instruction::store64(Register::R10, -32, 0),            // *(r10 - 32) = 0
+            Instruction::store64(Register::R10, -24, 0),            // *(r10 - 24) = 0
+            Instruction::store64(Register::R10, -16, 0),            // *(r10 - 16) = 0
+            Instruction::mov64(Register::R6, 7),                    // r6 = 7
+            Instruction::storex64(Register::R10, -48, Register::R6), // *(r10 - 48) = r6
+            Instruction::movx64(Register::R7, Register::R10),       // r7 = r10
+            Instruction::add64(Register::R7, -40),                  // r7 += -40
+            Instruction::storex64(Register::R10, -56, Register::R7), // *(r10 - 56) = r7
+            Instruction::movx64(Register::R7, Register::R10),       // r7 = r10
+            Instruction::add64(Register::R7, -8),                   // r7 += -8
+            Instruction::loadx64(Register::R7, Register::R7, 0),    // r7 = *(r7 + 0)
+            Instruction::jmp_if(Register::R7, JumpOperation::IfLessThan, 4, 2), // if r7 < 4; PC += 2
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+            Instruction::alu64(Register::R7, 8, ArithmeticOperation::Mul),      // r7 *= 8
+            Instruction::loadx64(Register::R9, Register::R10, -56),             // r9 = *(r10 - 56)
+            Instruction::addx64(Register::R7, Register::R9),                    // r7 += r9
+            Instruction::loadx64(Register::R6, Register::R10, -48),             // r6 = *(r10 - 48)
+            Instruction::storex64(Register::R7, 0, Register::R6),               // *(r7 + 0) = r6
+            Instruction::loadx64(Register::R0, Register::R10, -40),             // r0 = *(r10 - 40)
+            Instruction::exit(),                                                // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+    }
+
+    #[test]
+    fn array_index_by_variable_reuses_spill_slot() {
+        let prog = r#"
+            fn(i: __u64)
+                arr: [__u64; 4] = 0
+                a = arr[i]
+                b = arr[i]
+                return a + b
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // the spill slot of each access is given back, so `a` and `b` reuse it.
+        assert_eq!(compiler.get_stack_usage(), 56);
+    }
+
+    #[test]
+    fn array_index_by_constant() {
+        let prog = r#"
+            fn()
+                arr: [__u64; 4] = 0
+                return arr[3]
+        "#;
+
+        let expected = [
+            Instruction::store64(Register::R10, -32, 0), // *(r10 - 32) = 0
+            Instruction::store64(Register::R10, -24, 0), // *(r10 - 24) = 0
+            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
+            Instruction::movx64(Register::R0, Register::R10), // r0 = r10
+            Instruction::add64(Register::R0, -32),       // r0 += -32
+            Instruction::loadx64(Register::R0, Register::R0, 24), // r0 = *(r0 + 24)
+            Instruction::exit(),                         // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn()
+                arr: [__u64; 4] = 0
+                return arr[4]
+        "#,
+            )
+            .expect_err("Out-of-bounds access compiled.");
+        assert!(err.to_string().contains("Out-of-bounds array access 4/4"));
+    }
+
+    #[test]
+    fn nested_array_index() {
+        let mut database = TypeDatabase::default();
+        let u32id = database
+            .add_integer(Some("u32"), 4, false)
+            .expect("Failed to add type.");
+        let row_id = database
+            .add_array(Some("row"), u32id, 4)
+            .expect("Failed to add type.");
+        database
+            .add_array(Some("matrix"), row_id, 4)
+            .expect("Failed to add type.");
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+                m: matrix = 0
+                m[2][3] = 7
+                return m[2][3]
+        "#,
+            )
+            .unwrap();
+
+        // m[2][3] lives at 2 * 16 + 3 * 4 = 44 bytes into the 64-byte matrix.
+        let expected = [
+            Instruction::store64(Register::R10, -64, 0), // *(r10 - 64) = 0
+            Instruction::store64(Register::R10, -56, 0), // *(r10 - 56) = 0
+            Instruction::store64(Register::R10, -48, 0), // *(r10 - 48) = 0
+            Instruction::store64(Register::R10, -40, 0), // *(r10 - 40) = 0
+            Instruction::store64(Register::R10, -32, 0), // *(r10 - 32) = 0
+            Instruction::store64(Register::R10, -24, 0), // *(r10 - 24) = 0
+            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
+            Instruction::store32(Register::R10, -20, 7), // *(r10 - 64 + 44) = 7
+            Instruction::movx64(Register::R0, Register::R10), // r0 = r10
+            Instruction::add64(Register::R0, -64),       // r0 += -64
+            Instruction::add64(Register::R0, 32),        // r0 += 2 * 16
+            Instruction::loadx32(Register::R0, Register::R0, 12), // r0 = *(r0 + 3 * 4)
+            Instruction::exit(),                         // exit
+        ];
+
+        let instructions = compiler.get_instructions();
+        assert_eq!(instructions.len(), expected.len());
+        for (i, ins) in instructions.iter().enumerate() {
+            assert_eq!(ins, &expected[i]);
+        }
+    }
+
+    #[test]
+    fn deref_pointer_argument() {
+        let prog = r#"
+            fn(ptr: &__u64)
+                x: __u64 = *ptr
+                return x
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::movx64(Register::R1, Register::R10),       // r1 = r10
+            Instruction::add64(Register::R1, -16),                  // r1 += -16
+            Instruction::mov64(Register::R2, 8),                    // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
+            Instruction::call(4),                                   // call #4 (probe_read)
+            Instruction::loadx64(Register::R0, Register::R10, -16), // r0 = *(r10 - 16)
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let prog = r#"
+            fn(ptr: &iovec)
+                x: __u64 = ptr.iov_len
+                ptr.iov_base = x
+        "#;
+
+        let expected = [
+            Instruction::storex64(Register::R10, -8, Register::R1), // *(r10 - 8) = r1
+            Instruction::loadx64(Register::R6, Register::R10, -8),  // r6 = *(r10 - 8)
+            Instruction::add64(Register::R6, 8),                    // r6 += 8
+            Instruction::movx64(Register::R1, Register::R10),       // r1 = r10
+            Instruction::add64(Register::R1, -16),                  // r1 += -16
+            Instruction::mov64(Register::R2, 8),                    // r2 = 8
+            Instruction::movx64(Register::R3, Register::R6),        // r3 = r6
+            Instruction::call(4),                                   // call #4 (probe_read)
+            Instruction::loadx64(Register::R6, Register::R10, -16), // r6 = *(r10 - 16)
+            Instruction::movx64(Register::R7, Register::R10),       // r7 = r10
+            Instruction::loadx64(Register::R7, Register::R7, -8),   // r7 = *(r7 - 8)
+            Instruction::storex64(Register::R7, 0, Register::R6),   // *(r7 + 0) = r6
+            Instruction::mov64(Register::R0, 0),                    // r0 = 0
+            Instruction::exit(),                                    // exit
+        ];
+
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(value: __u64)
+                x: __u64 = *value
+        "#,
+            )
+            .expect_err("Dereferencing a non-pointer compiled.");
+        assert!(err
+            .to_string()
+            .contains("Cannot dereference a non-pointer type"));
+    }
+
+    #[test]
+    fn register_helper() {
+        let prog = r#"
+            fn()
+                my_kfunc(1)
+                return get_current_pid_tgid()
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.register_helper("my_kfunc", 200, [MemoryOpLoadType::Void; 5]);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // built-in helpers are still available next to the registered one.
+        let expected = [
+            Instruction::loadtype(Register::R1, 1, MemoryOpLoadType::Void), // r1 = 1
+            Instruction::call(200),                                         // call #200
+            Instruction::call(Helpers::GetCurrentPidTgid.id()), // call #14 (get_current_pid_tgid)
+            Instruction::exit(),                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn ringbuf_helpers() {
+        let prog = r#"
+            fn()
+                data: __u64 = 5
+                ringbuf_output(events, &data, 8, 0)
+                return 0
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("events", 7, MapType::RingBuf);
+        compiler.compile(prog).expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+
+        let expected = [
+            Instruction::store64(Register::R10, -8, 5), // *(r10 - 8) = 5
+            Instruction::loadtype(Register::R1, 7, MemoryOpLoadType::Map), // r1 = map(7)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -8),       // r2 += -8
+            Instruction::loadtype(Register::R3, 8, MemoryOpLoadType::Void), // r3 = 8
+            Instruction::loadtype(Register::R4, 0, MemoryOpLoadType::Void), // r4 = 0
+            Instruction::call(Helpers::RingbufOutput.id()), // call #130 (ringbuf_output)
+            Instruction::mov64(Register::R0, 0),        // r0 = 0
+            Instruction::exit(),                        // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+
+        for (name, id) in [
+            ("ringbuf_reserve", 131),
+            ("ringbuf_submit", 132),
+            ("ringbuf_discard", 133),
+            ("ringbuf_query", 134),
+        ] {
+            let helper = Helpers::from_string(name).expect("Helper not found");
+            assert_eq!(helper.id(), id);
+        }
+        assert_eq!(
+            Helpers::RingbufReserve.get_arg_types()[0],
+            MemoryOpLoadType::Map
+        );
+    }
+
+    #[test]
+    fn annotated_lookup_updates_value() {
+        let prog = r#"
+            fn()
+                key: __u64 = 0
+                vec: &iovec = map_lookup_elem(vectors, &key)
+                if vec != 0 {
+                    vec.iov_len = 4
+                }
+        "#;
+
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler.capture_map("vectors", 3, MapType::Hash);
+        compiler.compile(prog).expect("Failed to compile.");
+
+        // the field is written in place through the looked up pointer.
+        let expected = [
+            Instruction::store64(Register::R10, -8, 0), // *(r10 - 8) = 0
+            Instruction::loadtype(Register::R1, 3, MemoryOpLoadType::Map), // r1 = map(3)
+            Instruction::movx64(Register::R2, Register::R10), // r2 = r10
+            Instruction::add64(Register::R2, -8),       // r2 += -8
+            Instruction::call(Helpers::MapLookupElem.id()), // call #1 (map_lookup_elem)
+            Instruction::storex64(Register::R10, -16, Register::R0), // *(r10 - 16) = r0
+            Instruction::loadx64(Register::R8, Register::R10, -16), // r8 = *(r10 - 16)
+            Instruction::jmp_if(Register::R8, JumpOperation::IfNotEqual, 0, 1), // if r8 != 0; PC += 1
+            Instruction::jmp_abs(4),                                            // PC += 4
+            Instruction::mov64(Register::R6, 4),                                // r6 = 4
+            Instruction::loadx64(Register::R7, Register::R10, -16),             // r7 = *(r10 - 16)
+            Instruction::add64(Register::R7, 8),                                // r7 += 8
+            Instruction::storex64(Register::R7, 0, Register::R6),               // *(r7 + 0) = r6
+            Instruction::mov64(Register::R0, 0),                                // r0 = 0
+            Instruction::exit(),                                                // exit
+        ];
+        assert_eq!(compiler.get_instructions(), expected);
+    }
+
+    #[test]
+    fn rewrite_instructions() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+                return 1
+        "#,
+            )
+            .expect("Failed to compile.");
+
+        let mov = compiler
+            .get_instructions_mut()
+            .iter_mut()
+            .find(|ins| **ins == Instruction::mov64(Register::R0, 1))
+            .expect("Return value not found.");
+        *mov = Instruction::mov64(Register::R0, 42);
+
+        let (encoded, _) = Instruction::mov64(Register::R0, 42).encode();
+        assert_eq!(compiler.get_bytecode()[0], encoded);
+    }
+
+    #[test]
+    fn use_before_assignment() {
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(a: __u64)
+                x: __u64
+                if a > 1 {
+                    x = 1
+                }
+                return x
+        "#,
+            )
+            .expect_err("Maybe-unassigned variable read compiled.");
+        assert!(err
+            .to_string()
+            .contains("Variable \\\"x\\\" may be used before it's assigned"));
+
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn(a: __u64)
+                x: __u64
+                while a > 1 {
+                    x = 1
+                    a = a - 1
+                }
+                return x
+        "#,
+            )
+            .expect_err("Maybe-unassigned variable read compiled.");
+        assert!(err.to_string().contains("may be used before it's assigned"));
+
+        // assigned in every branch that reaches the read.
+        for prog in [
+            r#"
+            fn(a: __u64)
+                x: __u64
+                if a > 1 {
+                    x = 1
+                } else {
+                    x = 2
+                }
+                return x
+        "#,
+            r#"
+            fn(a: __u64)
+                x: __u64
+                if a > 1 {
+                    return 0
+                } else {
+                    x = 2
+                }
+                return x
+        "#,
+            r#"
+            fn(a: __u64)
+                y: __u64
+                if a == 1 {
+                    if a == 2 {
+                        return 1
+                    } else {
+                        return 2
+                    }
+                } else {
+                    y = 3
+                }
+                return y
+        "#,
+        ] {
+            Compiler::create(&database)
+                .compile(prog)
+                .expect("Assigned variable read failed.");
+        }
+    }
+
+    #[test]
+    fn implicit_return_warning() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+                get_current_pid_tgid()
+        "#,
+            )
+            .expect("Failed to compile.");
+        let warnings = compiler.get_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].1.contains("no explicit return"));
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+                get_current_pid_tgid()
+                return 1
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+
+        // every branch of the if statement returns.
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: int)
+                if a == 0 {
+                    return 1
+                } else {
+                    return 2
+                }
+        "#,
+            )
+            .expect("Failed to compile.");
+        assert!(compiler.get_warnings().is_empty());
+    }
+
+    #[test]
+    fn jump_out_of_range() {
+        // each call is one instruction, too many for the jump over the body.
+        let body = "                get_current_pid_tgid()\n".repeat(40000);
+        let prog = format!(
+            "fn()\n                if get_current_pid_tgid() == 0 {{\n{}                }}\n",
+            body
+        );
+
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(&prog)
+            .expect_err("Out-of-range jump compiled.");
+        assert!(matches!(err, Error::Semantics { line: 2, .. }));
+        assert!(err.to_string().contains("exceeds the 32767"));
+    }
+
+    #[test]
+    fn return_structure_address() {
+        let expected = [
+            Instruction::store64(Register::R10, -16, 0), // *(r10 - 16) = 0
+            Instruction::store64(Register::R10, -8, 0),  // *(r10 - 8) = 0
+            Instruction::movx64(Register::R0, Register::R10), // r0 = r10
+            Instruction::add64(Register::R0, -16),       // r0 += -16
+            Instruction::exit(),                         // exit
+        ];
+
+        let prog = r#"
+            fn() -> &iovec
+                vec: iovec = 0
+                return &vec
+        "#;
+        compile_and_compare(prog, &expected);
+
+        // a structure is returned by address, like `return &vec`.
+        let prog = r#"
+            fn() -> &iovec
+                vec: iovec = 0
+                return vec
+        "#;
+        compile_and_compare(prog, &expected);
+
+        let database = test_database();
+        let err = Compiler::create(&database)
+            .compile(
+                r#"
+            fn() -> __u64
+                vec: iovec = 0
+                return vec
+        "#,
+            )
+            .expect_err("Returning a structure as an integer compiled.");
+        assert!(err.to_string().contains("return type"));
+    }
+
+    #[test]
+    fn disassemble() {
+        let database = test_database();
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn()
+        "#,
+            )
+            .expect("Failed to compile.");
+        let text = compiler.disassemble();
+        assert!(text.contains("exit"));
+
+        let mut compiler = Compiler::create(&database);
+        compiler
+            .compile(
+                r#"
+            fn(a: __u64)
+                vec: iovec = 0
+                vec.iov_len = a
+                return vec.iov_len
+        "#,
+            )
+            .expect("Failed to compile.");
+        let text = compiler.disassemble();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), compiler.get_instructions().len());
+        assert!(lines[0].starts_with("   0: *(r10 + -8) = r1"));
+        assert!(lines[0].ends_with("; line 1, store to a"));
+        assert!(lines[1].ends_with("; line 2, store to vec"));
+        assert!(lines[2].ends_with("; line 2, store to vec+8"));
+        assert!(lines[3].ends_with("; line 3"));
+    }
 }
@@ -1,4 +1,7 @@
-use bpf_ins::{Instruction, Opcode};
+use bpf_ins::{
+    ArithmeticOperation, Instruction, JumpOperation, MemoryOpMode, MemoryOpSize, Opcode,
+    OpcodeClass, Register, SourceOperand,
+};
 
 /// An [`Optimizer`] takes a set of input instructions and pushes optimized
 /// instructions to the output (the second argument) if it succeeeds. On success
@@ -87,6 +90,173 @@ fn optimize_add_load(inp: &mut &[Instruction], out: &mut Vec<Instruction>) -> bo
     true
 }
 
+///
+/// Makes the following optimization:
+///
+///   *(r10 + N) = r0  | *(r10 + N) = r0
+///   r0 = *(r10 + N)  |
+///
+fn optimize_store_load(inp: &mut &[Instruction], out: &mut Vec<Instruction>) -> bool {
+    const NEEDED: usize = 2;
+    if inp.len() < NEEDED {
+        return false;
+    }
+    let (ins, rem) = inp.split_at(NEEDED);
+
+    let check0 = Instruction::storex64(
+        ins[0].get_dst_reg(),
+        ins[0].get_offset(),
+        ins[0].get_src_reg(),
+    );
+    let check1 = Instruction::loadx64(
+        ins[0].get_src_reg(),
+        ins[0].get_dst_reg(),
+        ins[0].get_offset(),
+    );
+
+    if check0 != ins[0] || check1 != ins[1] {
+        return false;
+    }
+
+    *inp = rem;
+    out.push(ins[0]);
+    true
+}
+
+///
+/// Makes the following optimization:
+///
+///   r6 = A      | r6 = A op B
+///   r7 = B      |
+///   r6 op= r7   |
+///
+/// Operations wrap like the 64-bit BPF ones do. Divisions by zero, shifts by 64
+/// or more and results that don't fit a 32-bit immediate are left alone.
+fn optimize_fold_constants(inp: &mut &[Instruction], out: &mut Vec<Instruction>) -> bool {
+    const NEEDED: usize = 3;
+    if inp.len() < NEEDED {
+        return false;
+    }
+    let (ins, rem) = inp.split_at(NEEDED);
+    let operation = if let Opcode::Arithmetic(arithmetic) = ins[2].get_opcode() {
+        *arithmetic.get_operation()
+    } else {
+        return false;
+    };
+
+    let (imm0, imm1): (i32, i32) = match (ins[0].get_imm().try_into(), ins[1].get_imm().try_into())
+    {
+        (Ok(imm0), Ok(imm1)) => (imm0, imm1),
+        _ => return false,
+    };
+
+    let dst_reg = ins[0].get_dst_reg();
+    let src_reg = ins[1].get_dst_reg();
+    let check0 = Instruction::mov64(dst_reg, imm0);
+    let check1 = Instruction::mov64(src_reg, imm1);
+    let check2 = Instruction::alux64(dst_reg, src_reg, operation);
+
+    if dst_reg == src_reg || check0 != ins[0] || check1 != ins[1] || check2 != ins[2] {
+        return false;
+    }
+
+    let (a, b) = (i64::from(imm0), i64::from(imm1));
+    let shift = u32::try_from(b).ok().filter(|shift| *shift < 64);
+    let value = match operation {
+        ArithmeticOperation::Add => Some(a.wrapping_add(b)),
+        ArithmeticOperation::Sub => Some(a.wrapping_sub(b)),
+        ArithmeticOperation::Mul => Some(a.wrapping_mul(b)),
+        ArithmeticOperation::Div => (a as u64).checked_div(b as u64).map(|v| v as i64),
+        ArithmeticOperation::Mod => (a as u64).checked_rem(b as u64).map(|v| v as i64),
+        ArithmeticOperation::And => Some(a & b),
+        ArithmeticOperation::Or => Some(a | b),
+        ArithmeticOperation::Xor => Some(a ^ b),
+        ArithmeticOperation::Lhs => shift.map(|shift| a << shift),
+        ArithmeticOperation::Rhs => shift.map(|shift| ((a as u64) >> shift) as i64),
+        ArithmeticOperation::Ash => shift.map(|shift| a >> shift),
+        _ => None,
+    };
+
+    let value: i32 = match value.map(i32::try_from) {
+        Some(Ok(value)) => value,
+        _ => return false,
+    };
+
+    *inp = rem;
+    out.push(Instruction::mov64(dst_reg, value));
+    true
+}
+
+/// Returns the range of stack bytes written by an instruction, if it's a store
+/// relative to the frame pointer.
+///
+/// # Arguments
+///
+/// * `ins` - The instruction.
+fn get_stack_store_range(ins: &Instruction) -> Option<(i64, i64)> {
+    let memory = match ins.get_opcode() {
+        Opcode::Memory(memory) => memory,
+        _ => return None,
+    };
+
+    let is_store = matches!(
+        memory.get_class(),
+        OpcodeClass::Store | OpcodeClass::StoreReg
+    );
+    if !is_store || !matches!(memory.get_mode(), MemoryOpMode::Memory) {
+        return None;
+    }
+    if ins.get_dst_reg() != Register::R10 {
+        return None;
+    }
+
+    let size = match memory.get_size() {
+        MemoryOpSize::Byte => 1,
+        MemoryOpSize::HalfWord => 2,
+        MemoryOpSize::Word => 4,
+        MemoryOpSize::DoubleWord => 8,
+    };
+    let start = i64::from(ins.get_offset());
+    Some((start, start + size))
+}
+
+///
+/// Makes the following optimization:
+///
+///   *(r10 - 16) = 0    | *(r10 - 8) = 0
+///   *(r10 - 8) = 0     | *(r10 - 16) = 100
+///   *(r10 - 16) = 100  |
+///
+/// A store to the stack is dropped when the same bytes are stored to again
+/// before anything else happens; only stores to other stack slots may come in
+/// between, so nothing can read the dropped value.
+fn optimize_dead_store(inp: &mut &[Instruction], _out: &mut Vec<Instruction>) -> bool {
+    let (ins, rem) = match inp.split_first() {
+        Some((ins, rem)) => (ins, rem),
+        None => return false,
+    };
+    let (start, end) = match get_stack_store_range(ins) {
+        Some(range) => range,
+        None => return false,
+    };
+
+    for next in rem {
+        let (next_start, next_end) = match get_stack_store_range(next) {
+            Some(range) => range,
+            None => return false,
+        };
+        if next_start == start && next_end == end {
+            *inp = rem;
+            return true;
+        }
+        if next_start < end && start < next_end {
+            return false;
+        }
+    }
+
+    false
+}
+
 fn no_optimization(inp: &mut &[Instruction], out: &mut Vec<Instruction>) -> bool {
     let (ins, rem) = match inp.split_first() {
         Some((ins, rem)) => (ins, rem),
@@ -98,21 +268,185 @@ fn no_optimization(inp: &mut &[Instruction], out: &mut Vec<Instruction>) -> bool
 }
 
 /// List of optimizers used by the `optimize` function.
-static OPTIMIZERS: [Optimizer; 3] = [optimize_mov_add_load, optimize_add_load, no_optimization];
+static OPTIMIZERS: [Optimizer; 6] = [
+    optimize_mov_add_load,
+    optimize_add_load,
+    optimize_store_load,
+    optimize_fold_constants,
+    optimize_dead_store,
+    no_optimization,
+];
 
-/// Applies various optimizations to the given list of instructions.
+/// Runs each of the optimizers over the given list of instructions once. Returns
+/// the optimized instructions and, for each of them, the index of the first input
+/// instruction it was made from.
 ///
 /// # Arguments
 ///
-/// * `instructions` - The program, as a list of instructions, to optimize.
-pub fn optimize(mut instructions: &[Instruction]) -> Vec<Instruction> {
+/// * `instructions` - The list of instructions to optimize.
+fn optimize_pass(mut instructions: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let total = instructions.len();
     let mut optimized = vec![];
+    let mut sources = vec![];
     let instructions = &mut instructions;
     while !instructions.is_empty() {
         for optimizer in OPTIMIZERS {
-            optimizer(instructions, &mut optimized);
+            let source = total - instructions.len();
+            if optimizer(instructions, &mut optimized) {
+                sources.resize(optimized.len(), source);
+            }
         }
     }
 
-    optimized
+    (optimized, sources)
+}
+
+/// Applies various optimizations to the given list of instructions. Passes are
+/// repeated until nothing changes, since one optimization can expose another.
+/// Every optimization removes instructions, so an unchanged length means no
+/// optimization applied. Returns the optimized instructions and, for each of
+/// them, the index of the first input instruction it was made from.
+///
+/// # Arguments
+///
+/// * `instructions` - The program, as a list of instructions, to optimize.
+pub fn optimize(instructions: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let (mut optimized, mut sources) = optimize_pass(instructions);
+    loop {
+        let (next, next_sources) = optimize_pass(&optimized);
+        if next.len() == optimized.len() {
+            return (optimized, sources);
+        }
+        optimized = next;
+        sources = next_sources.iter().map(|i| sources[*i]).collect();
+    }
+}
+
+/// Returns a copy of a jump with a new offset, or `None` if the instruction isn't
+/// a 64-bit jump with an offset.
+///
+/// # Arguments
+///
+/// * `jump` - The jump instruction.
+/// * `offset` - The new offset, relative to the instruction after the jump.
+pub fn retarget_jump(jump: &Instruction, offset: i16) -> Option<Instruction> {
+    let opcode = match jump.get_opcode() {
+        Opcode::Jump(opcode) if matches!(opcode.get_class(), OpcodeClass::Jump) => opcode,
+        _ => return None,
+    };
+
+    match (*opcode.get_operation(), opcode.get_source()) {
+        (JumpOperation::Call | JumpOperation::Exit, _) => None,
+        (JumpOperation::Absolute, _) => Some(Instruction::jmp_abs(offset)),
+        (operation, SourceOperand::Immediate) => Some(Instruction::jmp_if(
+            jump.get_dst_reg(),
+            operation,
+            jump.get_imm(),
+            offset,
+        )),
+        (operation, SourceOperand::Register) => Some(Instruction::jmp_ifx(
+            jump.get_dst_reg(),
+            operation,
+            jump.get_src_reg(),
+            offset,
+        )),
+    }
+}
+
+/// Removes the instructions that can't be reached from the start of the program,
+/// e.g. the code after a `return` at the end of both branches of an if statement,
+/// and re-patches the jumps over them. The kernel rejects programs containing
+/// unreachable instructions. Returns the remaining instructions and, for each of
+/// them, its index in the input. The program is returned unchanged if it holds a
+/// jump that can't be followed.
+///
+/// # Arguments
+///
+/// * `instructions` - The program, as a list of instructions.
+pub fn remove_dead_code(instructions: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let unchanged = || (instructions.to_vec(), (0..instructions.len()).collect());
+
+    // jump offsets count wide instructions as two slots.
+    let mut slots = Vec::with_capacity(instructions.len());
+    let mut slot = 0;
+    for ins in instructions {
+        slots.push(slot);
+        slot += if ins.is_wide() { 2 } else { 1 };
+    }
+
+    // the index each instruction can fall through to and the index it jumps to.
+    let mut edges = Vec::with_capacity(instructions.len());
+    for (i, ins) in instructions.iter().enumerate() {
+        let operation = match ins.get_opcode() {
+            Opcode::Jump(opcode) => Some(*opcode.get_operation()),
+            _ => None,
+        };
+        let next = Some(i + 1).filter(|next| *next < instructions.len());
+        let edge = match operation {
+            Some(JumpOperation::Exit) => (None, None),
+            Some(JumpOperation::Call) | None => (next, None),
+            Some(operation) => {
+                if retarget_jump(ins, 0).is_none() {
+                    return unchanged();
+                }
+                let target = slots[i] as i64 + 1 + i64::from(ins.get_offset());
+                let target = usize::try_from(target)
+                    .ok()
+                    .and_then(|target| slots.binary_search(&target).ok());
+                let target = match target {
+                    Some(target) => target,
+                    None => return unchanged(),
+                };
+                match operation {
+                    JumpOperation::Absolute => (None, Some(target)),
+                    _ => (next, Some(target)),
+                }
+            }
+        };
+        edges.push(edge);
+    }
+
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if i >= instructions.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        let (next, target) = edges[i];
+        pending.extend(next.into_iter().chain(target));
+    }
+
+    let sources: Vec<usize> = (0..instructions.len()).filter(|i| reachable[*i]).collect();
+    if sources.len() == instructions.len() {
+        return unchanged();
+    }
+
+    // slots of the remaining instructions, indexed by their input index.
+    let mut new_slots = vec![0; instructions.len()];
+    let mut slot = 0;
+    for i in &sources {
+        new_slots[*i] = slot;
+        slot += if instructions[*i].is_wide() { 2 } else { 1 };
+    }
+
+    let mut optimized = Vec::with_capacity(sources.len());
+    for i in &sources {
+        let ins = match edges[*i] {
+            (_, Some(target)) => {
+                let offset = new_slots[target] as i64 - new_slots[*i] as i64 - 1;
+                match i16::try_from(offset)
+                    .ok()
+                    .and_then(|offset| retarget_jump(&instructions[*i], offset))
+                {
+                    Some(ins) => ins,
+                    None => return unchanged(),
+                }
+            }
+            _ => instructions[*i],
+        };
+        optimized.push(ins);
+    }
+
+    (optimized, sources)
 }
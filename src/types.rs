@@ -47,10 +47,14 @@ pub struct Array {
 
     /// Cached size.
     pub size: u32,
+
+    /// Cached alignment, the alignment of the element type.
+    pub alignment: u32,
 }
 
 impl Array {
-    /// Creates a new array referencing the given database.
+    /// Creates a new array referencing the given database. Fails if the element
+    /// type is zero-sized (e.g. void), since the array couldn't be indexed.
     ///
     /// # Arguments
     ///
@@ -65,11 +69,18 @@ impl Array {
         let element_type = database
             .get_type_by_id(element_type_id)
             .ok_or(Error::InvalidTypeId)?;
+        if element_type.get_size() == 0 {
+            return Err(Error::ZeroSizedArrayElement {
+                name: format!("<anonymous>[{}]", num_elements),
+            });
+        }
+
         let size = element_type.get_size() * num_elements;
         Ok(Self {
             element_type_id,
             num_elements,
             size,
+            alignment: element_type.alignment(),
         })
     }
 
@@ -77,6 +88,11 @@ impl Array {
     pub fn get_size(&self) -> u32 {
         self.size
     }
+
+    /// Returns the alignment of the array in bytes.
+    pub fn alignment(&self) -> u32 {
+        self.alignment
+    }
 }
 
 /// Represents the phystical properties of a field in a struct or union.
@@ -100,6 +116,18 @@ impl Field {
     }
 }
 
+/// How the fields of a structure are laid out when only their order is given.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StructLayout {
+    /// Fields are packed together contiguously, without padding.
+    #[default]
+    Packed,
+
+    /// Each field is padded to its natural alignment, like a C compiler does.
+    /// The structure's size is padded to a multiple of its alignment.
+    Natural,
+}
+
 /// Represents the physical properties of a structure.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Struct {
@@ -108,10 +136,15 @@ pub struct Struct {
 
     /// Cached size
     pub size: u32,
+
+    /// Cached alignment, the largest alignment of any field.
+    pub alignment: u32,
 }
 
 impl Struct {
-    /// Create a new structure referencing the given database.
+    /// Create a new structure referencing the given database. A field with an
+    /// empty name whose type is a structure is anonymous: its fields are promoted
+    /// into this structure at their absolute offsets instead.
     ///
     /// # Arguments
     ///
@@ -119,7 +152,9 @@ impl Struct {
     /// * `fields` - The fields for the structure.
     pub fn create(database: &TypeDatabase, fields: &[(&str, Field)]) -> Result<Self> {
         let mut new_fields = HashMap::with_capacity(fields.len());
+        let mut promoted = vec![];
         let mut bits = 0;
+        let mut alignment = 1;
         for (name, field) in fields {
             let field_type = database
                 .get_type_by_id(field.type_id)
@@ -128,12 +163,32 @@ impl Struct {
             if reach > bits {
                 bits = reach
             }
-            new_fields.insert(name.to_string(), *field);
+            alignment = alignment.max(field_type.alignment());
+            match field_type.base_type.fields() {
+                Some(inner_fields) if name.is_empty() && !field_type.is_pointer() => {
+                    for (inner_name, inner_field) in inner_fields {
+                        let inner_field = Field {
+                            offset: field.offset + inner_field.offset,
+                            type_id: inner_field.type_id,
+                        };
+                        promoted.push((inner_name.clone(), inner_field));
+                    }
+                }
+                _ => {
+                    new_fields.insert(name.to_string(), *field);
+                }
+            }
+        }
+
+        // named fields take precedence over promoted ones.
+        for (name, field) in promoted {
+            new_fields.entry(name).or_insert(field);
         }
 
         Ok(Self {
             fields: new_fields,
             size: bits / 8,
+            alignment,
         })
     }
 
@@ -141,6 +196,67 @@ impl Struct {
     pub fn get_size(&self) -> u32 {
         self.size
     }
+
+    /// Returns the alignment of the structure in bytes.
+    pub fn alignment(&self) -> u32 {
+        self.alignment
+    }
+}
+
+/// Represents the physical properties of a union. Every field starts at offset
+/// 0, overlapping the others.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Union {
+    /// A map of field name to field type.
+    pub fields: HashMap<String, Field>,
+
+    /// Cached size, the size of the largest field.
+    pub size: u32,
+
+    /// Cached alignment, the largest alignment of any field.
+    pub alignment: u32,
+}
+
+impl Union {
+    /// Create a new union referencing the given database.
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - The database in which the field types are contained.
+    /// * `fields` - The (name, type id) pairs of the union's fields.
+    pub fn create(database: &TypeDatabase, fields: &[(&str, usize)]) -> Result<Self> {
+        let mut new_fields = HashMap::with_capacity(fields.len());
+        let mut size = 0;
+        let mut alignment = 1;
+        for (name, type_id) in fields {
+            let field_type = database
+                .get_type_by_id(*type_id)
+                .ok_or(Error::InvalidTypeId)?;
+            size = size.max(field_type.get_size());
+            alignment = alignment.max(field_type.alignment());
+            let field = Field {
+                offset: 0,
+                type_id: *type_id,
+            };
+            new_fields.insert(name.to_string(), field);
+        }
+
+        Ok(Self {
+            fields: new_fields,
+            size,
+            alignment,
+        })
+    }
+
+    /// Returns the size of the union in bytes.
+    pub fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the alignment of the union in bytes.
+    pub fn alignment(&self) -> u32 {
+        self.alignment
+    }
 }
 
 /// Represents the physical properties of an enum type.
@@ -158,6 +274,18 @@ impl Enum {
     pub fn get_size(&self) -> u32 {
         self.bits / 8
     }
+
+    /// Returns the value of the enum constant with the given name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the constant.
+    pub fn get_value(&self, name: &str) -> Option<i64> {
+        self.values
+            .iter()
+            .find(|(value_name, _)| value_name == name)
+            .map(|(_, value)| *value)
+    }
 }
 
 /// Represents the physical properties of a function.
@@ -189,6 +317,7 @@ pub enum BaseType {
     Float(Float),
     Array(Array),
     Struct(Struct),
+    Union(Union),
     Enum(Enum),
     Function(Function),
 }
@@ -203,10 +332,35 @@ impl BaseType {
             BaseType::Float(t) => t.get_size(),
             BaseType::Array(t) => t.get_size(),
             BaseType::Struct(t) => t.get_size(),
+            BaseType::Union(t) => t.get_size(),
             BaseType::Enum(t) => t.get_size(),
             BaseType::Function(_) => 0,
         }
     }
+
+    /// Returns the natural alignment of the underlying type in bytes. Scalars are
+    /// aligned to their size, arrays to their elements and structures to their
+    /// most-aligned field. Types without a size are byte-aligned.
+    pub fn alignment(&self) -> u32 {
+        match self {
+            BaseType::Integer(t) => t.get_size().max(1),
+            BaseType::Float(t) => t.get_size().max(1),
+            BaseType::Enum(t) => t.get_size().max(1),
+            BaseType::Array(t) => t.alignment().max(1),
+            BaseType::Struct(t) => t.alignment().max(1),
+            BaseType::Union(t) => t.alignment().max(1),
+            BaseType::Void | BaseType::Function(_) => 1,
+        }
+    }
+
+    /// Returns the fields of a structure or union, or `None` for any other type.
+    pub fn fields(&self) -> Option<&HashMap<String, Field>> {
+        match self {
+            BaseType::Struct(t) => Some(&t.fields),
+            BaseType::Union(t) => Some(&t.fields),
+            _ => None,
+        }
+    }
 }
 
 /// Represents a fully-qualified type.
@@ -236,6 +390,15 @@ impl Type {
 
         self.base_type.get_size()
     }
+
+    /// Gets the alignment, in bytes, of the type. Pointers are 8-byte aligned.
+    pub fn alignment(&self) -> u32 {
+        if self.num_refs > 0 {
+            return 8;
+        }
+
+        self.base_type.alignment()
+    }
 }
 
 impl From<BaseType> for Type {
@@ -279,6 +442,32 @@ impl TypeDatabase {
         }
     }
 
+    /// Adds another name for a type that's already in the database, e.g. a
+    /// typedef. Returns the id of the existing type.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The new name of the type.
+    /// * `existing_id` - The id of the existing type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let id = u64::add_to_database(&mut database).expect("Failed to add type.");
+    /// database.add_alias("__u64", id).expect("Failed to add alias.");
+    /// assert_eq!(database.get_type_id_by_name("__u64"), Some(id));
+    /// ```
+    pub fn add_alias(&mut self, name: &str, existing_id: usize) -> Result<usize> {
+        if existing_id >= self.types.len() {
+            return Err(Error::InvalidTypeId);
+        }
+
+        self.name_map.insert(name.to_string(), existing_id);
+        Ok(existing_id)
+    }
+
     /// Finds a type in the database by name.
     ///
     /// # Arguments
@@ -307,6 +496,79 @@ impl TypeDatabase {
         Some(*self.name_map.get(name)?)
     }
 
+    /// Returns whether the database has a type with the given name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// assert!(database.contains("u32"));
+    /// assert!(!database.contains("task_struct"));
+    /// ```
+    pub fn contains(&self, name: &str) -> bool {
+        self.name_map.contains_key(name)
+    }
+
+    /// Returns an iterator over the names of all the types in the database, in
+    /// no particular order.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// assert_eq!(database.type_names().collect::<Vec<_>>(), ["u32"]);
+    /// ```
+    pub fn type_names(&self) -> impl Iterator<Item = &str> {
+        self.name_map.keys().map(String::as_str)
+    }
+
+    /// Returns the number of types in the database, including unnamed ones.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// assert!(database.is_empty());
+    /// u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// assert_eq!(database.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    /// Returns whether the database has no types.
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    /// Returns the size, in bytes, of the type with the given name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// u64::add_to_database(&mut database).expect("Failed to add type.");
+    /// assert_eq!(database.size_of("u64"), Some(8));
+    /// assert_eq!(database.size_of("task_struct"), None);
+    /// ```
+    pub fn size_of(&self, name: &str) -> Option<u32> {
+        self.get_type_by_name(name).map(|ty| ty.get_size())
+    }
+
     /// Convenience function for adding an integer type to the database.
     ///
     /// # Arguments
@@ -330,6 +592,65 @@ impl TypeDatabase {
         self.add_type(name, &BaseType::Integer(new_integer).into())
     }
 
+    /// Convenience function for adding an enum type to the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    /// * `bytes` - The size of each value in bytes.
+    /// * `values` - The (name, value) pairs of the enum's constants.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::TypeDatabase;
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// database
+    ///     .add_enum(Some("state"), 4, &[("RUNNING", 0), ("STOPPED", 4)])
+    ///     .expect("Failed to add type.");
+    /// assert_eq!(database.find_enum_constant("STOPPED").len(), 1);
+    /// ```
+    pub fn add_enum(
+        &mut self,
+        name: Option<&str>,
+        bytes: u32,
+        values: &[(&str, i64)],
+    ) -> Result<usize> {
+        let new_enum = Enum {
+            bits: bytes * 8,
+            values: values
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        };
+
+        self.add_type(name, &BaseType::Enum(new_enum).into())
+    }
+
+    /// Finds the enum types defining a constant with the given name, returning
+    /// each type alongside the constant's value. Types registered under several
+    /// names are only returned once.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the constant.
+    pub fn find_enum_constant(&self, name: &str) -> Vec<(&Type, i64)> {
+        let mut found: Vec<(&Type, i64)> = vec![];
+        for ty in &self.types {
+            let value = match &ty.base_type {
+                BaseType::Enum(e) if !ty.is_pointer() => e.get_value(name),
+                _ => None,
+            };
+            if let Some(value) = value {
+                if !found.iter().any(|(other, _)| *other == ty) {
+                    found.push((ty, value));
+                }
+            }
+        }
+
+        found
+    }
+
     /// Convenience function for adding a float type to the database.
     ///
     /// # Arguments
@@ -355,11 +676,56 @@ impl TypeDatabase {
         element_type_id: usize,
         num_elements: u32,
     ) -> Result<usize> {
-        let new_array = Array::create(self, element_type_id, num_elements)?;
+        let new_array = match (Array::create(self, element_type_id, num_elements), name) {
+            (Err(Error::ZeroSizedArrayElement { .. }), Some(name)) => {
+                return Err(Error::ZeroSizedArrayElement {
+                    name: name.to_string(),
+                })
+            }
+            (result, _) => result?,
+        };
         self.add_type(name, &BaseType::Array(new_array).into())
     }
 
-    /// Convenience function for adding a struct to the database.
+    /// Convenience function for adding a pointer to an existing type to the
+    /// database, e.g. `u32 **` is a pointer to `u32` with 2 levels.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    /// * `pointee_id` - The type id of the type being pointed to.
+    /// * `levels` - The number of levels of indirection to add.
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let id = u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// let ptr_id = database
+    ///     .add_pointer(Some("u32_ptr"), id, 1)
+    ///     .expect("Failed to add type.");
+    /// let ptr = database.get_type_by_id(ptr_id).unwrap();
+    /// assert!(ptr.is_pointer());
+    /// assert_eq!(ptr.get_size(), 8);
+    /// ```
+    pub fn add_pointer(
+        &mut self,
+        name: Option<&str>,
+        pointee_id: usize,
+        levels: u32,
+    ) -> Result<usize> {
+        let mut pointer = self
+            .get_type_by_id(pointee_id)
+            .ok_or(Error::InvalidTypeId)?
+            .clone();
+        pointer.num_refs += levels;
+        self.add_type(name, &pointer)
+    }
+
+    /// Convenience function for adding a struct to the database. Fields with an
+    /// empty name embed an anonymous struct, whose fields can be accessed as
+    /// fields of the new struct.
     ///
     /// # Arguments
     ///
@@ -382,13 +748,35 @@ impl TypeDatabase {
         &mut self,
         name: Option<&str>,
         fields: &[(&str, usize)],
+    ) -> Result<usize> {
+        self.add_struct_by_ids_with_layout(name, fields, StructLayout::Packed)
+    }
+
+    /// Convenience function for adding a struct to the database using
+    /// a slice of (field_name, type_id). Types are added in order and
+    /// laid out according to `layout`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    /// * `fields` - The fields to add (by id).
+    /// * `layout` - Whether to pack the fields or pad them to their alignment.
+    pub fn add_struct_by_ids_with_layout(
+        &mut self,
+        name: Option<&str>,
+        fields: &[(&str, usize)],
+        layout: StructLayout,
     ) -> Result<usize> {
         let mut new_fields = Vec::with_capacity(fields.len());
-        let mut offset = 0;
+        let mut offset: u32 = 0;
         for (field_name, type_id) in fields {
             let field_type = self
                 .get_type_by_id(*type_id)
                 .ok_or(Error::InvalidTypeName)?;
+            if layout == StructLayout::Natural {
+                let alignment = field_type.alignment() * 8;
+                offset += (alignment - offset % alignment) % alignment;
+            }
             let field = Field {
                 offset,
                 type_id: *type_id,
@@ -396,10 +784,39 @@ impl TypeDatabase {
             offset += field_type.get_size() * 8;
             new_fields.push((*field_name, field));
         }
-        let new_struct = Struct::create(self, new_fields.as_slice())?;
+        let mut new_struct = Struct::create(self, new_fields.as_slice())?;
+        if layout == StructLayout::Natural {
+            let alignment = new_struct.alignment;
+            new_struct.size += (alignment - new_struct.size % alignment) % alignment;
+        }
         self.add_type(name, &BaseType::Struct(new_struct).into())
     }
 
+    /// Convenience function for adding a union to the database using a slice of
+    /// (field_name, type_id). Every field starts at offset 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the type.
+    /// * `fields` - The fields to add (by id).
+    ///
+    /// # Example
+    /// ```
+    /// use bpf_script::types::{AddToTypeDatabase, TypeDatabase};
+    ///
+    /// let mut database = TypeDatabase::default();
+    /// let u32_id = u32::add_to_database(&mut database).expect("Failed to add type.");
+    /// let u64_id = u64::add_to_database(&mut database).expect("Failed to add type.");
+    /// database
+    ///     .add_union(Some("value"), &[("small", u32_id), ("large", u64_id)])
+    ///     .expect("Failed to add type.");
+    /// assert_eq!(database.size_of("value"), Some(8));
+    /// ```
+    pub fn add_union(&mut self, name: Option<&str>, fields: &[(&str, usize)]) -> Result<usize> {
+        let new_union = Union::create(self, fields)?;
+        self.add_type(name, &BaseType::Union(new_union).into())
+    }
+
     /// Convenience function for adding a struct to the database using
     /// a slice of (field_name, type_name). Types are added in order, and
     /// packed together contiguously.